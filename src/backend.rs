@@ -0,0 +1,320 @@
+use std::collections::BTreeMap;
+use std::fmt;
+use std::hash::{DefaultHasher, Hash, Hasher};
+use std::ops::Range;
+use std::sync::Mutex;
+use std::time::SystemTime;
+
+use libc::{EACCES, EAGAIN, EBUSY, EIO, ENOENT, ENOSPC, EROFS};
+
+// Errors returned by an object store
+#[derive(Debug, Clone, PartialEq)]
+pub enum BackendError {
+    NotFound,
+    AccessDenied,
+    // Request rate exceeded, e.g. S3 503 SlowDown
+    Throttled,
+    // Bucket quota or object size limit exceeded
+    NoSpace,
+    // A conditional write found the object changed, e.g. S3 412
+    PreconditionFailed,
+    // Writes were refused because another host took over the filesystem
+    LeaseLost,
+    // Server side failure with the given HTTP status
+    Server(u16),
+    // Connection failed, timed out or was reset
+    Connection(String),
+    Other(String),
+}
+
+impl BackendError {
+    // Map the error to an errno value for a FUSE reply
+    pub fn errno(&self) -> i32 {
+        match self {
+            BackendError::NotFound => ENOENT,
+            BackendError::AccessDenied => EACCES,
+            BackendError::Throttled => EAGAIN,
+            BackendError::NoSpace => ENOSPC,
+            BackendError::PreconditionFailed => EBUSY,
+            BackendError::LeaseLost => EROFS,
+            BackendError::Server(_) | BackendError::Connection(_) | BackendError::Other(_) => EIO,
+        }
+    }
+
+    // Whether the request may succeed if tried again
+    pub fn is_transient(&self) -> bool {
+        matches!(
+            self,
+            BackendError::Throttled | BackendError::Server(_) | BackendError::Connection(_)
+        )
+    }
+}
+
+impl fmt::Display for BackendError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            BackendError::NotFound => write!(f, "object not found"),
+            BackendError::AccessDenied => write!(f, "access denied"),
+            BackendError::Throttled => write!(f, "request rate exceeded"),
+            BackendError::NoSpace => write!(f, "storage quota exceeded"),
+            BackendError::PreconditionFailed => write!(f, "object was changed concurrently"),
+            BackendError::LeaseLost => write!(f, "lease lost to another host"),
+            BackendError::Server(status) => write!(f, "server error {status}"),
+            BackendError::Connection(msg) => write!(f, "connection error: {msg}"),
+            BackendError::Other(msg) => write!(f, "{msg}"),
+        }
+    }
+}
+
+// An object found by listing a store
+#[derive(Debug, Clone, PartialEq)]
+pub struct ObjectInfo {
+    pub key: String,
+    pub size: u64,
+    pub last_modified: SystemTime,
+    pub etag: String,
+}
+
+// A version of an object kept by a store with versioning enabled
+#[derive(Debug, Clone, PartialEq)]
+pub struct ObjectVersion {
+    pub key: String,
+    pub version_id: String,
+    pub size: u64,
+    pub last_modified: SystemTime,
+    pub etag: String,
+    // Whether this is the current version of the object
+    pub latest: bool,
+    // A delete marker, hiding the versions before it
+    pub deleted: bool,
+}
+
+// Storage for the objects backing the filesystem. Keys are relative to the
+// bucket prefix the store was created with.
+pub trait ObjectStore: Send + Sync {
+    // Read a whole object, or only the given byte range of it. A range
+    // extending past the end of the object returns the bytes that exist.
+    fn get(&self, key: &str, range: Option<Range<u64>>) -> Result<Vec<u8>, BackendError>;
+    fn put(&self, key: &str, data: &[u8]) -> Result<(), BackendError>;
+    // Read a whole object along with its ETag
+    fn get_tagged(&self, key: &str) -> Result<(Vec<u8>, String), BackendError>;
+    // Write an object only if it doesn't exist (etag None) or still has the
+    // given ETag, returning its new ETag. Fails with PreconditionFailed
+    // otherwise.
+    fn put_if(&self, key: &str, data: &[u8], etag: Option<&str>) -> Result<String, BackendError>;
+    fn delete(&self, key: &str) -> Result<(), BackendError>;
+    // Copy an object, or a byte range of it, to another key without passing
+    // the data through the client
+    fn copy(&self, from: &str, range: Option<Range<u64>>, to: &str) -> Result<(), BackendError>;
+    // Every object whose key starts with the given prefix, in key order
+    fn list(&self, prefix: &str) -> Result<Vec<ObjectInfo>, BackendError>;
+
+    // Version id the store gave an object when last written through this
+    // client, None if the store doesn't keep versions
+    fn version(&self, _key: &str) -> Option<String> {
+        None
+    }
+    // Read a version of an object, which may since have been replaced or
+    // deleted
+    fn get_version(
+        &self,
+        _key: &str,
+        _version_id: &str,
+        _range: Option<Range<u64>>,
+    ) -> Result<Vec<u8>, BackendError> {
+        Err(BackendError::Other(
+            "object versions not supported".to_string(),
+        ))
+    }
+    // Every version of the objects whose key starts with the given prefix,
+    // in key order and newest first
+    fn list_versions(&self, _prefix: &str) -> Result<Vec<ObjectVersion>, BackendError> {
+        Err(BackendError::Other(
+            "object versions not supported".to_string(),
+        ))
+    }
+}
+
+// The bytes of an object within a range, which may extend past its end
+pub fn slice(data: &[u8], range: Option<Range<u64>>) -> Vec<u8> {
+    match range {
+        Some(range) => {
+            let len = data.len() as u64;
+            let start = range.start.min(len) as usize;
+            let end = range.end.min(len) as usize;
+            data[start..end.max(start)].to_vec()
+        }
+        None => data.to_vec(),
+    }
+}
+
+// Object store kept in memory, used for testing
+#[derive(Default)]
+pub struct MemoryStore {
+    objects: Mutex<BTreeMap<String, (Vec<u8>, SystemTime)>>,
+    // Every version of each object, oldest first, if versioning is enabled
+    versions: Option<Mutex<BTreeMap<String, Vec<MemoryVersion>>>>,
+}
+
+// A version of an object in a MemoryStore, without data for a delete marker
+struct MemoryVersion {
+    id: String,
+    data: Option<Vec<u8>>,
+    time: SystemTime,
+}
+
+impl MemoryStore {
+    pub fn new() -> MemoryStore {
+        MemoryStore::default()
+    }
+
+    // A store keeping every version of its objects, like a bucket with
+    // versioning enabled
+    pub fn versioned() -> MemoryStore {
+        MemoryStore {
+            versions: Some(Mutex::default()),
+            ..MemoryStore::default()
+        }
+    }
+
+    // Keep a new version of an object, when versioning is enabled
+    fn add_version(&self, key: &str, data: Option<&[u8]>, time: SystemTime) {
+        if let Some(versions) = &self.versions {
+            versions
+                .lock()
+                .unwrap()
+                .entry(key.to_string())
+                .or_default()
+                .push(MemoryVersion {
+                    id: format!("{:016x}", fastrand::u64(..)),
+                    data: data.map(<[u8]>::to_vec),
+                    time,
+                });
+        }
+    }
+}
+
+// ETag of an object in a MemoryStore, changing whenever it is written
+fn memory_etag(data: &[u8], time: SystemTime) -> String {
+    let mut hasher = DefaultHasher::new();
+    data.hash(&mut hasher);
+    time.hash(&mut hasher);
+    format!("\"{:016x}\"", hasher.finish())
+}
+
+impl ObjectStore for MemoryStore {
+    fn get(&self, key: &str, range: Option<Range<u64>>) -> Result<Vec<u8>, BackendError> {
+        let objects = self.objects.lock().unwrap();
+        let (data, _) = objects.get(key).ok_or(BackendError::NotFound)?;
+        Ok(slice(data, range))
+    }
+
+    fn put(&self, key: &str, data: &[u8]) -> Result<(), BackendError> {
+        let mut objects = self.objects.lock().unwrap();
+        let time = SystemTime::now();
+        objects.insert(key.to_string(), (data.to_vec(), time));
+        self.add_version(key, Some(data), time);
+        Ok(())
+    }
+
+    fn get_tagged(&self, key: &str) -> Result<(Vec<u8>, String), BackendError> {
+        let objects = self.objects.lock().unwrap();
+        let (data, time) = objects.get(key).ok_or(BackendError::NotFound)?;
+        Ok((data.clone(), memory_etag(data, *time)))
+    }
+
+    fn put_if(&self, key: &str, data: &[u8], etag: Option<&str>) -> Result<String, BackendError> {
+        let mut objects = self.objects.lock().unwrap();
+        let current = objects
+            .get(key)
+            .map(|(data, time)| memory_etag(data, *time));
+        if current.as_deref() != etag {
+            return Err(BackendError::PreconditionFailed);
+        }
+
+        let time = SystemTime::now();
+        objects.insert(key.to_string(), (data.to_vec(), time));
+        self.add_version(key, Some(data), time);
+        Ok(memory_etag(data, time))
+    }
+
+    fn delete(&self, key: &str) -> Result<(), BackendError> {
+        let mut objects = self.objects.lock().unwrap();
+        if objects.remove(key).is_some() {
+            self.add_version(key, None, SystemTime::now());
+        }
+        Ok(())
+    }
+
+    fn copy(&self, from: &str, range: Option<Range<u64>>, to: &str) -> Result<(), BackendError> {
+        let data = self.get(from, range)?;
+        self.put(to, &data)
+    }
+
+    fn list(&self, prefix: &str) -> Result<Vec<ObjectInfo>, BackendError> {
+        let objects = self.objects.lock().unwrap();
+        Ok(objects
+            .range(prefix.to_string()..)
+            .take_while(|(key, _)| key.starts_with(prefix))
+            .map(|(key, (data, last_modified))| ObjectInfo {
+                key: key.clone(),
+                size: data.len() as u64,
+                last_modified: *last_modified,
+                etag: memory_etag(data, *last_modified),
+            })
+            .collect())
+    }
+
+    fn version(&self, key: &str) -> Option<String> {
+        let versions = self.versions.as_ref()?.lock().unwrap();
+        let version = versions.get(key)?.last()?;
+        version.data.as_ref().map(|_| version.id.clone())
+    }
+
+    fn get_version(
+        &self,
+        key: &str,
+        version_id: &str,
+        range: Option<Range<u64>>,
+    ) -> Result<Vec<u8>, BackendError> {
+        let Some(versions) = &self.versions else {
+            return Err(BackendError::NotFound);
+        };
+        let versions = versions.lock().unwrap();
+        let data = versions
+            .get(key)
+            .and_then(|versions| versions.iter().find(|version| version.id == version_id))
+            .and_then(|version| version.data.as_ref())
+            .ok_or(BackendError::NotFound)?;
+        Ok(slice(data, range))
+    }
+
+    fn list_versions(&self, prefix: &str) -> Result<Vec<ObjectVersion>, BackendError> {
+        let Some(versions) = &self.versions else {
+            return Ok(Vec::new());
+        };
+        let versions = versions.lock().unwrap();
+        let mut listed = Vec::new();
+        for (key, versions) in versions
+            .range(prefix.to_string()..)
+            .take_while(|(key, _)| key.starts_with(prefix))
+        {
+            for (age, version) in versions.iter().rev().enumerate() {
+                let data = version.data.as_deref().unwrap_or_default();
+                listed.push(ObjectVersion {
+                    key: key.clone(),
+                    version_id: version.id.clone(),
+                    size: data.len() as u64,
+                    last_modified: version.time,
+                    etag: match version.data {
+                        Some(_) => memory_etag(data, version.time),
+                        None => String::new(),
+                    },
+                    latest: age == 0,
+                    deleted: version.data.is_none(),
+                });
+            }
+        }
+        Ok(listed)
+    }
+}
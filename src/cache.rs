@@ -0,0 +1,389 @@
+use std::collections::hash_map::Entry;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::ops::Range;
+use std::sync::{Condvar, Mutex, MutexGuard};
+
+// A band is identified by the inode of its file and its index within the file
+pub type BandId = (u64, u64);
+
+// Band objects are stored below this prefix
+pub const BAND_PREFIX: &str = "bands/";
+
+// Band objects are named after the file inode and band index, followed by
+// the generation of the band once it was written after a snapshot
+pub fn band_key(id: BandId, generation: u64) -> String {
+    match generation {
+        0 => format!("{BAND_PREFIX}{:x}/{:x}", id.0, id.1),
+        _ => format!("{BAND_PREFIX}{:x}/{:x}.{:x}", id.0, id.1, generation),
+    }
+}
+
+// Generation of a band object, from its key
+pub fn band_generation(key: &str) -> u64 {
+    key.rsplit_once('.')
+        .and_then(|(_, generation)| u64::from_str_radix(generation, 16).ok())
+        .unwrap_or(0)
+}
+
+// Generation of the object holding each band, shared between the filesystem
+// and the uploader. A band written after a snapshot moves to a new
+// generation, so the object the snapshot refers to is left untouched. Bands
+// not found are at generation 0.
+#[derive(Default)]
+pub struct Generations {
+    map: Mutex<HashMap<BandId, u64>>,
+}
+
+impl Generations {
+    pub fn new() -> Generations {
+        Generations::default()
+    }
+
+    pub fn get(&self, id: BandId) -> u64 {
+        self.map.lock().unwrap().get(&id).copied().unwrap_or(0)
+    }
+
+    pub fn set(&self, id: BandId, generation: u64) {
+        let mut map = self.map.lock().unwrap();
+        match generation {
+            0 => map.remove(&id),
+            _ => map.insert(id, generation),
+        };
+    }
+
+    // Key of the object currently holding a band
+    pub fn key(&self, id: BandId) -> String {
+        band_key(id, self.get(id))
+    }
+
+    // Forget the generations of a range of bands of a file
+    pub fn remove_bands(&self, ino: u64, bands: Range<u64>) {
+        self.map
+            .lock()
+            .unwrap()
+            .retain(|id, _| id.0 != ino || !bands.contains(&id.1));
+    }
+
+    // Every generation other than 0, by inode and band index
+    pub fn to_map(&self) -> BTreeMap<u64, BTreeMap<u64, u64>> {
+        let mut generations: BTreeMap<u64, BTreeMap<u64, u64>> = BTreeMap::new();
+        for (id, generation) in self.map.lock().unwrap().iter() {
+            generations
+                .entry(id.0)
+                .or_default()
+                .insert(id.1, *generation);
+        }
+        generations
+    }
+
+    pub fn replace(&self, generations: BTreeMap<u64, BTreeMap<u64, u64>>) {
+        *self.map.lock().unwrap() = generations
+            .into_iter()
+            .flat_map(|(ino, bands)| bands.into_iter().map(move |(index, g)| ((ino, index), g)))
+            .collect();
+    }
+}
+
+// Keys of the band objects stored for each file, shared between the
+// filesystem and the uploader. Those of a file are listed the first time
+// they are needed and kept up to date as bands are uploaded and deleted, so
+// finding the bands holding data doesn't take listing them every time.
+#[derive(Default)]
+pub struct StoredBands {
+    map: Mutex<HashMap<u64, HashSet<String>>>,
+}
+
+impl StoredBands {
+    pub fn new() -> StoredBands {
+        StoredBands::default()
+    }
+
+    // Keys of the band objects of a file, listed with `list` unless known.
+    // Uploads completing meanwhile wait for the listing, so none is missed.
+    pub fn keys<E>(
+        &self,
+        ino: u64,
+        list: impl FnOnce() -> Result<Vec<String>, E>,
+    ) -> Result<HashSet<String>, E> {
+        let mut map = self.map.lock().unwrap();
+        match map.entry(ino) {
+            Entry::Occupied(entry) => Ok(entry.get().clone()),
+            Entry::Vacant(entry) => Ok(entry.insert(list()?.into_iter().collect()).clone()),
+        }
+    }
+
+    // Record that a band object was stored. Files not listed yet are left
+    // for the listing to find.
+    pub fn insert(&self, ino: u64, key: String) {
+        if let Some(keys) = self.map.lock().unwrap().get_mut(&ino) {
+            keys.insert(key);
+        }
+    }
+
+    pub fn remove(&self, ino: u64, key: &str) {
+        if let Some(keys) = self.map.lock().unwrap().get_mut(&ino) {
+            keys.remove(key);
+        }
+    }
+
+    // Forget the band objects of a deleted file
+    pub fn forget(&self, ino: u64) {
+        self.map.lock().unwrap().remove(&ino);
+    }
+}
+
+struct Band {
+    data: Vec<u8>,
+    dirty: bool,
+    // Incremented on every write, so an upload can tell whether the band
+    // changed while it was in progress
+    version: u64,
+    last_used: u64,
+}
+
+struct Inner {
+    bands: HashMap<BandId, Band>,
+    // Bands being loaded in the background, and whether they were modified
+    // while the load was in progress
+    loading: HashMap<BandId, bool>,
+    clock: u64,
+}
+
+// In-memory cache of file bands shared between the filesystem and its
+// background workers. Clean bands are evicted least recently used first once
+// the cache holds more than `capacity` bands; dirty bands are kept until they
+// have been uploaded.
+pub struct BandCache {
+    capacity: usize,
+    inner: Mutex<Inner>,
+    loaded: Condvar,
+}
+
+impl BandCache {
+    pub fn new(capacity: usize) -> BandCache {
+        BandCache {
+            capacity,
+            inner: Mutex::new(Inner {
+                bands: HashMap::new(),
+                loading: HashMap::new(),
+                clock: 0,
+            }),
+            loaded: Condvar::new(),
+        }
+    }
+
+    // Lock the cache once no background load of the band is in progress
+    fn wait_loaded(&self, id: BandId) -> MutexGuard<'_, Inner> {
+        let mut inner = self.inner.lock().unwrap();
+        while inner.loading.contains_key(&id) {
+            inner = self.loaded.wait(inner).unwrap();
+        }
+        inner
+    }
+
+    fn evict(&self, inner: &mut Inner) {
+        while inner.bands.len() > self.capacity {
+            let victim = inner
+                .bands
+                .iter()
+                .filter(|(_, band)| !band.dirty)
+                .min_by_key(|(_, band)| band.last_used)
+                .map(|(id, _)| *id);
+            match victim {
+                Some(id) => inner.bands.remove(&id),
+                None => break,
+            };
+        }
+    }
+
+    // Check whether a band is cached, waiting for any load in progress
+    pub fn contains(&self, id: BandId) -> bool {
+        self.wait_loaded(id).bands.contains_key(&id)
+    }
+
+    // Copy part of a cached band into buf, zero-filling past the end of the
+    // band data. Returns false if the band is not cached.
+    pub fn read(&self, id: BandId, offset: usize, buf: &mut [u8]) -> bool {
+        let mut inner = self.wait_loaded(id);
+        inner.clock += 1;
+        let clock = inner.clock;
+
+        match inner.bands.get_mut(&id) {
+            Some(band) => {
+                band.last_used = clock;
+                let start = offset.min(band.data.len());
+                let end = (offset + buf.len()).min(band.data.len());
+                buf[..end - start].copy_from_slice(&band.data[start..end]);
+                buf[end - start..].fill(0);
+                true
+            }
+            None => false,
+        }
+    }
+
+    // Add a clean band unless it is already cached
+    pub fn insert(&self, id: BandId, data: Vec<u8>) {
+        let mut inner = self.inner.lock().unwrap();
+        inner.clock += 1;
+        let last_used = inner.clock;
+        inner.bands.entry(id).or_insert(Band {
+            data,
+            dirty: false,
+            version: 0,
+            last_used,
+        });
+        self.evict(&mut inner);
+    }
+
+    // Modify a band and mark it dirty. A band which is not cached is created
+    // from `base`, the band contents as stored. Returns false if the band is
+    // not cached and no base was given.
+    fn modify(&self, id: BandId, base: Option<Vec<u8>>, f: impl FnOnce(&mut Vec<u8>)) -> bool {
+        let mut inner = self.inner.lock().unwrap();
+        inner.clock += 1;
+        let clock = inner.clock;
+
+        if let Some(invalidated) = inner.loading.get_mut(&id) {
+            *invalidated = true;
+        }
+
+        let band = match (inner.bands.entry(id), base) {
+            (Entry::Occupied(entry), _) => entry.into_mut(),
+            (Entry::Vacant(entry), Some(data)) => entry.insert(Band {
+                data,
+                dirty: true,
+                version: 0,
+                last_used: clock,
+            }),
+            (Entry::Vacant(_), None) => return false,
+        };
+
+        f(&mut band.data);
+        band.dirty = true;
+        band.version += 1;
+        band.last_used = clock;
+        true
+    }
+
+    // Write into a band, see modify()
+    pub fn write(&self, id: BandId, offset: usize, data: &[u8], base: Option<Vec<u8>>) -> bool {
+        self.modify(id, base, |band| {
+            let end = offset + data.len();
+            if band.len() < end {
+                band.resize(end, 0);
+            }
+            band[offset..end].copy_from_slice(data);
+        })
+    }
+
+    // Cut a band short, see modify()
+    pub fn truncate(&self, id: BandId, len: usize, base: Option<Vec<u8>>) -> bool {
+        self.modify(id, base, |band| band.truncate(len))
+    }
+
+    // Reserve a band for loading in the background. Returns false if the band
+    // is already cached or being loaded.
+    pub fn begin_load(&self, id: BandId) -> bool {
+        let mut inner = self.inner.lock().unwrap();
+        if inner.bands.contains_key(&id) || inner.loading.contains_key(&id) {
+            return false;
+        }
+        inner.loading.insert(id, false);
+        true
+    }
+
+    // Complete a background load started with begin_load(). The data is
+    // discarded if the band was modified in the meantime.
+    pub fn finish_load(&self, id: BandId, data: Option<Vec<u8>>) {
+        let mut inner = self.inner.lock().unwrap();
+        let invalidated = inner.loading.remove(&id).unwrap_or(true);
+
+        if let (Some(data), false) = (data, invalidated) {
+            inner.clock += 1;
+            let last_used = inner.clock;
+            inner.bands.entry(id).or_insert(Band {
+                data,
+                dirty: false,
+                version: 0,
+                last_used,
+            });
+            self.evict(&mut inner);
+        }
+
+        self.loaded.notify_all();
+    }
+
+    // Dirty bands of a file in band order
+    pub fn dirty_bands(&self, ino: u64) -> Vec<BandId> {
+        let inner = self.inner.lock().unwrap();
+        let mut ids: Vec<BandId> = inner
+            .bands
+            .iter()
+            .filter(|(id, band)| id.0 == ino && band.dirty)
+            .map(|(id, _)| *id)
+            .collect();
+        ids.sort();
+        ids
+    }
+
+    // Dirty bands of all files
+    pub fn all_dirty(&self) -> Vec<BandId> {
+        let inner = self.inner.lock().unwrap();
+        inner
+            .bands
+            .iter()
+            .filter(|(_, band)| band.dirty)
+            .map(|(id, _)| *id)
+            .collect()
+    }
+
+    // Amount of data which has not been uploaded yet
+    pub fn dirty_bytes(&self) -> u64 {
+        let inner = self.inner.lock().unwrap();
+        inner
+            .bands
+            .values()
+            .filter(|band| band.dirty)
+            .map(|band| band.data.len() as u64)
+            .sum()
+    }
+
+    // Copy of the contents of a cached band and its version
+    pub fn snapshot(&self, id: BandId) -> Option<(Vec<u8>, u64)> {
+        let inner = self.inner.lock().unwrap();
+        inner
+            .bands
+            .get(&id)
+            .map(|band| (band.data.clone(), band.version))
+    }
+
+    // Mark a band as uploaded, unless it was written again after the given
+    // version was taken
+    pub fn mark_clean(&self, id: BandId, version: u64) {
+        let mut inner = self.inner.lock().unwrap();
+        if let Some(band) = inner.bands.get_mut(&id) {
+            if band.version == version {
+                band.dirty = false;
+            }
+        }
+        self.evict(&mut inner);
+    }
+
+    // Drop a range of bands of a file, including dirty ones
+    pub fn remove_bands(&self, ino: u64, bands: Range<u64>) {
+        let mut inner = self.inner.lock().unwrap();
+        inner
+            .bands
+            .retain(|id, _| id.0 != ino || !bands.contains(&id.1));
+        for (id, invalidated) in inner.loading.iter_mut() {
+            if id.0 == ino && bands.contains(&id.1) {
+                *invalidated = true;
+            }
+        }
+    }
+
+    // Drop every band of a file, including dirty ones
+    pub fn remove_inode(&self, ino: u64) {
+        self.remove_bands(ino, 0..u64::MAX);
+    }
+}
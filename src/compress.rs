@@ -0,0 +1,270 @@
+use crate::backend::{slice, BackendError, ObjectInfo, ObjectStore, ObjectVersion};
+use crate::cache::BAND_PREFIX;
+use crate::dedup::{CHUNK_PREFIX, LIST_MAGIC};
+
+use std::borrow::Cow;
+use std::fmt;
+use std::ops::Range;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+
+// Compressed band objects start with this, then the codec and the length of
+// the data uncompressed. Objects without it were stored as they are.
+const MAGIC: &[u8; 4] = b"S3Z\x01";
+const HEADER_LEN: usize = MAGIC.len() + 1 + 8;
+
+// Default zstd level, favouring speed
+const ZSTD_LEVEL: i32 = 3;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Codec {
+    // Stored with a header, as the data didn't compress or wasn't meant to
+    None,
+    Zstd(i32),
+    Lz4,
+}
+
+impl Codec {
+    fn id(self) -> u8 {
+        match self {
+            Codec::None => 0,
+            Codec::Zstd(_) => 1,
+            Codec::Lz4 => 2,
+        }
+    }
+
+    fn compress(self, data: &[u8]) -> Result<Vec<u8>, BackendError> {
+        match self {
+            Codec::None => Ok(data.to_vec()),
+            Codec::Zstd(level) => zstd::bulk::compress(data, level)
+                .map_err(|err| BackendError::Other(format!("zstd: {err}"))),
+            Codec::Lz4 => Ok(lz4_flex::block::compress(data)),
+        }
+    }
+}
+
+// Parse a codec such as lz4, zstd or zstd:19
+pub fn parse_codec(arg: &str) -> Result<Codec, String> {
+    match arg.split_once(':') {
+        None if arg == "lz4" => Ok(Codec::Lz4),
+        None if arg == "zstd" => Ok(Codec::Zstd(ZSTD_LEVEL)),
+        Some(("zstd", level)) => match level.parse() {
+            Ok(level) if zstd::compression_level_range().contains(&level) => Ok(Codec::Zstd(level)),
+            _ => Err(format!("invalid zstd level {level}")),
+        },
+        _ => Err(format!(
+            "invalid codec {arg}, expected zstd, zstd:LEVEL or lz4"
+        )),
+    }
+}
+
+// Data of a band object, whether compressed or not
+fn decode(key: &str, stored: Vec<u8>) -> Result<Vec<u8>, BackendError> {
+    if stored.len() < HEADER_LEN || !stored.starts_with(MAGIC) {
+        return Ok(stored);
+    }
+    let corrupt = |err: &dyn fmt::Display| BackendError::Other(format!("corrupt {key}: {err}"));
+    let len = u64::from_le_bytes(stored[MAGIC.len() + 1..HEADER_LEN].try_into().unwrap());
+    let len = usize::try_from(len).map_err(|err| corrupt(&err))?;
+    let compressed = &stored[HEADER_LEN..];
+    let data = match stored[MAGIC.len()] {
+        0 => compressed.to_vec(),
+        1 => zstd::bulk::decompress(compressed, len).map_err(|err| corrupt(&err))?,
+        2 => lz4_flex::block::decompress(compressed, len).map_err(|err| corrupt(&err))?,
+        codec => return Err(corrupt(&format!("unknown codec {codec}"))),
+    };
+    match data.len() == len {
+        true => Ok(data),
+        false => Err(corrupt(&"length mismatch")),
+    }
+}
+
+// Band objects are compressed, and the chunks bands are split into
+fn is_compressed(key: &str) -> bool {
+    key.starts_with(BAND_PREFIX) || key.starts_with(CHUNK_PREFIX)
+}
+
+// Bytes of band data written and what they took once compressed
+#[derive(Default)]
+pub struct CompressionStats {
+    pub bytes_in: AtomicU64,
+    pub bytes_stored: AtomicU64,
+}
+
+impl CompressionStats {
+    pub fn report(&self) -> String {
+        let bytes_in = self.bytes_in.load(Ordering::Relaxed);
+        let bytes_stored = self.bytes_stored.load(Ordering::Relaxed);
+        let ratio = match bytes_stored {
+            0 => 1.0,
+            _ => bytes_in as f64 / bytes_stored as f64,
+        };
+        format!("compressed {bytes_in} bytes of bands to {bytes_stored}, ratio {ratio:.2}")
+    }
+}
+
+// Object store compressing band objects of another. Compressed objects are
+// read whatever the codec, also when writes are not compressed, and objects
+// stored before compression was enabled read as they are.
+pub struct CompressedStore {
+    inner: Arc<dyn ObjectStore>,
+    codec: Option<Codec>,
+    stats: CompressionStats,
+}
+
+impl CompressedStore {
+    pub fn new(inner: Arc<dyn ObjectStore>, codec: Option<Codec>) -> CompressedStore {
+        CompressedStore {
+            inner,
+            codec,
+            stats: CompressionStats::default(),
+        }
+    }
+
+    // The objects as stored, compressed
+    pub fn as_stored(&self) -> Arc<dyn ObjectStore> {
+        self.inner.clone()
+    }
+
+    pub fn stats(&self) -> &CompressionStats {
+        &self.stats
+    }
+
+    // What to store for an object. Data starting like a header gets one all
+    // the same, so it isn't mistaken for compressed data. Chunk lists are
+    // stored as they are, see LIST_MAGIC.
+    fn stored<'a>(&self, key: &str, data: &'a [u8]) -> Result<Cow<'a, [u8]>, BackendError> {
+        if !is_compressed(key) || data.starts_with(LIST_MAGIC) {
+            return Ok(Cow::Borrowed(data));
+        }
+        let codec = match self.codec {
+            Some(codec) => codec,
+            None if data.starts_with(MAGIC) => Codec::None,
+            None => return Ok(Cow::Borrowed(data)),
+        };
+        let compressed = codec.compress(data)?;
+        let (codec, compressed) = match compressed.len() < data.len() {
+            true => (codec, compressed),
+            false => (Codec::None, data.to_vec()),
+        };
+
+        let mut stored = Vec::with_capacity(HEADER_LEN + compressed.len());
+        stored.extend_from_slice(MAGIC);
+        stored.push(codec.id());
+        stored.extend_from_slice(&(data.len() as u64).to_le_bytes());
+        stored.extend_from_slice(&compressed);
+        self.stats
+            .bytes_in
+            .fetch_add(data.len() as u64, Ordering::Relaxed);
+        self.stats
+            .bytes_stored
+            .fetch_add(stored.len() as u64, Ordering::Relaxed);
+        Ok(Cow::Owned(stored))
+    }
+
+    // Whether an object can be copied as stored. Whole band objects are.
+    // Ranges of them, and other objects copied to bands to be compressed,
+    // go through the client.
+    fn copies_as_stored(&self, from: &str, range: &Option<Range<u64>>, to: &str) -> bool {
+        match is_compressed(from) {
+            true => range.is_none() && is_compressed(to),
+            false => self.codec.is_none() || !is_compressed(to),
+        }
+    }
+
+    // Read a range of an object, or all of it. The header of a band object
+    // is read first, along with the range when it starts the object, and
+    // only compressed data takes fetching the whole object, as it can't be
+    // read from the middle.
+    fn read(
+        &self,
+        key: &str,
+        range: Option<Range<u64>>,
+        fetch: impl Fn(Option<Range<u64>>) -> Result<Vec<u8>, BackendError>,
+    ) -> Result<Vec<u8>, BackendError> {
+        let range = match range {
+            _ if !is_compressed(key) => return fetch(range),
+            Some(range) => range,
+            None => return decode(key, fetch(None)?),
+        };
+        let header = HEADER_LEN as u64;
+        let probed = match range.start {
+            0 => header + range.end,
+            _ => header,
+        };
+        let mut data = fetch(Some(0..probed))?;
+        let codec = match data.len() >= HEADER_LEN && data.starts_with(MAGIC) {
+            true => Some(data[MAGIC.len()]),
+            false => None,
+        };
+        match codec {
+            None if range.start == 0 => {
+                data.truncate(range.end.min(data.len() as u64) as usize);
+                Ok(data)
+            }
+            None => fetch(Some(range)),
+            Some(0) if range.start == 0 => Ok(data.split_off(HEADER_LEN)),
+            Some(0) => fetch(Some(range.start + header..range.end + header)),
+            // The object was fetched whole if shorter than the range
+            Some(_) if (data.len() as u64) < probed => Ok(slice(&decode(key, data)?, Some(range))),
+            Some(_) => Ok(slice(&decode(key, fetch(None)?)?, Some(range))),
+        }
+    }
+}
+
+impl ObjectStore for CompressedStore {
+    fn get(&self, key: &str, range: Option<Range<u64>>) -> Result<Vec<u8>, BackendError> {
+        self.read(key, range, |range| self.inner.get(key, range))
+    }
+
+    fn put(&self, key: &str, data: &[u8]) -> Result<(), BackendError> {
+        self.inner.put(key, &self.stored(key, data)?)
+    }
+
+    fn get_tagged(&self, key: &str) -> Result<(Vec<u8>, String), BackendError> {
+        let (stored, etag) = self.inner.get_tagged(key)?;
+        match is_compressed(key) {
+            true => Ok((decode(key, stored)?, etag)),
+            false => Ok((stored, etag)),
+        }
+    }
+
+    fn put_if(&self, key: &str, data: &[u8], etag: Option<&str>) -> Result<String, BackendError> {
+        self.inner.put_if(key, &self.stored(key, data)?, etag)
+    }
+
+    fn delete(&self, key: &str) -> Result<(), BackendError> {
+        self.inner.delete(key)
+    }
+
+    fn copy(&self, from: &str, range: Option<Range<u64>>, to: &str) -> Result<(), BackendError> {
+        if self.copies_as_stored(from, &range, to) {
+            return self.inner.copy(from, range, to);
+        }
+        let data = self.get(from, range)?;
+        self.put(to, &data)
+    }
+
+    fn list(&self, prefix: &str) -> Result<Vec<ObjectInfo>, BackendError> {
+        self.inner.list(prefix)
+    }
+
+    fn version(&self, key: &str) -> Option<String> {
+        self.inner.version(key)
+    }
+
+    fn get_version(
+        &self,
+        key: &str,
+        version_id: &str,
+        range: Option<Range<u64>>,
+    ) -> Result<Vec<u8>, BackendError> {
+        self.read(key, range, |range| {
+            self.inner.get_version(key, version_id, range)
+        })
+    }
+
+    fn list_versions(&self, prefix: &str) -> Result<Vec<ObjectVersion>, BackendError> {
+        self.inner.list_versions(prefix)
+    }
+}
@@ -0,0 +1,589 @@
+use crate::backend::{BackendError, ObjectInfo, ObjectStore, ObjectVersion};
+
+use std::collections::HashSet;
+use std::ops::Range;
+use std::sync::{Arc, RwLock};
+
+use aes_gcm::aead::rand_core::RngCore;
+use aes_gcm::aead::{Aead, KeyInit, OsRng, Payload};
+use aes_gcm::{Aes256Gcm, Nonce};
+use argon2::{Algorithm, Argon2, Params, Version};
+use hmac::{Hmac, Mac};
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+
+// Key of the object holding the data keys, wrapped by a master key which is
+// wrapped in turn by keys derived from the passphrases. It is the only object
+// stored in the clear.
+pub const KEY_HEADER_KEY: &str = "keyheader";
+
+// Environment variable the passphrase is read from
+pub const PASSPHRASE_ENV: &str = "S3TM_PASSPHRASE";
+
+// Objects are encrypted in segments of this much data, each with its own
+// nonce and tag, so a range can be read without fetching the whole object
+const SEGMENT_SIZE: u64 = 64 * 1024;
+const NONCE_LEN: u64 = 12;
+const TAG_LEN: u64 = 16;
+const SEGMENT_STRIDE: u64 = NONCE_LEN + SEGMENT_SIZE + TAG_LEN;
+
+// Cost of deriving the key encrypting the data key from the passphrase
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct KdfParams {
+    // Memory used, in KiB
+    pub m_cost: u32,
+    pub t_cost: u32,
+    pub p_cost: u32,
+}
+
+impl Default for KdfParams {
+    fn default() -> KdfParams {
+        KdfParams {
+            m_cost: 64 * 1024,
+            t_cost: 3,
+            p_cost: 1,
+        }
+    }
+}
+
+// Contents of the key header object. The master key is stored wrapped by
+// each passphrase which may unlock the bucket, and wraps the data keys in
+// turn, so a key slot can be added or removed without the other passphrases.
+#[derive(Serialize, Deserialize)]
+struct KeyHeader {
+    slots: Vec<KeySlot>,
+    // Keys objects are encrypted with, the newest last. Older ones are kept
+    // until no object nor version kept of one is encrypted with them.
+    data_keys: Vec<WrappedKey>,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+struct KeySlot {
+    name: String,
+    kdf: KdfParams,
+    salt: Vec<u8>,
+    master_key: WrappedKey,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+struct WrappedKey {
+    nonce: Vec<u8>,
+    wrapped: Vec<u8>,
+}
+
+impl KeyHeader {
+    fn parse(data: &[u8]) -> Result<KeyHeader, BackendError> {
+        serde_json::from_slice(data)
+            .map_err(|err| BackendError::Other(format!("corrupt key header: {err}")))
+    }
+
+    fn to_vec(&self) -> Result<Vec<u8>, BackendError> {
+        serde_json::to_vec(self).map_err(|err| BackendError::Other(err.to_string()))
+    }
+}
+
+// Name of the key slot created along with the bucket's keys
+pub const DEFAULT_SLOT: &str = "default";
+
+fn random_bytes<const N: usize>() -> [u8; N] {
+    let mut bytes = [0; N];
+    OsRng.fill_bytes(&mut bytes);
+    bytes
+}
+
+// Key encrypting the master key, derived from a passphrase with Argon2id
+fn derive_key(
+    passphrase: &str,
+    salt: &[u8],
+    params: &KdfParams,
+) -> Result<Aes256Gcm, BackendError> {
+    let invalid = |err: argon2::Error| BackendError::Other(format!("key derivation: {err}"));
+    let params =
+        Params::new(params.m_cost, params.t_cost, params.p_cost, Some(32)).map_err(invalid)?;
+    let mut key = [0; 32];
+    Argon2::new(Algorithm::Argon2id, Version::V0x13, params)
+        .hash_password_into(passphrase.as_bytes(), salt, &mut key)
+        .map_err(invalid)?;
+    Ok(Aes256Gcm::new(&key.into()))
+}
+
+fn wrap(wrapping: &Aes256Gcm, key: &[u8; 32]) -> Result<WrappedKey, BackendError> {
+    let nonce: [u8; 12] = random_bytes();
+    let payload = Payload {
+        msg: key,
+        aad: KEY_HEADER_KEY.as_bytes(),
+    };
+    let wrapped = wrapping
+        .encrypt(Nonce::from_slice(&nonce), payload)
+        .map_err(|_| BackendError::Other("encryption failed".to_string()))?;
+    Ok(WrappedKey {
+        nonce: nonce.to_vec(),
+        wrapped,
+    })
+}
+
+// The key, or None if it wasn't wrapped with this key
+fn unwrap(wrapping: &Aes256Gcm, key: &WrappedKey) -> Option<[u8; 32]> {
+    let payload = Payload {
+        msg: &key.wrapped,
+        aad: KEY_HEADER_KEY.as_bytes(),
+    };
+    let nonce = (key.nonce.len() == NONCE_LEN as usize).then(|| Nonce::from_slice(&key.nonce))?;
+    wrapping.decrypt(nonce, payload).ok()?.try_into().ok()
+}
+
+fn new_slot(
+    name: &str,
+    passphrase: &str,
+    params: &KdfParams,
+    master: &[u8; 32],
+) -> Result<KeySlot, BackendError> {
+    let salt: [u8; 16] = random_bytes();
+    Ok(KeySlot {
+        name: name.to_string(),
+        kdf: params.clone(),
+        salt: salt.to_vec(),
+        master_key: wrap(&derive_key(passphrase, &salt, params)?, master)?,
+    })
+}
+
+// Data bound to each segment along with its contents: the key of its object,
+// its position and whether it is the last one, so segments can't be moved,
+// reordered or dropped without failing authentication
+fn segment_aad(key: &str, index: u64, last: bool) -> Vec<u8> {
+    let mut aad = key.as_bytes().to_vec();
+    aad.extend_from_slice(&index.to_be_bytes());
+    aad.push(last as u8);
+    aad
+}
+
+// Size of the data held by an object of the given size
+fn plain_size(size: u64) -> u64 {
+    let segments = size.div_ceil(SEGMENT_STRIDE).max(1);
+    size.saturating_sub(segments * (NONCE_LEN + TAG_LEN))
+}
+
+fn tampered(key: &str) -> BackendError {
+    BackendError::Other(format!("{key} failed authentication"))
+}
+
+// Whether the objects of a store are encrypted
+pub fn is_encrypted(store: &dyn ObjectStore) -> Result<bool, BackendError> {
+    Ok(store
+        .list(KEY_HEADER_KEY)?
+        .iter()
+        .any(|object| object.key == KEY_HEADER_KEY))
+}
+
+// Object store encrypting the objects of another with AES-256-GCM under data
+// keys kept in the key header object. Each segment gets a random nonce, and
+// objects which were altered fail to read with an error.
+pub struct EncryptedStore {
+    inner: Arc<dyn ObjectStore>,
+    master: [u8; 32],
+    // Keys objects may be encrypted with, newest first. New objects are
+    // encrypted with the newest.
+    ciphers: RwLock<Vec<Aes256Gcm>>,
+}
+
+impl EncryptedStore {
+    // Unlock the master key with a passphrase of one of the key slots. An
+    // empty store gets new keys, with the key derivation costing as given.
+    pub fn open(
+        inner: Arc<dyn ObjectStore>,
+        passphrase: &str,
+        params: &KdfParams,
+    ) -> Result<EncryptedStore, BackendError> {
+        let header = match inner.get(KEY_HEADER_KEY, None) {
+            Ok(data) => KeyHeader::parse(&data)?,
+            Err(BackendError::NotFound) => {
+                return EncryptedStore::create(inner, passphrase, params)
+            }
+            Err(err) => return Err(err),
+        };
+
+        for slot in &header.slots {
+            let wrapping = derive_key(passphrase, &slot.salt, &slot.kdf)?;
+            if let Some(master) = unwrap(&wrapping, &slot.master_key) {
+                let store = EncryptedStore {
+                    inner,
+                    master,
+                    ciphers: RwLock::default(),
+                };
+                store.load_keys(&header)?;
+                return Ok(store);
+            }
+        }
+        Err(BackendError::Other("wrong passphrase".to_string()))
+    }
+
+    fn create(
+        inner: Arc<dyn ObjectStore>,
+        passphrase: &str,
+        params: &KdfParams,
+    ) -> Result<EncryptedStore, BackendError> {
+        // Objects stored before could no longer be read
+        if !inner.list("")?.is_empty() {
+            return Err(BackendError::Other(
+                "the bucket holds unencrypted objects".to_string(),
+            ));
+        }
+
+        let master: [u8; 32] = random_bytes();
+        let data_key: [u8; 32] = random_bytes();
+        let header = KeyHeader {
+            slots: vec![new_slot(DEFAULT_SLOT, passphrase, params, &master)?],
+            data_keys: vec![wrap(&Aes256Gcm::new(&master.into()), &data_key)?],
+        };
+
+        match inner.put_if(KEY_HEADER_KEY, &header.to_vec()?, None) {
+            Ok(_) => {
+                let store = EncryptedStore {
+                    inner,
+                    master,
+                    ciphers: RwLock::default(),
+                };
+                store.load_keys(&header)?;
+                Ok(store)
+            }
+            // Another client set up the keys first
+            Err(BackendError::PreconditionFailed) => {
+                EncryptedStore::open(inner, passphrase, params)
+            }
+            Err(err) => Err(err),
+        }
+    }
+
+    // Unwrap the data keys of a key header
+    fn load_keys(&self, header: &KeyHeader) -> Result<(), BackendError> {
+        let master = Aes256Gcm::new(&self.master.into());
+        let mut ciphers = Vec::new();
+        for data_key in header.data_keys.iter().rev() {
+            let key = unwrap(&master, data_key)
+                .ok_or_else(|| BackendError::Other("corrupt key header".to_string()))?;
+            ciphers.push(Aes256Gcm::new(&key.into()));
+        }
+        if ciphers.is_empty() {
+            return Err(BackendError::Other("corrupt key header".to_string()));
+        }
+        *self.ciphers.write().unwrap() = ciphers;
+        Ok(())
+    }
+
+    // Change the key header, applying the change again if another client
+    // changed it meanwhile
+    fn update_header(
+        &self,
+        change: impl Fn(&mut KeyHeader) -> Result<(), BackendError>,
+    ) -> Result<KeyHeader, BackendError> {
+        loop {
+            let (data, etag) = self.inner.get_tagged(KEY_HEADER_KEY)?;
+            let mut header = KeyHeader::parse(&data)?;
+            change(&mut header)?;
+            match self
+                .inner
+                .put_if(KEY_HEADER_KEY, &header.to_vec()?, Some(&etag))
+            {
+                Ok(_) => return Ok(header),
+                Err(BackendError::PreconditionFailed) => continue,
+                Err(err) => return Err(err),
+            }
+        }
+    }
+
+    // Key the names of chunks stored in the bucket are hashed with, so they
+    // don't tell which bands hold the same data without the passphrase
+    pub fn chunk_name_key(&self) -> [u8; 32] {
+        let mut mac = <Hmac<Sha256> as KeyInit>::new_from_slice(&self.master).unwrap();
+        mac.update(b"chunk names");
+        mac.finalize().into_bytes().into()
+    }
+
+    // Names of the key slots
+    pub fn slots(&self) -> Result<Vec<String>, BackendError> {
+        let header = KeyHeader::parse(&self.inner.get(KEY_HEADER_KEY, None)?)?;
+        Ok(header.slots.into_iter().map(|slot| slot.name).collect())
+    }
+
+    // Let another passphrase unlock the bucket
+    pub fn add_slot(
+        &self,
+        name: &str,
+        passphrase: &str,
+        params: &KdfParams,
+    ) -> Result<(), BackendError> {
+        let slot = new_slot(name, passphrase, params, &self.master)?;
+        self.update_header(|header| {
+            if header.slots.iter().any(|slot| slot.name == name) {
+                return Err(BackendError::Other(format!("key slot {name} exists")));
+            }
+            header.slots.push(slot.clone());
+            Ok(())
+        })?;
+        Ok(())
+    }
+
+    // Stop a passphrase from unlocking the bucket. The last slot is kept.
+    pub fn remove_slot(&self, name: &str) -> Result<(), BackendError> {
+        self.update_header(|header| {
+            let count = header.slots.len();
+            header.slots.retain(|slot| slot.name != name);
+            match header.slots.len() {
+                0 => Err(BackendError::Other(
+                    "the last key slot can't be removed".to_string(),
+                )),
+                len if len == count => Err(BackendError::Other(format!("no key slot {name}"))),
+                _ => Ok(()),
+            }
+        })?;
+        Ok(())
+    }
+
+    // Encrypt new objects with a new data key. Objects encrypted with older
+    // keys remain readable until they are encrypted again by rekey().
+    pub fn rotate(&self) -> Result<(), BackendError> {
+        let master = Aes256Gcm::new(&self.master.into());
+        let data_key: [u8; 32] = random_bytes();
+        let wrapped = wrap(&master, &data_key)?;
+        let header = self.update_header(|header| {
+            header.data_keys.push(wrapped.clone());
+            Ok(())
+        })?;
+        self.load_keys(&header)
+    }
+
+    // Encrypt every object with the newest data key, then drop the older
+    // keys no version kept of an object is encrypted with, as snapshots may
+    // read those. Objects are replaced only if unchanged since they were
+    // read, any changed meanwhile were written with the newest key. Returns
+    // the number of objects encrypted again.
+    pub fn rekey(&self) -> Result<u64, BackendError> {
+        // Keys added by another client meanwhile are newer, so only those
+        // known now may be dropped
+        let header = KeyHeader::parse(&self.inner.get(KEY_HEADER_KEY, None)?)?;
+        self.load_keys(&header)?;
+        let older = header.data_keys.len() - 1;
+        let mut count = 0;
+        for object in self.inner.list("")? {
+            if object.key == KEY_HEADER_KEY {
+                continue;
+            }
+            let (sealed, etag) = match self.inner.get_tagged(&object.key) {
+                Ok(tagged) => tagged,
+                Err(BackendError::NotFound) => continue,
+                Err(err) => return Err(err),
+            };
+            let (data, index) = self.open_range(&object.key, None, |_| Ok(sealed.clone()))?;
+            if index == 0 {
+                continue;
+            }
+            let sealed = self.seal(&object.key, &data)?;
+            match self.inner.put_if(&object.key, &sealed, Some(&etag)) {
+                Ok(_) => count += 1,
+                Err(BackendError::PreconditionFailed | BackendError::NotFound) => (),
+                Err(err) => return Err(err),
+            }
+        }
+
+        // Versions replaced are never written again. The current ones were
+        // just encrypted with the newest key, so only older ones are read.
+        let mut used = HashSet::new();
+        for version in self.inner.list_versions("")? {
+            if used.len() == older {
+                break;
+            }
+            if version.latest || version.deleted || version.key == KEY_HEADER_KEY {
+                continue;
+            }
+            let fetch = |range| {
+                self.inner
+                    .get_version(&version.key, &version.version_id, range)
+            };
+            let (_, index) = self.open_range(&version.key, Some(0..1), fetch)?;
+            if index != 0 {
+                used.insert(index);
+            }
+        }
+
+        // Cipher i is data key older - i, the ciphers being newest first
+        let retired: Vec<Vec<u8>> = header.data_keys[..older]
+            .iter()
+            .enumerate()
+            .filter(|(key, _)| !used.contains(&(older - key)))
+            .map(|(_, key)| key.nonce.clone())
+            .collect();
+        let header = self.update_header(|header| {
+            header.data_keys.retain(|key| !retired.contains(&key.nonce));
+            Ok(())
+        })?;
+        self.load_keys(&header)?;
+        Ok(count)
+    }
+
+    // Encrypt the data of an object. The last segment holds less than a
+    // whole segment of data, if need be nothing.
+    fn seal(&self, key: &str, data: &[u8]) -> Result<Vec<u8>, BackendError> {
+        let segments = data.len() as u64 / SEGMENT_SIZE + 1;
+        let overhead = segments * (NONCE_LEN + TAG_LEN);
+        let mut sealed = Vec::with_capacity((data.len() as u64 + overhead) as usize);
+        for index in 0..segments {
+            let start = (index * SEGMENT_SIZE) as usize;
+            let end = (start + SEGMENT_SIZE as usize).min(data.len());
+            let nonce: [u8; 12] = random_bytes();
+            let aad = segment_aad(key, index, index + 1 == segments);
+            let payload = Payload {
+                msg: &data[start..end],
+                aad: &aad,
+            };
+            let encrypted = self.ciphers.read().unwrap()[0]
+                .encrypt(Nonce::from_slice(&nonce), payload)
+                .map_err(|_| BackendError::Other("encryption failed".to_string()))?;
+            sealed.extend_from_slice(&nonce);
+            sealed.extend_from_slice(&encrypted);
+        }
+        Ok(sealed)
+    }
+
+    // Read a range of an object, or all of it, given how to fetch a range of
+    // the encrypted object. Also tells which data key the object is
+    // encrypted with, 0 for the newest.
+    fn open_range(
+        &self,
+        key: &str,
+        range: Option<Range<u64>>,
+        fetch: impl Fn(Option<Range<u64>>) -> Result<Vec<u8>, BackendError>,
+    ) -> Result<(Vec<u8>, usize), BackendError> {
+        let (first, sealed, wanted) = match &range {
+            None => (0, fetch(None)?, None),
+            Some(range) if range.is_empty() => return Ok((Vec::new(), 0)),
+            Some(range) => {
+                let first = range.start / SEGMENT_SIZE;
+                let end = (range.end - 1) / SEGMENT_SIZE + 1;
+                let sealed = fetch(Some(first * SEGMENT_STRIDE..end * SEGMENT_STRIDE))?;
+                (first, sealed, Some(end - first))
+            }
+        };
+
+        // Every segment of an object is encrypted with the same key
+        let ciphers = self.ciphers.read().unwrap();
+        let mut cipher = None;
+        let mut data = Vec::with_capacity(sealed.len());
+        let mut last = false;
+        for (index, segment) in sealed.chunks(SEGMENT_STRIDE as usize).enumerate() {
+            if segment.len() < (NONCE_LEN + TAG_LEN) as usize {
+                return Err(tampered(key));
+            }
+            last = segment.len() < SEGMENT_STRIDE as usize;
+            let (nonce, encrypted) = segment.split_at(NONCE_LEN as usize);
+            let aad = segment_aad(key, first + index as u64, last);
+            let decrypt = |cipher: &Aes256Gcm| {
+                let payload = Payload {
+                    msg: encrypted,
+                    aad: &aad,
+                };
+                cipher.decrypt(Nonce::from_slice(nonce), payload).ok()
+            };
+            let decrypted = match cipher {
+                Some(index) => decrypt(&ciphers[index]),
+                None => ciphers.iter().enumerate().find_map(|(index, tried)| {
+                    let decrypted = decrypt(tried)?;
+                    cipher = Some(index);
+                    Some(decrypted)
+                }),
+            };
+            data.extend_from_slice(&decrypted.ok_or_else(|| tampered(key))?);
+        }
+
+        // An object ends with a segment which isn't whole, so one cut short
+        // at a segment boundary is told apart from a range reaching past the
+        // end of the object
+        let segments = sealed.len().div_ceil(SEGMENT_STRIDE as usize) as u64;
+        let complete = match wanted {
+            None => last,
+            Some(wanted) => segments == wanted || last || sealed.is_empty(),
+        };
+        if !complete {
+            return Err(tampered(key));
+        }
+
+        let cipher = cipher.unwrap_or(0);
+        match range {
+            Some(range) => {
+                let offset = first * SEGMENT_SIZE;
+                let len = data.len() as u64;
+                let start = (range.start - offset).min(len) as usize;
+                let end = (range.end - offset).min(len) as usize;
+                Ok((data[start..end].to_vec(), cipher))
+            }
+            None => Ok((data, cipher)),
+        }
+    }
+}
+
+impl ObjectStore for EncryptedStore {
+    fn get(&self, key: &str, range: Option<Range<u64>>) -> Result<Vec<u8>, BackendError> {
+        let (data, _) = self.open_range(key, range, |range| self.inner.get(key, range))?;
+        Ok(data)
+    }
+
+    fn put(&self, key: &str, data: &[u8]) -> Result<(), BackendError> {
+        self.inner.put(key, &self.seal(key, data)?)
+    }
+
+    fn get_tagged(&self, key: &str) -> Result<(Vec<u8>, String), BackendError> {
+        let (sealed, etag) = self.inner.get_tagged(key)?;
+        let (data, _) = self.open_range(key, None, |_| Ok(sealed.clone()))?;
+        Ok((data, etag))
+    }
+
+    fn put_if(&self, key: &str, data: &[u8], etag: Option<&str>) -> Result<String, BackendError> {
+        self.inner.put_if(key, &self.seal(key, data)?, etag)
+    }
+
+    fn delete(&self, key: &str) -> Result<(), BackendError> {
+        self.inner.delete(key)
+    }
+
+    // Segments are bound to the key of their object, so objects are copied
+    // through the client
+    fn copy(&self, from: &str, range: Option<Range<u64>>, to: &str) -> Result<(), BackendError> {
+        let data = self.get(from, range)?;
+        self.put(to, &data)
+    }
+
+    fn list(&self, prefix: &str) -> Result<Vec<ObjectInfo>, BackendError> {
+        let mut objects = self.inner.list(prefix)?;
+        objects.retain(|object| object.key != KEY_HEADER_KEY);
+        for object in &mut objects {
+            object.size = plain_size(object.size);
+        }
+        Ok(objects)
+    }
+
+    fn version(&self, key: &str) -> Option<String> {
+        self.inner.version(key)
+    }
+
+    fn get_version(
+        &self,
+        key: &str,
+        version_id: &str,
+        range: Option<Range<u64>>,
+    ) -> Result<Vec<u8>, BackendError> {
+        let (data, _) = self.open_range(key, range, |range| {
+            self.inner.get_version(key, version_id, range)
+        })?;
+        Ok(data)
+    }
+
+    fn list_versions(&self, prefix: &str) -> Result<Vec<ObjectVersion>, BackendError> {
+        let mut versions = self.inner.list_versions(prefix)?;
+        versions.retain(|version| version.key != KEY_HEADER_KEY);
+        for version in &mut versions {
+            if !version.deleted {
+                version.size = plain_size(version.size);
+            }
+        }
+        Ok(versions)
+    }
+}
@@ -0,0 +1,471 @@
+use crate::backend::{slice, BackendError, ObjectInfo, ObjectStore, ObjectVersion};
+use crate::cache::BAND_PREFIX;
+use crate::snapshot::{Manifest, SNAPSHOT_PREFIX};
+
+use std::borrow::Cow;
+use std::collections::HashSet;
+use std::ops::Range;
+use std::sync::{Arc, Mutex, RwLock};
+
+use fastcdc::v2020::FastCDC;
+use hmac::{Hmac, Mac};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+// Chunks are stored below this prefix, named after the SHA-256 of their
+// data, or its HMAC when they are encrypted
+pub const CHUNK_PREFIX: &str = "chunks/";
+
+// Band objects split into chunks start with this, then the chunk list.
+// Chunk lists are not compressed, so they are told apart from other band
+// objects by the first bytes stored.
+pub const LIST_MAGIC: &[u8; 4] = b"S3C\x01";
+
+// Bounds of the size of chunks, which are cut where the data looks alike
+// whatever its offset, so the same contents make the same chunks in any band
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct ChunkSizes {
+    pub min: u32,
+    pub avg: u32,
+    pub max: u32,
+}
+
+impl ChunkSizes {
+    fn from_average(avg: u32) -> ChunkSizes {
+        ChunkSizes {
+            min: avg / 4,
+            avg,
+            max: avg * 4,
+        }
+    }
+}
+
+impl Default for ChunkSizes {
+    fn default() -> ChunkSizes {
+        ChunkSizes::from_average(64 * 1024)
+    }
+}
+
+// Parse the average size of chunks, in bytes
+pub fn parse_chunk_size(arg: &str) -> Result<ChunkSizes, String> {
+    let range = fastcdc::v2020::AVERAGE_MIN..=fastcdc::v2020::AVERAGE_MAX;
+    match arg.parse() {
+        Ok(avg) if range.contains(&avg) => Ok(ChunkSizes::from_average(avg)),
+        _ => Err(format!(
+            "invalid chunk size {arg}, expected {} to {} bytes",
+            range.start(),
+            range.end()
+        )),
+    }
+}
+
+// Contents of a band object split into chunks: the hash and length of each
+// chunk in order
+#[derive(Serialize, Deserialize)]
+struct ChunkList {
+    chunks: Vec<(String, u64)>,
+}
+
+impl ChunkList {
+    // The chunk list a band object holds, None if it holds the data itself
+    fn parse(key: &str, stored: &[u8]) -> Result<Option<ChunkList>, BackendError> {
+        let Some(list) = stored.strip_prefix(LIST_MAGIC) else {
+            return Ok(None);
+        };
+        serde_json::from_slice(list)
+            .map(Some)
+            .map_err(|err| BackendError::Other(format!("corrupt chunk list {key}: {err}")))
+    }
+
+    fn to_vec(&self) -> Result<Vec<u8>, BackendError> {
+        let mut stored = LIST_MAGIC.to_vec();
+        serde_json::to_writer(&mut stored, self)
+            .map_err(|err| BackendError::Other(err.to_string()))?;
+        Ok(stored)
+    }
+}
+
+fn chunk_key(hash: &str) -> String {
+    format!("{CHUNK_PREFIX}{hash}")
+}
+
+fn is_band(key: &str) -> bool {
+    key.starts_with(BAND_PREFIX)
+}
+
+// Add the chunks a band object refers to, given a function reading a range
+// of it as stored. Only chunk lists are read whole.
+fn mark_band(
+    key: &str,
+    read: impl Fn(Option<Range<u64>>) -> Result<Vec<u8>, BackendError>,
+    marked: &mut HashSet<String>,
+) -> Result<(), BackendError> {
+    let stored = match read(Some(0..LIST_MAGIC.len() as u64)) {
+        Ok(header) if header.starts_with(LIST_MAGIC) => read(None),
+        Ok(_) | Err(BackendError::NotFound) => return Ok(()),
+        Err(err) => return Err(err),
+    };
+    match stored {
+        Ok(stored) => {
+            if let Some(list) = ChunkList::parse(key, &stored)? {
+                marked.extend(list.chunks.into_iter().map(|(hash, _)| hash));
+            }
+            Ok(())
+        }
+        Err(BackendError::NotFound) => Ok(()),
+        Err(err) => Err(err),
+    }
+}
+
+// Add the chunks referred to by the band objects of a filesystem, and by the
+// versions of them its snapshots recorded, leaving out the objects about to
+// be deleted. The store holds the band objects as stored, below the
+// DedupStore and compression, so only chunk lists are read past their
+// first bytes.
+pub fn mark_chunks(
+    image: &dyn ObjectStore,
+    deleted: &HashSet<String>,
+    marked: &mut HashSet<String>,
+) -> Result<(), BackendError> {
+    for object in image.list("")? {
+        if deleted.contains(&object.key) {
+            continue;
+        }
+        if is_band(&object.key) {
+            mark_band(&object.key, |range| image.get(&object.key, range), marked)?;
+        } else if let Some(id) = object.key.strip_prefix(SNAPSHOT_PREFIX) {
+            for (key, version_id) in Manifest::load(image, id)?.versions {
+                let read = |range| image.get_version(&key, &version_id, range);
+                mark_band(&key, read, marked)?;
+            }
+        }
+    }
+    Ok(())
+}
+
+// The chunk objects listed none of the marked chunks are
+pub fn unmarked_chunks(mut listed: Vec<ObjectInfo>, marked: &HashSet<String>) -> Vec<ObjectInfo> {
+    listed.retain(|object| !marked.contains(&object.key[CHUNK_PREFIX.len()..]));
+    listed
+}
+
+// Object store splitting band objects of another into content-defined
+// chunks, each stored once however many bands hold it. Bands become lists of
+// chunks, which are deleted by garbage collection once no band refers to
+// them. Band objects stored whole read as they are.
+pub struct DedupStore {
+    inner: Arc<dyn ObjectStore>,
+    // The band objects as stored, below compression, where marking looks
+    // for chunk lists
+    stored: Arc<dyn ObjectStore>,
+    // Where chunks are stored, None if along with the band objects. Chunks
+    // shared with other filesystems are left for `chunk-gc` to delete.
+    shared: Option<Arc<dyn ObjectStore>>,
+    // How bands written are split, None to write them whole
+    sizes: Option<ChunkSizes>,
+    // Key chunk names are hashed with, None to name chunks after the
+    // SHA-256 of their data
+    name_key: Option<[u8; 32]>,
+    // Hashes of the chunks known to be stored, listed on the first write
+    known: Mutex<Option<HashSet<String>>>,
+    // Held to write band objects, and exclusively to start marking chunks
+    // and to delete them
+    writing: RwLock<()>,
+    // Chunks written bands referred to since marking started, which are
+    // not deleted whether marked or not
+    referenced: Mutex<Option<HashSet<String>>>,
+}
+
+impl DedupStore {
+    pub fn new(
+        inner: Arc<dyn ObjectStore>,
+        stored: Arc<dyn ObjectStore>,
+        shared: Option<Arc<dyn ObjectStore>>,
+        sizes: Option<ChunkSizes>,
+        name_key: Option<[u8; 32]>,
+    ) -> DedupStore {
+        DedupStore {
+            inner,
+            stored,
+            shared,
+            sizes,
+            name_key,
+            known: Mutex::new(None),
+            writing: RwLock::new(()),
+            referenced: Mutex::new(None),
+        }
+    }
+
+    fn chunks(&self) -> &dyn ObjectStore {
+        match &self.shared {
+            Some(shared) => &**shared,
+            None => &*self.inner,
+        }
+    }
+
+    fn chunk_hash(&self, data: &[u8]) -> String {
+        let hash: [u8; 32] = match &self.name_key {
+            Some(key) => {
+                let mut mac = Hmac::<Sha256>::new_from_slice(key).unwrap();
+                mac.update(data);
+                mac.finalize().into_bytes().into()
+            }
+            None => Sha256::digest(data).into(),
+        };
+        hash.iter().map(|byte| format!("{byte:02x}")).collect()
+    }
+
+    // Apply a function to the hashes of the chunks stored, listed the first
+    // time they are needed
+    fn known<T>(&self, f: impl FnOnce(&mut HashSet<String>) -> T) -> Result<T, BackendError> {
+        let mut known = self.known.lock().unwrap();
+        if known.is_none() {
+            let listing = self.chunks().list(CHUNK_PREFIX)?;
+            let hashes = listing
+                .into_iter()
+                .map(|object| object.key[CHUNK_PREFIX.len()..].to_string());
+            *known = Some(hashes.collect());
+        }
+        Ok(f(known.as_mut().unwrap()))
+    }
+
+    // Whether bands may be chunk lists: only if written with --dedup, or if
+    // some chunk is stored
+    fn in_use(&self) -> Result<bool, BackendError> {
+        match self.sizes {
+            Some(_) => Ok(true),
+            None => self.known(|known| !known.is_empty()),
+        }
+    }
+
+    // Store a chunk unless it is known to be stored already
+    fn put_chunk(&self, hash: &str, data: &[u8]) -> Result<(), BackendError> {
+        if let Some(referenced) = self.referenced.lock().unwrap().as_mut() {
+            referenced.insert(hash.to_string());
+        }
+        if self.known(|known| known.contains(hash))? {
+            return Ok(());
+        }
+
+        // Chunks are written without holding the lock, so bands are
+        // uploaded in parallel. A chunk written twice is the same.
+        self.chunks().put(&chunk_key(hash), data)?;
+        self.known(|known| known.insert(hash.to_string()))?;
+        Ok(())
+    }
+
+    // What to store for an object. Data starting like a chunk list is split
+    // all the same, so it isn't mistaken for one.
+    fn stored<'a>(&self, key: &str, data: &'a [u8]) -> Result<Cow<'a, [u8]>, BackendError> {
+        if !is_band(key) {
+            return Ok(Cow::Borrowed(data));
+        }
+        let sizes = match self.sizes {
+            Some(sizes) => sizes,
+            None if data.starts_with(LIST_MAGIC) => ChunkSizes::default(),
+            None => return Ok(Cow::Borrowed(data)),
+        };
+
+        let mut list = ChunkList { chunks: Vec::new() };
+        for chunk in FastCDC::new(data, sizes.min, sizes.avg, sizes.max) {
+            let data = &data[chunk.offset..chunk.offset + chunk.length];
+            let hash = self.chunk_hash(data);
+            self.put_chunk(&hash, data)?;
+            list.chunks.push((hash, chunk.length as u64));
+        }
+        Ok(Cow::Owned(list.to_vec()?))
+    }
+
+    // Whether an object can be copied as stored. Whole band objects are,
+    // sharing their chunks. Ranges of them, and other objects copied to
+    // bands to be split, go through the client.
+    fn copies_as_stored(&self, from: &str, range: &Option<Range<u64>>, to: &str) -> bool {
+        match is_band(from) {
+            true => range.is_none() && is_band(to),
+            false => self.sizes.is_none() || !is_band(to),
+        }
+    }
+
+    // Read a range of an object, or all of it, given how to fetch the
+    // object as stored. A range of a band is read along with the start of
+    // the band, or after it, to tell whether the band is a chunk list. Only
+    // the chunks holding the range are fetched.
+    fn read(
+        &self,
+        key: &str,
+        range: Option<Range<u64>>,
+        fetch: impl Fn(Option<Range<u64>>) -> Result<Vec<u8>, BackendError>,
+    ) -> Result<Vec<u8>, BackendError> {
+        if !is_band(key) {
+            return fetch(range);
+        }
+        let stored = match &range {
+            None => fetch(None)?,
+            Some(_) if !self.in_use()? => return fetch(range),
+            Some(range) => {
+                let probed = match range.start {
+                    0 => range.end.max(LIST_MAGIC.len() as u64),
+                    _ => LIST_MAGIC.len() as u64,
+                };
+                let stored = fetch(Some(0..probed))?;
+                match stored.starts_with(LIST_MAGIC) {
+                    true if (stored.len() as u64) < probed => stored,
+                    true => fetch(None)?,
+                    false if range.start == 0 => return Ok(slice(&stored, Some(range.clone()))),
+                    false => return fetch(Some(range.clone())),
+                }
+            }
+        };
+        let Some(list) = ChunkList::parse(key, &stored)? else {
+            return Ok(slice(&stored, range));
+        };
+
+        let range = range.unwrap_or(0..u64::MAX);
+        let mut data = Vec::new();
+        let mut offset = 0;
+        for (hash, len) in list.chunks {
+            let start = range.start.max(offset);
+            let end = range.end.min(offset + len);
+            if start < end {
+                let part = start - offset..end - offset;
+                let part = match part.start == 0 && part.end == len {
+                    true => self.chunks().get(&chunk_key(&hash), None),
+                    false => self.chunks().get(&chunk_key(&hash), Some(part)),
+                };
+                match part {
+                    Ok(part) => data.extend_from_slice(&part),
+                    Err(BackendError::NotFound) => {
+                        return Err(BackendError::Other(format!(
+                            "{key} refers to missing chunk {hash}"
+                        )))
+                    }
+                    Err(err) => return Err(err),
+                }
+            }
+            offset += len;
+        }
+        Ok(data)
+    }
+
+    // Delete a chunk, unless a band written since marking started refers
+    // to it
+    fn delete_chunk(&self, key: &str) -> Result<(), BackendError> {
+        let _writing = self.writing.write().unwrap();
+        let hash = &key[CHUNK_PREFIX.len()..];
+        if let Some(referenced) = self.referenced.lock().unwrap().as_ref() {
+            if referenced.contains(hash) {
+                return Ok(());
+            }
+        }
+        if let Some(known) = self.known.lock().unwrap().as_mut() {
+            known.remove(hash);
+        }
+        self.chunks().delete(key)
+    }
+
+    // Chunks none of the band objects left refers to once the given objects
+    // are deleted, to delete through this store. Bands written from now on
+    // record the chunks they refer to, so those are kept when the chunks
+    // found are deleted, until marking ends. Chunks shared with other
+    // filesystems are left for `chunk-gc`.
+    pub fn unreferenced(&self, deleted: &[String]) -> Result<Vec<ObjectInfo>, BackendError> {
+        if self.shared.is_some() {
+            return Ok(Vec::new());
+        }
+        // Chunks stored after listing are referred to by bands written since
+        let listed = self.chunks().list(CHUNK_PREFIX)?;
+        if listed.is_empty() {
+            return Ok(Vec::new());
+        }
+        {
+            let _writing = self.writing.write().unwrap();
+            *self.referenced.lock().unwrap() = Some(HashSet::new());
+        }
+
+        let deleted: HashSet<String> = deleted.iter().cloned().collect();
+        let mut marked = HashSet::new();
+        mark_chunks(&*self.stored, &deleted, &mut marked)?;
+        Ok(unmarked_chunks(listed, &marked))
+    }
+
+    // Stop recording the chunks bands refer to, once the chunks found
+    // unreferenced are deleted or left
+    pub fn end_marking(&self) {
+        *self.referenced.lock().unwrap() = None;
+    }
+}
+
+impl ObjectStore for DedupStore {
+    fn get(&self, key: &str, range: Option<Range<u64>>) -> Result<Vec<u8>, BackendError> {
+        self.read(key, range, |range| self.inner.get(key, range))
+    }
+
+    fn put(&self, key: &str, data: &[u8]) -> Result<(), BackendError> {
+        let _writing = self.writing.read().unwrap();
+        self.inner.put(key, &self.stored(key, data)?)
+    }
+
+    fn get_tagged(&self, key: &str) -> Result<(Vec<u8>, String), BackendError> {
+        let (stored, etag) = self.inner.get_tagged(key)?;
+        let data = self.read(key, None, |_| Ok(stored.clone()))?;
+        Ok((data, etag))
+    }
+
+    fn put_if(&self, key: &str, data: &[u8], etag: Option<&str>) -> Result<String, BackendError> {
+        let _writing = self.writing.read().unwrap();
+        self.inner.put_if(key, &self.stored(key, data)?, etag)
+    }
+
+    fn delete(&self, key: &str) -> Result<(), BackendError> {
+        match key.starts_with(CHUNK_PREFIX) {
+            true => self.delete_chunk(key),
+            false => self.inner.delete(key),
+        }
+    }
+
+    // Band objects copied as stored share their chunks
+    fn copy(&self, from: &str, range: Option<Range<u64>>, to: &str) -> Result<(), BackendError> {
+        if self.copies_as_stored(from, &range, to) {
+            let _writing = self.writing.read().unwrap();
+            // The copy refers to the same chunks, which marking may have
+            // found unreferenced by the time it is written
+            if is_band(from) && self.referenced.lock().unwrap().is_some() {
+                let mut chunks = HashSet::new();
+                mark_band(from, |range| self.stored.get(from, range), &mut chunks)?;
+                if let Some(referenced) = self.referenced.lock().unwrap().as_mut() {
+                    referenced.extend(chunks);
+                }
+            }
+            return self.inner.copy(from, range, to);
+        }
+        let data = self.get(from, range)?;
+        self.put(to, &data)
+    }
+
+    fn list(&self, prefix: &str) -> Result<Vec<ObjectInfo>, BackendError> {
+        let mut objects = self.inner.list(prefix)?;
+        objects.retain(|object| !object.key.starts_with(CHUNK_PREFIX));
+        Ok(objects)
+    }
+
+    fn version(&self, key: &str) -> Option<String> {
+        self.inner.version(key)
+    }
+
+    fn get_version(
+        &self,
+        key: &str,
+        version_id: &str,
+        range: Option<Range<u64>>,
+    ) -> Result<Vec<u8>, BackendError> {
+        self.read(key, range, |range| {
+            self.inner.get_version(key, version_id, range)
+        })
+    }
+
+    fn list_versions(&self, prefix: &str) -> Result<Vec<ObjectVersion>, BackendError> {
+        let mut versions = self.inner.list_versions(prefix)?;
+        versions.retain(|version| !version.key.starts_with(CHUNK_PREFIX));
+        Ok(versions)
+    }
+}
@@ -0,0 +1,186 @@
+use crate::backend::{BackendError, ObjectInfo, ObjectStore};
+use crate::cache::{band_generation, BAND_PREFIX};
+use crate::dedup::DedupStore;
+use crate::snapshot::{Manifest, SNAPSHOT_PREFIX};
+
+use std::collections::{BTreeSet, HashSet};
+use std::time::{Duration, SystemTime};
+
+const HOUR: u64 = 3600;
+const DAY: u64 = 24 * HOUR;
+
+// How long snapshots are kept: the last one of every hour, day and month
+// taken within the given time. The latest snapshot is always kept.
+#[derive(Clone)]
+pub struct Retention {
+    pub hourly: Duration,
+    pub daily: Duration,
+    pub monthly: Duration,
+}
+
+impl Default for Retention {
+    fn default() -> Retention {
+        Retention {
+            hourly: Duration::from_secs(DAY),
+            daily: Duration::from_secs(30 * DAY),
+            monthly: Duration::from_secs(365 * DAY),
+        }
+    }
+}
+
+// Parse a duration such as 36h, 30d, 2w or 1y. 0 keeps nothing.
+pub fn parse_duration(arg: &str) -> Result<Duration, String> {
+    let split = arg.find(|c: char| !c.is_ascii_digit()).unwrap_or(arg.len());
+    let (count, unit) = arg.split_at(split);
+    let count: u64 = count
+        .parse()
+        .map_err(|_| format!("invalid duration {arg}"))?;
+    let unit = match unit {
+        "" if count == 0 => 0,
+        "h" => HOUR,
+        "d" => DAY,
+        "w" => 7 * DAY,
+        "y" => 365 * DAY,
+        _ => {
+            return Err(format!(
+                "invalid duration {arg}, expected a unit of h, d, w or y"
+            ))
+        }
+    };
+    Ok(Duration::from_secs(count * unit))
+}
+
+// Ids of the snapshots the retention policy keeps, given the id of every
+// snapshot, oldest first, and when it was taken
+pub fn kept_snapshots(
+    snapshots: &[(String, SystemTime)],
+    retention: &Retention,
+    now: SystemTime,
+) -> BTreeSet<String> {
+    let mut kept = BTreeSet::new();
+    if let Some((id, _)) = snapshots.last() {
+        kept.insert(id.clone());
+    }
+
+    // Ids start with the UTC time, so those taken in the same hour, day or
+    // month share the first 11, 8 or 6 characters
+    for (len, keep_for) in [
+        (11, retention.hourly),
+        (8, retention.daily),
+        (6, retention.monthly),
+    ] {
+        let mut periods = HashSet::new();
+        for (id, created) in snapshots.iter().rev() {
+            if now.duration_since(*created).unwrap_or_default() >= keep_for {
+                break;
+            }
+            if periods.insert(&id[..len.min(id.len())]) {
+                kept.insert(id.clone());
+            }
+        }
+    }
+    kept
+}
+
+// Find the snapshots the retention policy no longer keeps, the band objects
+// neither the filesystem nor a snapshot kept refers to, given those the
+// filesystem refers to, and the chunks none of the bands left refers to.
+// Band objects of the generation being written may appear while
+// collecting, so those are left for a later run.
+pub fn collect(
+    store: &dyn ObjectStore,
+    dedup: Option<&DedupStore>,
+    mut referenced: BTreeSet<String>,
+    epoch: u64,
+    retention: &Retention,
+) -> Result<Garbage, BackendError> {
+    let listing = store.list("")?;
+    let snapshots: Vec<(String, SystemTime)> = listing
+        .iter()
+        .filter_map(|object| {
+            let id = object.key.strip_prefix(SNAPSHOT_PREFIX)?;
+            Some((id.to_string(), object.last_modified))
+        })
+        .collect();
+    let kept = kept_snapshots(&snapshots, retention, SystemTime::now());
+    for id in &kept {
+        referenced.extend(Manifest::load(store, id)?.objects.into_keys());
+    }
+
+    let mut garbage = Garbage::default();
+    for object in listing {
+        match object.key.strip_prefix(SNAPSHOT_PREFIX) {
+            Some(id) if !kept.contains(id) => garbage.snapshots.push(object),
+            None if object.key.starts_with(BAND_PREFIX)
+                && band_generation(&object.key) < epoch
+                && !referenced.contains(&object.key) =>
+            {
+                garbage.objects.push(object)
+            }
+            _ => (),
+        }
+    }
+
+    let deleted: Vec<String> = garbage
+        .snapshots
+        .iter()
+        .chain(&garbage.objects)
+        .map(|object| object.key.clone())
+        .collect();
+    if let Some(dedup) = dedup {
+        garbage.chunks = dedup.unreferenced(&deleted)?;
+    }
+    Ok(garbage)
+}
+
+// What garbage collection deletes: the manifests of snapshots which expired,
+// the band objects nothing refers to any more and the chunks of deduplicated
+// bands none of the others refer to
+#[derive(Default)]
+pub struct Garbage {
+    pub snapshots: Vec<ObjectInfo>,
+    pub objects: Vec<ObjectInfo>,
+    pub chunks: Vec<ObjectInfo>,
+}
+
+impl Garbage {
+    // Space reclaimed by deleting the garbage
+    pub fn bytes(&self) -> u64 {
+        self.snapshots
+            .iter()
+            .chain(&self.objects)
+            .chain(&self.chunks)
+            .map(|object| object.size)
+            .sum()
+    }
+
+    // Delete the garbage. Manifests go first and chunks last, so none is
+    // ever left referring to deleted objects.
+    pub fn sweep(&self, store: &dyn ObjectStore) -> Result<(), BackendError> {
+        for object in self
+            .snapshots
+            .iter()
+            .chain(&self.objects)
+            .chain(&self.chunks)
+        {
+            store.delete(&object.key)?;
+        }
+        Ok(())
+    }
+
+    pub fn report(&self) -> String {
+        let ids: Vec<&str> = self
+            .snapshots
+            .iter()
+            .map(|snapshot| &snapshot.key[SNAPSHOT_PREFIX.len()..])
+            .collect();
+        format!(
+            "{} snapshots expired [{}], {} objects and {} chunks unreferenced, {} bytes",
+            ids.len(),
+            ids.join(" "),
+            self.objects.len(),
+            self.chunks.len(),
+            self.bytes()
+        )
+    }
+}
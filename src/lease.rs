@@ -0,0 +1,318 @@
+use crate::backend::{BackendError, ObjectInfo, ObjectStore, ObjectVersion};
+
+use std::fmt;
+use std::ops::Range;
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, SystemTime};
+
+use serde::{Deserialize, Serialize};
+
+// Key of the object recording which host has the filesystem mounted
+pub const LEASE_KEY: &str = "lease";
+
+// How long a lease lasts unless renewed. It is renewed three times as often.
+pub const LEASE_DURATION: Duration = Duration::from_secs(60);
+
+// Attempts at taking a lease other hosts keep changing
+const ACQUIRE_ATTEMPTS: u32 = 3;
+
+// Contents of the lease object
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct LeaseRecord {
+    pub host: String,
+    pub pid: u32,
+    // Random value telling the leases of different mounts apart
+    pub token: u64,
+    pub expires: SystemTime,
+}
+
+#[derive(Debug)]
+pub enum LeaseError {
+    // Another mount holds a lease which has not expired
+    Held(LeaseRecord),
+    Backend(BackendError),
+}
+
+impl fmt::Display for LeaseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LeaseError::Held(held) => {
+                let left = held
+                    .expires
+                    .duration_since(SystemTime::now())
+                    .unwrap_or_default();
+                write!(
+                    f,
+                    "mounted by {} (pid {}), lease expires in {}s",
+                    held.host,
+                    held.pid,
+                    left.as_secs()
+                )
+            }
+            LeaseError::Backend(err) => write!(f, "{err}"),
+        }
+    }
+}
+
+struct State {
+    // ETag of the lease object as last written
+    etag: String,
+    expires: SystemTime,
+    // Whether another mount took the lease over
+    lost: bool,
+    stopped: bool,
+}
+
+// Exclusive right to write to the filesystem, held by a single mount at a
+// time. The lease object is replaced with conditional PUTs, so two hosts
+// can't both take it, and renewed in the background while mounted.
+pub struct Lease {
+    store: Arc<dyn ObjectStore>,
+    duration: Duration,
+    host: String,
+    pid: u32,
+    token: u64,
+    state: Mutex<State>,
+    wakeup: Condvar,
+    renewer: Mutex<Option<JoinHandle<()>>>,
+}
+
+impl Lease {
+    // Take the lease, unless another mount holds it. With `force` the lease
+    // is taken whether it is held or not.
+    pub fn acquire(
+        store: Arc<dyn ObjectStore>,
+        duration: Duration,
+        force: bool,
+    ) -> Result<Arc<Lease>, LeaseError> {
+        let lease = Lease {
+            store,
+            duration,
+            host: hostname(),
+            pid: std::process::id(),
+            token: fastrand::u64(..),
+            state: Mutex::new(State {
+                etag: String::new(),
+                expires: SystemTime::UNIX_EPOCH,
+                lost: false,
+                stopped: false,
+            }),
+            wakeup: Condvar::new(),
+            renewer: Mutex::new(None),
+        };
+
+        for _ in 0..ACQUIRE_ATTEMPTS {
+            let etag = match lease.store.get_tagged(LEASE_KEY) {
+                Ok((data, etag)) => match serde_json::from_slice::<LeaseRecord>(&data) {
+                    // A retried write which succeeded the first time
+                    Ok(held) if held.token == lease.token => {
+                        lease.update(etag, held.expires);
+                        return Ok(Arc::new(lease));
+                    }
+                    Ok(held) if !force && held.expires > SystemTime::now() => {
+                        return Err(LeaseError::Held(held));
+                    }
+                    Err(err) if !force => {
+                        let msg = format!("corrupt lease: {err}");
+                        return Err(LeaseError::Backend(BackendError::Other(msg)));
+                    }
+                    _ => Some(etag),
+                },
+                Err(BackendError::NotFound) => None,
+                Err(err) => return Err(LeaseError::Backend(err)),
+            };
+
+            // Another mount may take the lease between reading and writing
+            // it, the next attempt finds it held
+            match lease.write(etag.as_deref()) {
+                Ok(()) => return Ok(Arc::new(lease)),
+                Err(BackendError::PreconditionFailed) => continue,
+                Err(err) => return Err(LeaseError::Backend(err)),
+            }
+        }
+        Err(LeaseError::Backend(BackendError::PreconditionFailed))
+    }
+
+    fn update(&self, etag: String, expires: SystemTime) {
+        let mut state = self.state.lock().unwrap();
+        state.etag = etag;
+        state.expires = expires;
+    }
+
+    // Write the lease object with a new expiry time, if it still has the
+    // given ETag
+    fn write(&self, etag: Option<&str>) -> Result<(), BackendError> {
+        let record = LeaseRecord {
+            host: self.host.clone(),
+            pid: self.pid,
+            token: self.token,
+            expires: SystemTime::now() + self.duration,
+        };
+        let data =
+            serde_json::to_vec(&record).map_err(|err| BackendError::Other(err.to_string()))?;
+        let etag = self.store.put_if(LEASE_KEY, &data, etag)?;
+        self.update(etag, record.expires);
+        Ok(())
+    }
+
+    // The lease object as stored, if it is ours
+    fn ours(&self) -> Option<(LeaseRecord, String)> {
+        let (data, etag) = self.store.get_tagged(LEASE_KEY).ok()?;
+        let record: LeaseRecord = serde_json::from_slice(&data).ok()?;
+        (record.token == self.token).then_some((record, etag))
+    }
+
+    // Extend the lease. Fails with LeaseLost if another mount took it over.
+    pub fn renew(&self) -> Result<(), BackendError> {
+        let etag = {
+            let state = self.state.lock().unwrap();
+            if state.lost {
+                return Err(BackendError::LeaseLost);
+            }
+            state.etag.clone()
+        };
+
+        match self.write(Some(&etag)) {
+            Err(BackendError::PreconditionFailed) => match self.ours() {
+                Some((record, etag)) => {
+                    self.update(etag, record.expires);
+                    Ok(())
+                }
+                None => {
+                    println!("\tlease taken over by another mount");
+                    self.state.lock().unwrap().lost = true;
+                    Err(BackendError::LeaseLost)
+                }
+            },
+            result => result,
+        }
+    }
+
+    // Whether the filesystem may be written to: the lease was not taken
+    // over and has not expired for lack of renewal
+    pub fn is_valid(&self) -> bool {
+        let state = self.state.lock().unwrap();
+        !state.lost && SystemTime::now() < state.expires
+    }
+
+    // Renew the lease in the background until it is released
+    pub fn keep_renewed(self: &Arc<Self>) {
+        let lease = self.clone();
+        let handle = thread::spawn(move || {
+            let mut state = lease.state.lock().unwrap();
+            while !state.stopped && !state.lost {
+                state = lease
+                    .wakeup
+                    .wait_timeout(state, lease.duration / 3)
+                    .unwrap()
+                    .0;
+                if state.stopped {
+                    return;
+                }
+                drop(state);
+                if let Err(err) = lease.renew() {
+                    println!("\trenewing lease failed: {err}");
+                }
+                state = lease.state.lock().unwrap();
+            }
+        });
+        *self.renewer.lock().unwrap() = Some(handle);
+    }
+
+    // Stop renewing the lease and delete it, unless it was taken over
+    pub fn release(&self) {
+        self.state.lock().unwrap().stopped = true;
+        self.wakeup.notify_all();
+        if let Some(handle) = self.renewer.lock().unwrap().take() {
+            handle.join().unwrap();
+        }
+
+        if self.ours().is_some() {
+            if let Err(err) = self.store.delete(LEASE_KEY) {
+                println!("\treleasing lease failed: {err}");
+            }
+        }
+    }
+}
+
+fn hostname() -> String {
+    let mut buf = [0u8; 256];
+    let ret = unsafe { libc::gethostname(buf.as_mut_ptr() as *mut libc::c_char, buf.len()) };
+    if ret != 0 {
+        return "unknown".to_string();
+    }
+    let len = buf.iter().position(|b| *b == 0).unwrap_or(buf.len());
+    String::from_utf8_lossy(&buf[..len]).into_owned()
+}
+
+// Object store refusing writes once the lease is no longer valid, so a mount
+// which lost its lease can't overwrite objects of the one which took over
+pub struct LeasedStore {
+    inner: Arc<dyn ObjectStore>,
+    lease: Arc<Lease>,
+}
+
+impl LeasedStore {
+    pub fn new(inner: Arc<dyn ObjectStore>, lease: Arc<Lease>) -> LeasedStore {
+        LeasedStore { inner, lease }
+    }
+
+    fn check(&self) -> Result<(), BackendError> {
+        match self.lease.is_valid() {
+            true => Ok(()),
+            false => Err(BackendError::LeaseLost),
+        }
+    }
+}
+
+impl ObjectStore for LeasedStore {
+    fn get(&self, key: &str, range: Option<Range<u64>>) -> Result<Vec<u8>, BackendError> {
+        self.inner.get(key, range)
+    }
+
+    fn put(&self, key: &str, data: &[u8]) -> Result<(), BackendError> {
+        self.check()?;
+        self.inner.put(key, data)
+    }
+
+    fn get_tagged(&self, key: &str) -> Result<(Vec<u8>, String), BackendError> {
+        self.inner.get_tagged(key)
+    }
+
+    fn put_if(&self, key: &str, data: &[u8], etag: Option<&str>) -> Result<String, BackendError> {
+        self.check()?;
+        self.inner.put_if(key, data, etag)
+    }
+
+    fn delete(&self, key: &str) -> Result<(), BackendError> {
+        self.check()?;
+        self.inner.delete(key)
+    }
+
+    fn copy(&self, from: &str, range: Option<Range<u64>>, to: &str) -> Result<(), BackendError> {
+        self.check()?;
+        self.inner.copy(from, range, to)
+    }
+
+    fn list(&self, prefix: &str) -> Result<Vec<ObjectInfo>, BackendError> {
+        self.inner.list(prefix)
+    }
+
+    fn version(&self, key: &str) -> Option<String> {
+        self.inner.version(key)
+    }
+
+    fn get_version(
+        &self,
+        key: &str,
+        version_id: &str,
+        range: Option<Range<u64>>,
+    ) -> Result<Vec<u8>, BackendError> {
+        self.inner.get_version(key, version_id, range)
+    }
+
+    fn list_versions(&self, prefix: &str) -> Result<Vec<ObjectVersion>, BackendError> {
+        self.inner.list_versions(prefix)
+    }
+}
@@ -0,0 +1,104 @@
+use std::collections::HashMap;
+
+// Lock types as found in requests. libc declares them as c_short on BSDs.
+#[allow(clippy::unnecessary_cast)]
+pub const F_RDLCK: i32 = libc::F_RDLCK as i32;
+#[allow(clippy::unnecessary_cast)]
+pub const F_WRLCK: i32 = libc::F_WRLCK as i32;
+#[allow(clippy::unnecessary_cast)]
+pub const F_UNLCK: i32 = libc::F_UNLCK as i32;
+
+// A byte-range lock. The range includes its end, which is u64::MAX for a
+// lock reaching to the end of the file whatever its size.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Lock {
+    pub owner: u64,
+    pub start: u64,
+    pub end: u64,
+    pub typ: i32,
+    pub pid: u32,
+}
+
+impl Lock {
+    fn overlaps(&self, start: u64, end: u64) -> bool {
+        self.start <= end && start <= self.end
+    }
+}
+
+// Advisory locks held on inodes. Only processes going through this mount
+// see them, they are not stored with the filesystem.
+#[derive(Default)]
+pub struct LockTable {
+    locks: HashMap<u64, Vec<Lock>>,
+}
+
+impl LockTable {
+    pub fn new() -> LockTable {
+        LockTable::default()
+    }
+
+    // A lock held by another owner preventing a lock from being taken, None
+    // if it can be taken. Read locks only conflict with write locks.
+    pub fn conflict(&self, ino: u64, lock: &Lock) -> Option<Lock> {
+        self.locks
+            .get(&ino)?
+            .iter()
+            .find(|held| {
+                held.owner != lock.owner
+                    && held.overlaps(lock.start, lock.end)
+                    && (held.typ == F_WRLCK || lock.typ == F_WRLCK)
+            })
+            .copied()
+    }
+
+    // Take, change or release (F_UNLCK) a lock. The owner's locks in the
+    // range are replaced, splitting those extending past it. Fails with the
+    // conflicting lock if another owner holds one.
+    pub fn set(&mut self, ino: u64, lock: Lock) -> Result<(), Lock> {
+        if lock.typ != F_UNLCK {
+            if let Some(held) = self.conflict(ino, &lock) {
+                return Err(held);
+            }
+        }
+
+        let locks = self.locks.entry(ino).or_default();
+        let mut kept = Vec::with_capacity(locks.len() + 1);
+        for held in locks.drain(..) {
+            if held.owner != lock.owner || !held.overlaps(lock.start, lock.end) {
+                kept.push(held);
+                continue;
+            }
+            if held.start < lock.start {
+                kept.push(Lock {
+                    end: lock.start - 1,
+                    ..held
+                });
+            }
+            if held.end > lock.end {
+                kept.push(Lock {
+                    start: lock.end + 1,
+                    ..held
+                });
+            }
+        }
+        if lock.typ != F_UNLCK {
+            kept.push(lock);
+        }
+
+        match kept.is_empty() {
+            true => self.locks.remove(&ino),
+            false => self.locks.insert(ino, kept),
+        };
+        Ok(())
+    }
+
+    // Release every lock an owner holds on an inode
+    pub fn release(&mut self, ino: u64, owner: u64) {
+        if let Some(locks) = self.locks.get_mut(&ino) {
+            locks.retain(|held| held.owner != owner);
+            if locks.is_empty() {
+                self.locks.remove(&ino);
+            }
+        }
+    }
+}
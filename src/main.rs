@@ -1,31 +1,631 @@
+pub mod backend;
+pub mod cache;
+pub mod compress;
+pub mod crypto;
+pub mod dedup;
+pub mod gc;
+pub mod lease;
+pub mod lock;
+pub mod metadata;
+pub mod prefetch;
+pub mod retry;
+pub mod s3;
 pub mod s3tmfs;
+pub mod snapshot;
+pub mod upload;
 pub mod wrapperfs;
 
 #[cfg(test)]
 mod tests;
 
-use crate::s3tmfs::S3TMFS;
+use crate::backend::{MemoryStore, ObjectStore};
+use crate::compress::{parse_codec, Codec, CompressedStore};
+use crate::crypto::{is_encrypted, EncryptedStore, KdfParams, PASSPHRASE_ENV};
+use crate::dedup::{
+    mark_chunks, parse_chunk_size, unmarked_chunks, ChunkSizes, DedupStore, CHUNK_PREFIX,
+};
+use crate::gc::{parse_duration, Retention};
+use crate::lease::{Lease, LeasedStore, LEASE_DURATION};
+use crate::metadata::METADATA_KEY;
+use crate::retry::{RetryPolicy, RetryStore};
+use crate::s3::S3Store;
+use crate::s3tmfs::{Config, S3TMFS};
+use crate::snapshot::{resolve_snapshot, utc_time, Manifest, PinnedStore};
+use crate::wrapperfs::WrappedFilesystem;
 
-use clap::{arg, Command};
+use std::collections::HashSet;
+use std::sync::Arc;
+use std::time::Duration;
+
+use clap::{arg, value_parser, Arg, ArgAction, ArgMatches, Command};
 use fuser::MountOption;
 
-fn main() {
-    // Command line options
-    let matches = Command::new("s3-time-machine")
-        .arg(arg!(--mountpoint <DIR>).required(true))
-        .get_matches();
+// Options selecting where the filesystem is stored
+fn store_args() -> Vec<Arg> {
+    vec![
+        arg!(--bucket <NAME> "S3 bucket, contents are kept in memory if not given"),
+        arg!(--prefix <PREFIX> "Key prefix within the bucket").default_value(""),
+        arg!(--region <REGION> "AWS region of the bucket"),
+        arg!(--endpoint <URL> "Endpoint of an S3-compatible server"),
+        arg!(--"max-attempts" <N> "Attempts made at requests failing temporarily")
+            .value_parser(value_parser!(u32).range(1..)),
+        arg!(--"band-size" <BYTES> "Size of the objects file contents are stored in")
+            .value_parser(value_parser!(u64).range(1..)),
+        arg!(--"force-break-lease" "Proceed even if another host holds the lease"),
+        arg!(--"passphrase-file" <FILE> "File holding the passphrase the bucket is encrypted with"),
+        arg!(--"chunk-prefix" <PREFIX> "Key prefix of the chunks shared with other filesystems"),
+    ]
+}
+
+// Passphrase the objects in the bucket are encrypted with, if any, read from
+// a file or the environment
+fn passphrase(matches: &ArgMatches) -> Option<String> {
+    match matches.get_one::<String>("passphrase-file") {
+        Some(path) => Some(read_passphrase(path)),
+        None => std::env::var(PASSPHRASE_ENV).ok(),
+    }
+}
+
+fn read_passphrase(path: &str) -> String {
+    match std::fs::read_to_string(path) {
+        Ok(passphrase) => passphrase.trim_end_matches(['\r', '\n']).to_string(),
+        Err(err) => {
+            eprintln!("Cannot read {path}: {err}");
+            std::process::exit(1);
+        }
+    }
+}
+
+// Options setting how long snapshots are kept
+fn retention_args() -> Vec<Arg> {
+    vec![
+        arg!(--"keep-hourly" <DURATION> "Keep the last snapshot of every hour for this long")
+            .value_parser(parse_duration)
+            .default_value("1d"),
+        arg!(--"keep-daily" <DURATION> "Keep the last snapshot of every day for this long")
+            .value_parser(parse_duration)
+            .default_value("30d"),
+        arg!(--"keep-monthly" <DURATION> "Keep the last snapshot of every month for this long")
+            .value_parser(parse_duration)
+            .default_value("1y"),
+    ]
+}
+
+fn retention(matches: &ArgMatches) -> Retention {
+    Retention {
+        hourly: *matches.get_one::<Duration>("keep-hourly").unwrap(),
+        daily: *matches.get_one::<Duration>("keep-daily").unwrap(),
+        monthly: *matches.get_one::<Duration>("keep-monthly").unwrap(),
+    }
+}
+
+// Objects below a prefix of the bucket, as stored
+fn open_bucket(matches: &ArgMatches, prefix: &str) -> Arc<dyn ObjectStore> {
+    match matches.get_one::<String>("bucket") {
+        Some(bucket) => {
+            let s3 = S3Store::new(
+                bucket,
+                prefix,
+                matches.get_one::<String>("region").map(String::as_str),
+                matches.get_one::<String>("endpoint").map(String::as_str),
+            );
+
+            let mut policy = RetryPolicy::default();
+            if let Some(max_attempts) = matches.get_one::<u32>("max-attempts") {
+                policy.max_attempts = *max_attempts;
+            }
+            Arc::new(RetryStore::new(Arc::new(s3), policy))
+        }
+        None => Arc::new(MemoryStore::new()),
+    }
+}
+
+// The bucket decrypted with the passphrase given, if any
+fn decrypt(store: Arc<dyn ObjectStore>, matches: &ArgMatches) -> Option<Arc<EncryptedStore>> {
+    let passphrase = passphrase(matches)?;
+    match EncryptedStore::open(store, &passphrase, &KdfParams::default()) {
+        Ok(encrypted) => Some(Arc::new(encrypted)),
+        Err(err) => {
+            eprintln!("Cannot decrypt the bucket: {err}");
+            std::process::exit(1);
+        }
+    }
+}
+
+fn passphrase_missing() -> ! {
+    eprintln!(
+        "The bucket is encrypted, give its passphrase with \
+         --passphrase-file or {PASSPHRASE_ENV}"
+    );
+    std::process::exit(1);
+}
+
+// Compressed band objects are read whatever the options, bands are only
+// compressed when written with a codec given
+fn compress(store: Arc<dyn ObjectStore>, matches: &ArgMatches) -> Arc<CompressedStore> {
+    let codec = matches.try_get_one::<Codec>("compress").ok().flatten();
+    Arc::new(CompressedStore::new(store, codec.copied()))
+}
+
+// Objects below a prefix of the bucket, decrypted and decompressed, and the
+// store decrypting them if the bucket is encrypted
+fn open_image(
+    matches: &ArgMatches,
+    prefix: &str,
+) -> (Arc<CompressedStore>, Option<Arc<EncryptedStore>>) {
+    let store = open_bucket(matches, prefix);
+    match decrypt(store.clone(), matches) {
+        Some(encrypted) => (compress(encrypted.clone(), matches), Some(encrypted)),
+        None => {
+            if is_encrypted(&*store).unwrap_or(false) {
+                passphrase_missing();
+            }
+            (compress(store, matches), None)
+        }
+    }
+}
+
+// Bands split into chunks are read whatever the options, bands are only
+// split when written with --dedup. Chunks are kept with the filesystem
+// unless a prefix for chunks shared with others is given, and named with the
+// key of the bucket they are kept in if it is encrypted.
+fn dedup(
+    store: Arc<CompressedStore>,
+    encrypted: Option<Arc<EncryptedStore>>,
+    matches: &ArgMatches,
+) -> Arc<DedupStore> {
+    let prefix = matches.get_one::<String>("prefix").unwrap();
+    let (shared, encrypted) = match matches.get_one::<String>("chunk-prefix") {
+        Some(chunk_prefix) if chunk_prefix != prefix => {
+            let (chunks, encrypted) = open_image(matches, chunk_prefix);
+            (Some(chunks as Arc<dyn ObjectStore>), encrypted)
+        }
+        _ => (None, encrypted),
+    };
+    let sizes = match matches.try_get_one::<bool>("dedup") {
+        Ok(Some(true)) => matches.get_one::<ChunkSizes>("chunk-size").copied(),
+        _ => None,
+    };
+    let name_key = encrypted.map(|encrypted| encrypted.chunk_name_key());
+    let stored = store.as_stored();
+    Arc::new(DedupStore::new(store, stored, shared, sizes, name_key))
+}
+
+// Object store holding the filesystem contents
+fn open_store(matches: &ArgMatches) -> Arc<dyn ObjectStore> {
+    let prefix = matches.get_one::<String>("prefix").unwrap();
+    let (image, encrypted) = open_image(matches, prefix);
+    dedup(image, encrypted, matches)
+}
+
+// Take the lease on the bucket, exiting if another host holds it. Writes go
+// through the returned store, which refuses them once the lease is lost.
+fn take_lease(
+    store: Arc<dyn ObjectStore>,
+    matches: &ArgMatches,
+) -> (Arc<dyn ObjectStore>, Arc<Lease>) {
+    let force = matches.get_flag("force-break-lease");
+    match Lease::acquire(store.clone(), LEASE_DURATION, force) {
+        Ok(lease) => {
+            lease.keep_renewed();
+            (Arc::new(LeasedStore::new(store, lease.clone())), lease)
+        }
+        Err(err) => {
+            eprintln!("Cannot use the bucket: {err}");
+            std::process::exit(1);
+        }
+    }
+}
 
+fn config(matches: &ArgMatches) -> Config {
+    let mut config = Config::default();
+    if let Some(band_size) = matches.get_one::<u64>("band-size") {
+        config.band_size = *band_size;
+    }
+    config
+}
+
+fn mount(matches: &ArgMatches) {
     // Get mount point directory
     let mountpoint = matches.get_one::<String>("mountpoint").unwrap();
+    let rekey = matches.get_flag("rekey");
+    if rekey && (matches.get_flag("read-only") || matches.contains_id("at")) {
+        eprintln!("Cannot rekey a read-only mount");
+        std::process::exit(1);
+    }
+    let prefix = matches.get_one::<String>("prefix").unwrap();
+    let (compressed, encrypted) = match rekey {
+        true => {
+            let decrypted = decrypt(open_bucket(matches, prefix), matches)
+                .unwrap_or_else(|| passphrase_missing());
+            (compress(decrypted.clone(), matches), Some(decrypted))
+        }
+        false => open_image(matches, prefix),
+    };
+    let deduped = dedup(compressed.clone(), encrypted.clone(), matches);
+    let mut store: Arc<dyn ObjectStore> = deduped.clone();
+
+    // A snapshot is shown as it was, without the changes made since
+    let snapshot = matches.get_one::<String>("at").map(|at| {
+        let manifest = resolve_snapshot(&*store, at)
+            .and_then(|id| Manifest::load(&*store, &id))
+            .unwrap_or_else(|err| {
+                eprintln!("Cannot mount snapshot: {err}");
+                std::process::exit(1);
+            });
+        // In a bucket keeping versions, objects are read as the snapshot
+        // recorded them
+        if !manifest.versions.is_empty() {
+            store = Arc::new(PinnedStore::new(store.clone(), manifest.versions));
+        }
+        manifest.id
+    });
+
+    // Only one host may write to a bucket at a time, while read-only mounts
+    // may inspect it alongside. A filesystem kept in memory can't be shared.
+    let read_only = matches.get_flag("read-only") || snapshot.is_some();
+    let mut lease = None;
+    if matches.contains_id("bucket") && !read_only {
+        let (leased, acquired) = take_lease(store, matches);
+        store = leased;
+        lease = Some(acquired);
+    }
+
+    // Objects are encrypted again in the background while mounted, those
+    // written meanwhile get the new data key straight away
+    if let Some(encrypted) = encrypted.filter(|_| rekey) {
+        if let Err(err) = encrypted.rotate() {
+            eprintln!("Cannot rotate the data key: {err}");
+            std::process::exit(1);
+        }
+        std::thread::spawn(move || match encrypted.rekey() {
+            Ok(count) => println!("\trekeyed {count} objects"),
+            Err(err) => println!("\trekeying failed: {err}"),
+        });
+    }
+
+    let mut config = config(matches);
+    if let Some(depth) = matches.get_one::<u64>("prefetch-depth") {
+        config.prefetch_depth = *depth;
+    }
+    config.prefetch_bandwidth = matches.get_one::<u64>("prefetch-bandwidth").copied();
+    if let Some(concurrency) = matches.get_one::<u64>("upload-concurrency") {
+        config.upload_concurrency = *concurrency as usize;
+    }
+    if let Some(max_dirty) = matches.get_one::<u64>("max-dirty") {
+        config.max_dirty_bytes = *max_dirty;
+    }
+    config.read_only = read_only;
+    config.snapshot = snapshot;
+    if matches.get_flag("gc") {
+        config.retention = Some(retention(matches));
+    }
+    // Without a retention policy, snapshots are only taken on demand
+    config.auto_snapshot = config.retention.is_some() && !matches.get_flag("no-auto-snapshot");
 
     // Mount filesystem
     let mut options = vec![
         MountOption::AutoUnmount,
-        MountOption::RW,
+        match read_only {
+            true => MountOption::RO,
+            false => MountOption::RW,
+        },
         MountOption::FSName("s3-tm".to_string()),
     ];
     options.push(MountOption::AutoUnmount);
 
-    let fs = S3TMFS::new();
+    let mut fs = S3TMFS::new(store, config);
+    fs.set_dedup(deduped.clone());
     fuser::mount2(fs, mountpoint, &options).unwrap();
+    if matches.contains_id("compress") {
+        println!("{}", compressed.stats().report());
+    }
+
+    if let Some(lease) = lease {
+        lease.release();
+    }
+}
+
+// Snapshot a filesystem which is not mounted
+fn snapshot(matches: &ArgMatches) {
+    let (store, lease) = take_lease(open_store(matches), matches);
+    let mut fs = S3TMFS::new(store, config(matches));
+
+    let result = fs.fuse_init().and_then(|()| fs.snapshot());
+    lease.release();
+    match result {
+        Ok(id) => println!("{id}"),
+        Err(errno) => {
+            eprintln!(
+                "Snapshot failed: {}",
+                std::io::Error::from_raw_os_error(errno)
+            );
+            std::process::exit(1);
+        }
+    }
+}
+
+// Delete expired snapshots and the objects no longer referenced, of a
+// filesystem which is not mounted
+fn gc(matches: &ArgMatches) {
+    let dry_run = matches.get_flag("dry-run");
+    let prefix = matches.get_one::<String>("prefix").unwrap();
+    let (image, encrypted) = open_image(matches, prefix);
+    let deduped = dedup(image, encrypted, matches);
+    let mut store: Arc<dyn ObjectStore> = deduped.clone();
+    let mut lease = None;
+    if !dry_run {
+        let (leased, acquired) = take_lease(store, matches);
+        store = leased;
+        lease = Some(acquired);
+    }
+    let mut config = config(matches);
+    config.read_only = dry_run;
+    let mut fs = S3TMFS::new(store.clone(), config);
+    fs.set_dedup(deduped.clone());
+
+    let result = fs
+        .fuse_init()
+        .map_err(|errno| std::io::Error::from_raw_os_error(errno).to_string())
+        .and_then(|()| {
+            let garbage = fs
+                .collect_garbage(&retention(matches))
+                .map_err(|err| err.to_string())?;
+            match dry_run {
+                true => println!("Would delete {}", garbage.report()),
+                false => {
+                    garbage.sweep(&*store).map_err(|err| err.to_string())?;
+                    println!("Deleted {}", garbage.report());
+                }
+            }
+            Ok(())
+        });
+    deduped.end_marking();
+    if let Some(lease) = lease {
+        lease.release();
+    }
+    if let Err(err) = result {
+        eprintln!("Garbage collection failed: {err}");
+        std::process::exit(1);
+    }
+}
+
+// Delete the shared chunks none of the filesystems using them refers to any
+// more. They can't be written meanwhile, so their leases are taken.
+fn chunk_gc(matches: &ArgMatches) {
+    let dry_run = matches.get_flag("dry-run");
+    let (chunks, _) = open_image(matches, matches.get_one::<String>("chunk-prefix").unwrap());
+
+    let mut leases = Vec::new();
+    let mut result = Ok(());
+    let listed = chunks.list(CHUNK_PREFIX).unwrap_or_else(|err| {
+        result = Err(format!("cannot list chunks: {err}"));
+        Vec::new()
+    });
+    // Without chunks there is nothing to mark
+    let images = match listed.is_empty() {
+        true => Vec::new(),
+        false => matches.get_many::<String>("image").unwrap().collect(),
+    };
+    let mut marked = HashSet::new();
+    for prefix in images {
+        let (image, _) = open_image(matches, prefix);
+        if !dry_run {
+            let force = matches.get_flag("force-break-lease");
+            match Lease::acquire(image.clone(), LEASE_DURATION, force) {
+                Ok(lease) => {
+                    lease.keep_renewed();
+                    leases.push(lease);
+                }
+                Err(err) => {
+                    result = Err(format!("cannot use {prefix}: {err}"));
+                    break;
+                }
+            }
+        }
+        if let Err(err) = mark_chunks(&*image.as_stored(), &HashSet::new(), &mut marked) {
+            result = Err(format!("cannot read {prefix}: {err}"));
+            break;
+        }
+    }
+
+    let result = result.and_then(|()| {
+        let garbage = unmarked_chunks(listed, &marked);
+        let bytes: u64 = garbage.iter().map(|chunk| chunk.size).sum();
+        if !dry_run {
+            for chunk in &garbage {
+                chunks.delete(&chunk.key).map_err(|err| err.to_string())?;
+            }
+        }
+        let verb = if dry_run { "Would delete" } else { "Deleted" };
+        println!(
+            "{verb} {} chunks unreferenced, {bytes} bytes",
+            garbage.len()
+        );
+        Ok(())
+    });
+    for lease in leases {
+        lease.release();
+    }
+    if let Err(err) = result {
+        eprintln!("Garbage collection failed: {err}");
+        std::process::exit(1);
+    }
+}
+
+// List the versions kept of an object
+fn history(matches: &ArgMatches) {
+    let store = open_store(matches);
+    let key = matches.get_one::<String>("key").unwrap();
+
+    let versions = match store.list_versions(key) {
+        Ok(versions) => versions,
+        Err(err) => {
+            eprintln!("Cannot list versions: {err}");
+            std::process::exit(1);
+        }
+    };
+    for version in versions.iter().filter(|version| version.key == *key) {
+        let state = match (version.deleted, version.latest) {
+            (true, _) => "deleted",
+            (false, true) => "latest",
+            (false, false) => "",
+        };
+        println!(
+            "{}\t{}\t{}\t{}\t{state}",
+            version.version_id,
+            utc_time(version.last_modified),
+            version.size,
+            version.etag,
+        );
+    }
+}
+
+// Manage the passphrases unlocking an encrypted bucket and its data keys
+fn key(matches: &ArgMatches) {
+    let prefix = matches.get_one::<String>("prefix").unwrap();
+    let store =
+        decrypt(open_bucket(matches, prefix), matches).unwrap_or_else(|| passphrase_missing());
+
+    let result = match matches.subcommand() {
+        Some(("add", matches)) => {
+            let passphrase =
+                read_passphrase(matches.get_one::<String>("new-passphrase-file").unwrap());
+            let slot = matches.get_one::<String>("slot").unwrap();
+            store.add_slot(slot, &passphrase, &KdfParams::default())
+        }
+        Some(("remove", matches)) => store.remove_slot(matches.get_one::<String>("slot").unwrap()),
+        Some(("list", _)) => store.slots().map(|slots| {
+            for slot in slots {
+                println!("{slot}");
+            }
+        }),
+        Some(("rotate", _)) => {
+            // Objects are only written by the mount holding the lease
+            let (_, lease) = take_lease(store.clone(), matches);
+            let result = store.rotate().and_then(|()| store.rekey());
+            lease.release();
+            result.map(|count| println!("Encrypted {count} objects with the new data key"))
+        }
+        _ => unreachable!(),
+    };
+    if let Err(err) = result {
+        eprintln!("Key management failed: {err}");
+        std::process::exit(1);
+    }
+}
+
+fn main() {
+    // Command line options
+    let matches = Command::new("s3-time-machine")
+        .subcommand_required(true)
+        .subcommand(
+            Command::new("mount")
+                .about("Mount the filesystem")
+                .arg(arg!(--mountpoint <DIR>).required(true))
+                .args(store_args())
+                .arg(
+                    arg!(--"prefetch-depth" <BANDS> "Number of bands read ahead of sequential reads")
+                        .value_parser(value_parser!(u64)),
+                )
+                .arg(
+                    arg!(--"prefetch-bandwidth" <BYTES_PER_SEC> "Limit on read-ahead bandwidth")
+                        .value_parser(value_parser!(u64).range(1..)),
+                )
+                .arg(
+                    arg!(--"upload-concurrency" <N> "Number of bands uploaded in parallel")
+                        .value_parser(value_parser!(u64).range(1..)),
+                )
+                .arg(
+                    arg!(--"max-dirty" <BYTES> "Modified data kept in memory before writes wait")
+                        .value_parser(value_parser!(u64)),
+                )
+                .arg(arg!(--"read-only" "Mount without ever writing to the bucket"))
+                .arg(arg!(--"no-auto-snapshot" "Don't snapshot when the image is detached with --gc"))
+                .arg(arg!(--at <SNAPSHOT> "Mount a snapshot read-only, by id or UTC time"))
+                .arg(arg!(--gc "Delete expired snapshots and unreferenced objects after each snapshot"))
+                .args(retention_args())
+                .arg(arg!(--rekey "Encrypt every object again with a new data key while mounted"))
+                .arg(
+                    arg!(--compress <CODEC> "Compress bands written with zstd, zstd:LEVEL or lz4")
+                        .value_parser(parse_codec),
+                )
+                .arg(arg!(--dedup "Split bands written into chunks stored once"))
+                .arg(
+                    arg!(--"chunk-size" <BYTES> "Average size of the chunks bands are split into")
+                        .value_parser(parse_chunk_size)
+                        .default_value("65536"),
+                ),
+        )
+        .subcommand(
+            Command::new("snapshot")
+                .about("Record the current state of a filesystem which is not mounted")
+                .args(store_args())
+                .mut_arg("bucket", |arg| arg.required(true)),
+        )
+        .subcommand(
+            Command::new("gc")
+                .about("Delete expired snapshots and the objects no longer referenced")
+                .args(store_args())
+                .mut_arg("bucket", |arg| arg.required(true))
+                .args(retention_args())
+                .arg(arg!(--"dry-run" "Report what would be deleted without deleting it")),
+        )
+        .subcommand(
+            Command::new("history")
+                .about("List the versions a bucket with versioning enabled keeps of an object")
+                .args(store_args())
+                .mut_arg("bucket", |arg| arg.required(true))
+                .arg(arg!([key] "Key of a band or the metadata").default_value(METADATA_KEY)),
+        )
+        .subcommand(
+            Command::new("chunk-gc")
+                .about("Delete the shared chunks none of the filesystems using them refers to")
+                .args(store_args())
+                .mut_arg("bucket", |arg| arg.required(true))
+                .mut_arg("chunk-prefix", |arg| arg.required(true))
+                .arg(
+                    arg!(--image <PREFIX> "Key prefix of a filesystem using the chunks")
+                        .required(true)
+                        .action(ArgAction::Append),
+                )
+                .arg(arg!(--"dry-run" "Report what would be deleted without deleting it")),
+        )
+        .subcommand(
+            Command::new("key")
+                .about("Manage the passphrases and data keys of an encrypted bucket")
+                .args(store_args())
+                .mut_arg("bucket", |arg| arg.required(true))
+                .subcommand_required(true)
+                .subcommand(
+                    Command::new("add")
+                        .about("Let another passphrase unlock the bucket")
+                        .arg(arg!(--slot <NAME> "Name of the key slot").required(true))
+                        .arg(
+                            arg!(--"new-passphrase-file" <FILE> "File holding the passphrase added")
+                                .required(true),
+                        ),
+                )
+                .subcommand(
+                    Command::new("remove")
+                        .about("Stop a passphrase from unlocking the bucket")
+                        .arg(arg!(--slot <NAME> "Name of the key slot").required(true)),
+                )
+                .subcommand(Command::new("list").about("List the key slots"))
+                .subcommand(
+                    Command::new("rotate")
+                        .about("Encrypt every object again with a new data key, while not mounted"),
+                ),
+        )
+        .get_matches();
+
+    match matches.subcommand() {
+        Some(("mount", matches)) => mount(matches),
+        Some(("snapshot", matches)) => snapshot(matches),
+        Some(("gc", matches)) => gc(matches),
+        Some(("history", matches)) => history(matches),
+        Some(("chunk-gc", matches)) => chunk_gc(matches),
+        Some(("key", matches)) => key(matches),
+        _ => unreachable!(),
+    }
 }
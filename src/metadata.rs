@@ -0,0 +1,101 @@
+use crate::backend::{BackendError, ObjectStore};
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::time::SystemTime;
+
+use fuser::FileAttr;
+use serde::{Deserialize, Serialize};
+
+// Key of the object holding the filesystem metadata
+pub const METADATA_KEY: &str = "metadata";
+
+// Bumped whenever the layout of Metadata changes incompatibly
+const FORMAT_VERSION: u32 = 2;
+
+// Everything about the mounted tree except file contents. The whole tree is
+// written as a single object, so a single PUT replaces it atomically and a
+// mount never sees a partial update.
+#[derive(Serialize, Deserialize)]
+pub struct Metadata {
+    pub version: u32,
+    pub next_inode: u64,
+    pub inodes: BTreeMap<u64, FileAttr>,
+    // Directory entries as (parent inode, name, inode)
+    pub names: Vec<(u64, String, u64)>,
+    pub backup_times: BTreeMap<u64, SystemTime>,
+    pub sources: BTreeMap<u64, Source>,
+    // Extended attributes by inode and name
+    #[serde(default)]
+    pub xattrs: BTreeMap<u64, BTreeMap<String, Vec<u8>>>,
+    // Targets of symbolic links by inode
+    #[serde(default)]
+    pub symlinks: BTreeMap<u64, String>,
+    // Number of snapshots taken. Bands at an older generation are referred
+    // to by a snapshot and written to a new generation.
+    #[serde(default)]
+    pub epoch: u64,
+    // Generation of the band objects by inode and band index, if not 0
+    #[serde(default)]
+    pub generations: BTreeMap<u64, BTreeMap<u64, u64>>,
+}
+
+// Contents of a file imported from an object written by another tool. Bands
+// are read from ranges of that object until they are written, after which
+// they are stored as band objects like those of any other file.
+#[derive(Serialize, Deserialize, Clone)]
+pub struct Source {
+    pub key: String,
+    // Length of the object still part of the file
+    pub len: u64,
+    // Bands stored as band objects
+    pub bands: BTreeSet<u64>,
+}
+
+impl Default for Metadata {
+    fn default() -> Metadata {
+        Metadata {
+            version: FORMAT_VERSION,
+            next_inode: 0,
+            inodes: BTreeMap::new(),
+            names: Vec::new(),
+            backup_times: BTreeMap::new(),
+            sources: BTreeMap::new(),
+            xattrs: BTreeMap::new(),
+            symlinks: BTreeMap::new(),
+            epoch: 0,
+            generations: BTreeMap::new(),
+        }
+    }
+}
+
+impl Metadata {
+    // Read the metadata of a filesystem, None if nothing was stored yet
+    pub fn load(store: &dyn ObjectStore) -> Result<Option<Metadata>, BackendError> {
+        let data = match store.get(METADATA_KEY, None) {
+            Ok(data) => data,
+            Err(BackendError::NotFound) => return Ok(None),
+            Err(err) => return Err(err),
+        };
+
+        let metadata: Metadata = serde_json::from_slice(&data)
+            .map_err(|err| BackendError::Other(format!("corrupt metadata: {err}")))?;
+        metadata.check_version()?;
+        Ok(Some(metadata))
+    }
+
+    // Fail on metadata written in a layout this version can't read
+    pub fn check_version(&self) -> Result<(), BackendError> {
+        if self.version != FORMAT_VERSION {
+            return Err(BackendError::Other(format!(
+                "unsupported metadata version {}",
+                self.version
+            )));
+        }
+        Ok(())
+    }
+
+    pub fn save(&self, store: &dyn ObjectStore) -> Result<(), BackendError> {
+        let data = serde_json::to_vec(self).map_err(|err| BackendError::Other(err.to_string()))?;
+        store.put(METADATA_KEY, &data)
+    }
+}
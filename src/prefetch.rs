@@ -0,0 +1,185 @@
+use crate::backend::{BackendError, ObjectStore};
+use crate::cache::{BandCache, BandId};
+
+use std::collections::HashMap;
+use std::ops::Range;
+use std::sync::mpsc::{channel, Receiver, Sender};
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+// Number of threads fetching bands in the background
+const PREFETCH_WORKERS: usize = 4;
+
+// Number of consecutive reads through a file handle before it is considered
+// to be reading sequentially
+const SEQUENTIAL_READS: u32 = 2;
+
+// Read position of a file handle
+struct Stream {
+    next_offset: u64,
+    sequential: u32,
+}
+
+struct Job {
+    id: BandId,
+    key: String,
+    range: Range<u64>,
+}
+
+// Spaces out fetches so they don't exceed a given number of bytes per second
+struct Throttle {
+    bytes_per_sec: u64,
+    next: Mutex<Instant>,
+}
+
+impl Throttle {
+    fn acquire(&self, bytes: u64) {
+        let delay = {
+            let mut next = self.next.lock().unwrap();
+            let now = Instant::now();
+            let start = (*next).max(now);
+            *next = start + Duration::from_secs_f64(bytes as f64 / self.bytes_per_sec as f64);
+            start - now
+        };
+
+        if !delay.is_zero() {
+            thread::sleep(delay);
+        }
+    }
+}
+
+// Detects sequential reads and fetches the bands ahead of them into the cache
+// using a pool of worker threads
+pub struct Prefetcher {
+    streams: HashMap<u64, Stream>,
+    cache: Arc<BandCache>,
+    sender: Option<Sender<Job>>,
+    workers: Vec<JoinHandle<()>>,
+    pending: Arc<(Mutex<usize>, Condvar)>,
+}
+
+impl Prefetcher {
+    pub fn new(
+        store: Arc<dyn ObjectStore>,
+        cache: Arc<BandCache>,
+        bandwidth: Option<u64>,
+    ) -> Prefetcher {
+        let (sender, receiver) = channel::<Job>();
+        let receiver = Arc::new(Mutex::new(receiver));
+        let pending = Arc::new((Mutex::new(0), Condvar::new()));
+        let throttle = bandwidth.map(|bytes_per_sec| {
+            Arc::new(Throttle {
+                bytes_per_sec,
+                next: Mutex::new(Instant::now()),
+            })
+        });
+
+        let workers = (0..PREFETCH_WORKERS)
+            .map(|_| {
+                let store = store.clone();
+                let cache = cache.clone();
+                let receiver = receiver.clone();
+                let pending = pending.clone();
+                let throttle = throttle.clone();
+                thread::spawn(move || worker(store, cache, receiver, pending, throttle))
+            })
+            .collect();
+
+        Prefetcher {
+            streams: HashMap::new(),
+            cache,
+            sender: Some(sender),
+            workers,
+            pending,
+        }
+    }
+
+    // Record a read through a file handle. Returns whether the handle is
+    // reading sequentially.
+    pub fn observe(&mut self, fh: u64, offset: u64, size: u64) -> bool {
+        let stream = self.streams.entry(fh).or_insert(Stream {
+            next_offset: 0,
+            sequential: 0,
+        });
+
+        if offset == stream.next_offset {
+            stream.sequential += 1;
+        } else {
+            stream.sequential = 0;
+        }
+        stream.next_offset = offset + size;
+
+        stream.sequential >= SEQUENTIAL_READS
+    }
+
+    // Fetch a band, stored as the given range of an object, in the
+    // background unless it is cached or already being fetched
+    pub fn fetch(&self, id: BandId, key: String, range: Range<u64>) {
+        if !self.cache.begin_load(id) {
+            return;
+        }
+
+        *self.pending.0.lock().unwrap() += 1;
+        if let Some(sender) = &self.sender {
+            sender.send(Job { id, key, range }).unwrap();
+        }
+    }
+
+    // Drop the read position of a released file handle
+    pub fn forget(&mut self, fh: u64) {
+        self.streams.remove(&fh);
+    }
+
+    // Wait for all fetches in progress to complete
+    pub fn wait_idle(&self) {
+        let (count, idle) = &*self.pending;
+        let mut count = count.lock().unwrap();
+        while *count > 0 {
+            count = idle.wait(count).unwrap();
+        }
+    }
+}
+
+impl Drop for Prefetcher {
+    fn drop(&mut self) {
+        // Closing the channel stops the workers
+        self.sender = None;
+        for worker in self.workers.drain(..) {
+            worker.join().unwrap();
+        }
+    }
+}
+
+fn worker(
+    store: Arc<dyn ObjectStore>,
+    cache: Arc<BandCache>,
+    receiver: Arc<Mutex<Receiver<Job>>>,
+    pending: Arc<(Mutex<usize>, Condvar)>,
+    throttle: Option<Arc<Throttle>>,
+) {
+    loop {
+        let job = match receiver.lock().unwrap().recv() {
+            Ok(job) => job,
+            Err(_) => return,
+        };
+
+        if let Some(throttle) = &throttle {
+            throttle.acquire(job.range.end - job.range.start);
+        }
+
+        let data = match store.get(&job.key, Some(job.range)) {
+            Ok(data) => Some(data),
+            Err(BackendError::NotFound) => Some(Vec::new()),
+            Err(err) => {
+                println!("\tprefetch {} failed: {err}", job.key);
+                None
+            }
+        };
+        cache.finish_load(job.id, data);
+
+        let (count, idle) = &*pending;
+        *count.lock().unwrap() -= 1;
+        idle.notify_all();
+    }
+}
@@ -0,0 +1,121 @@
+use crate::backend::{BackendError, ObjectInfo, ObjectStore, ObjectVersion};
+
+use std::ops::Range;
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+// How often and how long to wait before retrying a failed request
+#[derive(Clone)]
+pub struct RetryPolicy {
+    pub max_attempts: u32,
+    pub base_delay: Duration,
+    pub max_delay: Duration,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> RetryPolicy {
+        RetryPolicy {
+            max_attempts: 6,
+            base_delay: Duration::from_millis(100),
+            max_delay: Duration::from_secs(10),
+        }
+    }
+}
+
+impl RetryPolicy {
+    // Exponential backoff with full jitter: a random delay up to twice the
+    // previous limit, so clients throttled together don't retry together
+    pub fn delay(&self, attempt: u32) -> Duration {
+        let limit = self
+            .base_delay
+            .saturating_mul(1 << attempt.min(16))
+            .min(self.max_delay);
+        limit.mul_f64(fastrand::f64())
+    }
+}
+
+// Object store retrying transient failures of another store. Errors which
+// won't go away by retrying, like access denied or not found, are returned
+// immediately.
+pub struct RetryStore {
+    inner: Arc<dyn ObjectStore>,
+    policy: RetryPolicy,
+}
+
+impl RetryStore {
+    pub fn new(inner: Arc<dyn ObjectStore>, policy: RetryPolicy) -> RetryStore {
+        RetryStore { inner, policy }
+    }
+
+    fn retry<T>(
+        &self,
+        op: &str,
+        key: &str,
+        f: impl Fn() -> Result<T, BackendError>,
+    ) -> Result<T, BackendError> {
+        let mut attempt = 0;
+        loop {
+            match f() {
+                Err(err) if err.is_transient() && attempt + 1 < self.policy.max_attempts => {
+                    let delay = self.policy.delay(attempt);
+                    println!("\t{op} {key} failed: {err}, retrying in {delay:?}");
+                    thread::sleep(delay);
+                    attempt += 1;
+                }
+                result => return result,
+            }
+        }
+    }
+}
+
+impl ObjectStore for RetryStore {
+    fn get(&self, key: &str, range: Option<Range<u64>>) -> Result<Vec<u8>, BackendError> {
+        self.retry("get", key, || self.inner.get(key, range.clone()))
+    }
+
+    fn put(&self, key: &str, data: &[u8]) -> Result<(), BackendError> {
+        self.retry("put", key, || self.inner.put(key, data))
+    }
+
+    fn get_tagged(&self, key: &str) -> Result<(Vec<u8>, String), BackendError> {
+        self.retry("get", key, || self.inner.get_tagged(key))
+    }
+
+    // A retried write may find the object changed by its own first attempt,
+    // callers must check for that when it fails with PreconditionFailed
+    fn put_if(&self, key: &str, data: &[u8], etag: Option<&str>) -> Result<String, BackendError> {
+        self.retry("put", key, || self.inner.put_if(key, data, etag))
+    }
+
+    fn delete(&self, key: &str) -> Result<(), BackendError> {
+        self.retry("delete", key, || self.inner.delete(key))
+    }
+
+    fn copy(&self, from: &str, range: Option<Range<u64>>, to: &str) -> Result<(), BackendError> {
+        self.retry("copy", to, || self.inner.copy(from, range.clone(), to))
+    }
+
+    fn list(&self, prefix: &str) -> Result<Vec<ObjectInfo>, BackendError> {
+        self.retry("list", prefix, || self.inner.list(prefix))
+    }
+
+    fn version(&self, key: &str) -> Option<String> {
+        self.inner.version(key)
+    }
+
+    fn get_version(
+        &self,
+        key: &str,
+        version_id: &str,
+        range: Option<Range<u64>>,
+    ) -> Result<Vec<u8>, BackendError> {
+        self.retry("get", key, || {
+            self.inner.get_version(key, version_id, range.clone())
+        })
+    }
+
+    fn list_versions(&self, prefix: &str) -> Result<Vec<ObjectVersion>, BackendError> {
+        self.retry("list", prefix, || self.inner.list_versions(prefix))
+    }
+}
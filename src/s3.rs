@@ -0,0 +1,442 @@
+use crate::backend::{BackendError, ObjectInfo, ObjectStore, ObjectVersion};
+
+use std::collections::HashMap;
+use std::ops::Range;
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use aws_config::retry::RetryConfig;
+use aws_config::timeout::TimeoutConfig;
+use aws_config::BehaviorVersion;
+use aws_sdk_s3::error::{DisplayErrorContext, ProvideErrorMetadata, SdkError};
+use aws_sdk_s3::primitives::ByteStream;
+use aws_sdk_s3::types::{CompletedMultipartUpload, CompletedPart};
+use aws_sdk_s3::Client;
+use tokio::runtime::Runtime;
+
+// Object store backed by an S3 bucket
+pub struct S3Store {
+    runtime: Runtime,
+    client: Client,
+    bucket: String,
+    prefix: String,
+    // Version ids given to the objects written, if the bucket has versioning
+    // enabled
+    versions: Mutex<HashMap<String, String>>,
+}
+
+impl S3Store {
+    pub fn new(
+        bucket: &str,
+        prefix: &str,
+        region: Option<&str>,
+        endpoint: Option<&str>,
+    ) -> S3Store {
+        let runtime = Runtime::new().unwrap();
+
+        let mut loader = aws_config::defaults(BehaviorVersion::latest());
+        if let Some(region) = region {
+            loader = loader.region(aws_config::Region::new(region.to_string()));
+        }
+        let sdk_config = runtime.block_on(loader.load());
+
+        // Retries are left to the caller, which knows which failures are
+        // worth retrying
+        let timeouts = TimeoutConfig::builder()
+            .connect_timeout(Duration::from_secs(10))
+            .read_timeout(Duration::from_secs(60))
+            .build();
+        let mut builder = aws_sdk_s3::config::Builder::from(&sdk_config)
+            .retry_config(RetryConfig::disabled())
+            .timeout_config(timeouts);
+        if let Some(endpoint) = endpoint {
+            // Custom endpoints are usually S3-compatible servers like MinIO
+            builder = builder.endpoint_url(endpoint).force_path_style(true);
+        }
+        let client = Client::from_conf(builder.build());
+
+        // Keys are stored below the prefix as if it were a directory
+        let mut prefix = prefix.to_string();
+        if !prefix.is_empty() && !prefix.ends_with('/') {
+            prefix.push('/');
+        }
+
+        S3Store {
+            runtime,
+            client,
+            bucket: bucket.to_string(),
+            prefix,
+            versions: Mutex::default(),
+        }
+    }
+
+    fn key(&self, key: &str) -> String {
+        format!("{}{}", self.prefix, key)
+    }
+
+    // Remember the version id a write returned
+    fn record_version(&self, key: &str, version_id: Option<&str>) {
+        let mut versions = self.versions.lock().unwrap();
+        match version_id {
+            Some(version_id) => versions.insert(key.to_string(), version_id.to_string()),
+            None => versions.remove(key),
+        };
+    }
+
+    // Read an object, or a version of it
+    fn get_object(
+        &self,
+        key: &str,
+        version_id: Option<&str>,
+        range: Option<Range<u64>>,
+    ) -> Result<Vec<u8>, BackendError> {
+        let mut request = self
+            .client
+            .get_object()
+            .bucket(&self.bucket)
+            .key(self.key(key))
+            .set_version_id(version_id.map(str::to_string));
+        if let Some(range) = &range {
+            if range.is_empty() {
+                return Ok(Vec::new());
+            }
+            request = request.range(format!("bytes={}-{}", range.start, range.end - 1));
+        }
+
+        self.runtime.block_on(async {
+            match request.send().await {
+                Ok(output) => match output.body.collect().await {
+                    Ok(data) => Ok(data.into_bytes().to_vec()),
+                    // The connection failed while receiving the body
+                    Err(err) => Err(BackendError::Connection(err.to_string())),
+                },
+                // A range starting past the end of the object is not an error
+                Err(err) if range.is_some() && status(&err) == Some(416) => Ok(Vec::new()),
+                Err(err) => Err(backend_error(err)),
+            }
+        })
+    }
+}
+
+// Percent-encode a key for use in a copy source, keeping the '/' separators
+fn encode_key(key: &str) -> String {
+    let mut encoded = String::new();
+    for byte in key.bytes() {
+        match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' | b'/' => {
+                encoded.push(byte as char)
+            }
+            _ => encoded.push_str(&format!("%{byte:02X}")),
+        }
+    }
+    encoded
+}
+
+// HTTP status of the response that caused an SDK error, if there was one
+fn status<E>(err: &SdkError<E>) -> Option<u16> {
+    err.raw_response()
+        .map(|response| response.status().as_u16())
+}
+
+// Classify an SDK error as a backend error
+fn backend_error<E>(err: SdkError<E>) -> BackendError
+where
+    E: std::error::Error + ProvideErrorMetadata + Send + Sync + 'static,
+{
+    let msg = DisplayErrorContext(&err).to_string();
+    match &err {
+        SdkError::TimeoutError(_) | SdkError::DispatchFailure(_) | SdkError::ResponseError(_) => {
+            return BackendError::Connection(msg)
+        }
+        _ => (),
+    }
+
+    match (err.code(), status(&err)) {
+        (Some("NoSuchBucket"), _) => BackendError::Other(msg),
+        (Some("SlowDown" | "Throttling" | "RequestLimitExceeded"), _) | (_, Some(503)) => {
+            BackendError::Throttled
+        }
+        (Some("QuotaExceeded" | "EntityTooLarge"), _) => BackendError::NoSpace,
+        (Some("PreconditionFailed" | "ConditionalRequestConflict"), _) | (_, Some(412)) => {
+            BackendError::PreconditionFailed
+        }
+        (_, Some(404)) => BackendError::NotFound,
+        (_, Some(403)) => BackendError::AccessDenied,
+        (_, Some(status)) if status >= 500 => BackendError::Server(status),
+        _ => BackendError::Other(msg),
+    }
+}
+
+impl ObjectStore for S3Store {
+    fn get(&self, key: &str, range: Option<Range<u64>>) -> Result<Vec<u8>, BackendError> {
+        self.get_object(key, None, range)
+    }
+
+    fn put(&self, key: &str, data: &[u8]) -> Result<(), BackendError> {
+        let request = self
+            .client
+            .put_object()
+            .bucket(&self.bucket)
+            .key(self.key(key))
+            .body(ByteStream::from(data.to_vec()));
+
+        let output = self
+            .runtime
+            .block_on(request.send())
+            .map_err(backend_error)?;
+        self.record_version(key, output.version_id());
+        Ok(())
+    }
+
+    fn get_tagged(&self, key: &str) -> Result<(Vec<u8>, String), BackendError> {
+        let request = self
+            .client
+            .get_object()
+            .bucket(&self.bucket)
+            .key(self.key(key));
+
+        self.runtime.block_on(async {
+            let output = request.send().await.map_err(backend_error)?;
+            let etag = output.e_tag().unwrap_or_default().to_string();
+            match output.body.collect().await {
+                Ok(data) => Ok((data.into_bytes().to_vec(), etag)),
+                Err(err) => Err(BackendError::Connection(err.to_string())),
+            }
+        })
+    }
+
+    fn put_if(&self, key: &str, data: &[u8], etag: Option<&str>) -> Result<String, BackendError> {
+        let request = self
+            .client
+            .put_object()
+            .bucket(&self.bucket)
+            .key(self.key(key))
+            .body(ByteStream::from(data.to_vec()));
+        let request = match etag {
+            Some(etag) => request.if_match(etag),
+            None => request.if_none_match("*"),
+        };
+
+        let output = self
+            .runtime
+            .block_on(request.send())
+            .map_err(backend_error)?;
+        self.record_version(key, output.version_id());
+        Ok(output.e_tag().unwrap_or_default().to_string())
+    }
+
+    fn delete(&self, key: &str) -> Result<(), BackendError> {
+        let request = self
+            .client
+            .delete_object()
+            .bucket(&self.bucket)
+            .key(self.key(key));
+
+        self.runtime
+            .block_on(request.send())
+            .map_err(backend_error)?;
+        self.record_version(key, None);
+        Ok(())
+    }
+
+    fn copy(&self, from: &str, range: Option<Range<u64>>, to: &str) -> Result<(), BackendError> {
+        let source = format!("{}/{}", self.bucket, encode_key(&self.key(from)));
+
+        let range = match range {
+            None => {
+                let request = self
+                    .client
+                    .copy_object()
+                    .bucket(&self.bucket)
+                    .key(self.key(to))
+                    .copy_source(source);
+                let output = self
+                    .runtime
+                    .block_on(request.send())
+                    .map_err(backend_error)?;
+                self.record_version(to, output.version_id());
+                return Ok(());
+            }
+            Some(range) if range.is_empty() => return self.put(to, &[]),
+            Some(range) => range,
+        };
+
+        // Part of an object is copied as the single part of a multipart
+        // upload, which may be smaller than the usual minimum part size
+        let output = self.runtime.block_on(async {
+            let upload = self
+                .client
+                .create_multipart_upload()
+                .bucket(&self.bucket)
+                .key(self.key(to))
+                .send()
+                .await
+                .map_err(backend_error)?;
+            let upload_id = upload.upload_id().unwrap_or_default();
+
+            let part = self
+                .client
+                .upload_part_copy()
+                .bucket(&self.bucket)
+                .key(self.key(to))
+                .upload_id(upload_id)
+                .part_number(1)
+                .copy_source(source)
+                .copy_source_range(format!("bytes={}-{}", range.start, range.end - 1))
+                .send()
+                .await;
+            let etag = match part {
+                Ok(part) => part
+                    .copy_part_result()
+                    .and_then(|result| result.e_tag())
+                    .map(str::to_string),
+                Err(err) => {
+                    let _ = self
+                        .client
+                        .abort_multipart_upload()
+                        .bucket(&self.bucket)
+                        .key(self.key(to))
+                        .upload_id(upload_id)
+                        .send()
+                        .await;
+                    return Err(backend_error(err));
+                }
+            };
+
+            let parts = CompletedMultipartUpload::builder()
+                .parts(
+                    CompletedPart::builder()
+                        .set_e_tag(etag)
+                        .part_number(1)
+                        .build(),
+                )
+                .build();
+            self.client
+                .complete_multipart_upload()
+                .bucket(&self.bucket)
+                .key(self.key(to))
+                .upload_id(upload_id)
+                .multipart_upload(parts)
+                .send()
+                .await
+                .map_err(backend_error)
+        })?;
+        self.record_version(to, output.version_id());
+        Ok(())
+    }
+
+    fn list(&self, prefix: &str) -> Result<Vec<ObjectInfo>, BackendError> {
+        let mut objects = Vec::new();
+        let mut continuation = None;
+
+        // Listings are returned a page of up to 1000 keys at a time
+        loop {
+            let request = self
+                .client
+                .list_objects_v2()
+                .bucket(&self.bucket)
+                .prefix(self.key(prefix))
+                .set_continuation_token(continuation);
+            let output = self
+                .runtime
+                .block_on(request.send())
+                .map_err(backend_error)?;
+
+            for object in output.contents() {
+                let Some(key) = object.key() else { continue };
+                objects.push(ObjectInfo {
+                    key: key[self.prefix.len()..].to_string(),
+                    size: object.size().unwrap_or(0).max(0) as u64,
+                    last_modified: object
+                        .last_modified()
+                        .and_then(|time| SystemTime::try_from(*time).ok())
+                        .unwrap_or(UNIX_EPOCH),
+                    etag: object.e_tag().unwrap_or_default().to_string(),
+                });
+            }
+
+            match output.next_continuation_token() {
+                Some(token) if output.is_truncated() == Some(true) => {
+                    continuation = Some(token.to_string())
+                }
+                _ => return Ok(objects),
+            }
+        }
+    }
+
+    fn version(&self, key: &str) -> Option<String> {
+        self.versions.lock().unwrap().get(key).cloned()
+    }
+
+    fn get_version(
+        &self,
+        key: &str,
+        version_id: &str,
+        range: Option<Range<u64>>,
+    ) -> Result<Vec<u8>, BackendError> {
+        self.get_object(key, Some(version_id), range)
+    }
+
+    fn list_versions(&self, prefix: &str) -> Result<Vec<ObjectVersion>, BackendError> {
+        let mut versions = Vec::new();
+        let mut key_marker = None;
+        let mut version_id_marker = None;
+
+        loop {
+            let request = self
+                .client
+                .list_object_versions()
+                .bucket(&self.bucket)
+                .prefix(self.key(prefix))
+                .set_key_marker(key_marker)
+                .set_version_id_marker(version_id_marker);
+            let output = self
+                .runtime
+                .block_on(request.send())
+                .map_err(backend_error)?;
+
+            let time = |time: Option<&aws_sdk_s3::primitives::DateTime>| {
+                time.and_then(|time| SystemTime::try_from(*time).ok())
+                    .unwrap_or(UNIX_EPOCH)
+            };
+            for version in output.versions() {
+                let Some(key) = version.key() else { continue };
+                versions.push(ObjectVersion {
+                    key: key[self.prefix.len()..].to_string(),
+                    version_id: version.version_id().unwrap_or("null").to_string(),
+                    size: version.size().unwrap_or(0).max(0) as u64,
+                    last_modified: time(version.last_modified()),
+                    etag: version.e_tag().unwrap_or_default().to_string(),
+                    latest: version.is_latest() == Some(true),
+                    deleted: false,
+                });
+            }
+            for marker in output.delete_markers() {
+                let Some(key) = marker.key() else { continue };
+                versions.push(ObjectVersion {
+                    key: key[self.prefix.len()..].to_string(),
+                    version_id: marker.version_id().unwrap_or("null").to_string(),
+                    size: 0,
+                    last_modified: time(marker.last_modified()),
+                    etag: String::new(),
+                    latest: marker.is_latest() == Some(true),
+                    deleted: true,
+                });
+            }
+
+            if output.is_truncated() != Some(true) {
+                break;
+            }
+            key_marker = output.next_key_marker().map(str::to_string);
+            version_id_marker = output.next_version_id_marker().map(str::to_string);
+        }
+
+        // Versions and delete markers are listed separately
+        versions.sort_by(|a, b| {
+            a.key
+                .cmp(&b.key)
+                .then(b.last_modified.cmp(&a.last_modified))
+        });
+        Ok(versions)
+    }
+}
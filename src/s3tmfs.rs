@@ -1,18 +1,49 @@
+use crate::backend::{BackendError, ObjectStore};
+use crate::cache::{band_key, BandCache, BandId, Generations, StoredBands, BAND_PREFIX};
+use crate::dedup::DedupStore;
+use crate::gc::{collect, Garbage, Retention};
+use crate::lease::LEASE_KEY;
+use crate::lock::{Lock, LockTable, F_RDLCK, F_UNLCK, F_WRLCK};
+use crate::metadata::{Metadata, Source, METADATA_KEY};
+use crate::prefetch::Prefetcher;
+use crate::retry::RetryPolicy;
+use crate::snapshot::{snapshot_id, Manifest, SNAPSHOT_PREFIX};
+use crate::upload::Uploader;
 use crate::wrapperfs::{
-    ReplyAttr, ReplyBmap, ReplyCreate, ReplyData, ReplyDirectory, ReplyDirectoryPlus, ReplyEntry,
-    ReplyIoctl, ReplyLock, ReplyLseek, ReplyOpen, ReplyStatfs, ReplyWrite, ReplyXattr,
-    WrappedFilesystem,
+    DirectoryEntry, ReplyAttr, ReplyBmap, ReplyCreate, ReplyData, ReplyDirectory,
+    ReplyDirectoryPlus, ReplyEntry, ReplyIoctl, ReplyLock, ReplyLseek, ReplyOpen, ReplyStatfs,
+    ReplyWrite, ReplyXattr, WrappedFilesystem,
 };
 
 #[cfg(feature = "macos")]
 use crate::wrapperfs::ReplyXTimes;
 
-use std::collections::HashMap;
-use std::time::{Duration, UNIX_EPOCH};
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+use std::ops::Range;
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
-use libc::ENOENT;
+use libc::{
+    EAGAIN, EEXIST, EINVAL, EISDIR, ENOENT, ENOLCK, ENOSYS, ENOTDIR, ENOTEMPTY, ENXIO, EOPNOTSUPP,
+    EPERM, ERANGE, EROFS, SEEK_DATA, SEEK_END, SEEK_HOLE, SEEK_SET,
+};
+
+// Error for a missing extended attribute, which Linux calls ENODATA
+#[cfg(target_os = "macos")]
+use libc::ENOATTR;
+#[cfg(not(target_os = "macos"))]
+use libc::ENODATA as ENOATTR;
+
+use fuser::{FileAttr, FileType, TimeOrNow, FUSE_ROOT_ID};
 
-use fuser::{FileAttr, FileType, FUSE_ROOT_ID};
+// Flags of the Linux renameat2() call
+const RENAME_NOREPLACE: u32 = 1;
+const RENAME_EXCHANGE: u32 = 2;
+
+// Modes of the Linux fallocate() call
+const FALLOC_FL_KEEP_SIZE: i32 = 1;
+const FALLOC_FL_PUNCH_HOLE: i32 = 2;
 
 // Default TTL value
 const TTL: Duration = Duration::from_secs(1); // 1 second
@@ -36,40 +67,1207 @@ const ROOT_DIR_ATTR: FileAttr = FileAttr {
     blksize: 512,
 };
 
+// Filesystem tuning
+pub struct Config {
+    // Size of the bands file contents are stored in
+    pub band_size: u64,
+    // Number of bands kept in memory
+    pub cache_bands: usize,
+    // Number of bands fetched ahead of a sequential reader
+    pub prefetch_depth: u64,
+    // Maximum bytes per second fetched ahead of readers
+    pub prefetch_bandwidth: Option<u64>,
+    // Number of bands uploaded in parallel
+    pub upload_concurrency: usize,
+    // Amount of modified data kept in memory before writes wait for uploads
+    pub max_dirty_bytes: u64,
+    // How uploads failing once the store stopped retrying are tried again
+    pub upload_retry: RetryPolicy,
+    // Refuse every modification and never write to the store
+    pub read_only: bool,
+    // Take a snapshot when the image is detached after its contents changed,
+    // which is when the last open file is closed, and on unmount. Only
+    // enabled along with a retention policy, so snapshots don't pile up.
+    pub auto_snapshot: bool,
+    // Id of the snapshot to show instead of the current tree, which requires
+    // a read-only mount
+    pub snapshot: Option<String>,
+    // Collect garbage after every automatic snapshot, keeping snapshots as
+    // given
+    pub retention: Option<Retention>,
+}
+
+impl Default for Config {
+    fn default() -> Config {
+        Config {
+            band_size: 8 * 1024 * 1024, // 8 MiB, same as a sparsebundle band
+            cache_bands: 64,
+            prefetch_depth: 4,
+            prefetch_bandwidth: None,
+            upload_concurrency: 8,
+            max_dirty_bytes: 256 * 1024 * 1024,
+            upload_retry: RetryPolicy::default(),
+            read_only: false,
+            auto_snapshot: false,
+            snapshot: None,
+            retention: None,
+        }
+    }
+}
+
 // Our filesystem
 pub struct S3TMFS {
+    config: Config,
     next_inode: u64,
+    next_fh: u64,
     inode_map: HashMap<u64, FileAttr>,
-    name_map: HashMap<String, u64>,
+    // Directory entries by parent inode and name, ordered so the entries of a
+    // directory can be listed from a range
+    name_map: BTreeMap<(u64, String), u64>,
+    // macOS backup times, which FileAttr has no field for
+    backup_times: HashMap<u64, SystemTime>,
+    // Files imported from objects in the store
+    sources: HashMap<u64, Source>,
+    // Extended attributes by inode and name
+    xattrs: HashMap<u64, BTreeMap<String, Vec<u8>>>,
+    // Targets of symbolic links
+    symlinks: HashMap<u64, String>,
+    // Whether the metadata changed since it was last stored
+    metadata_dirty: bool,
+    // Number of snapshots taken, see Metadata
+    epoch: u64,
+    generations: Arc<Generations>,
+    stored: Arc<StoredBands>,
+    // Number of files open, and whether anything changed since the last
+    // snapshot
+    open_files: usize,
+    // Handles open on each file, and the files unlinked while open. Those
+    // are left out of the stored tree, and their contents are dropped once
+    // their last handle is closed.
+    open_handles: HashMap<u64, usize>,
+    orphans: HashSet<u64>,
+    snapshot_pending: bool,
+    // Garbage being deleted in the background
+    sweeper: Option<JoinHandle<()>>,
+    // Store splitting bands into chunks, which garbage collection looks
+    // through for the chunks no band refers to
+    dedup: Option<Arc<DedupStore>>,
+    locks: LockTable,
+    store: Arc<dyn ObjectStore>,
+    cache: Arc<BandCache>,
+    prefetcher: Prefetcher,
+    uploader: Uploader,
+    read_buf: Vec<u8>,
+}
+
+// An entry removed from the tree, kept until the removal is stored. Unless
+// it was the last link to its inode, only the entry itself is removed.
+struct Detached {
+    attr: FileAttr,
+    last_link: bool,
+    backup_time: Option<SystemTime>,
+    source: Option<Source>,
+    xattrs: Option<BTreeMap<String, Vec<u8>>>,
+    symlink: Option<String>,
 }
 
 // WrappedFilesystem implements Filesystem and exposes request-less interface
 
 impl S3TMFS {
-    pub fn new() -> S3TMFS {
+    pub fn new(store: Arc<dyn ObjectStore>, config: Config) -> S3TMFS {
         let next_inode = FUSE_ROOT_ID + 1;
 
         let mut inode_map = HashMap::new();
         inode_map.insert(FUSE_ROOT_ID, ROOT_DIR_ATTR);
 
-        let mut name_map = HashMap::new();
-        name_map.insert(".".to_string(), FUSE_ROOT_ID);
+        let cache = Arc::new(BandCache::new(config.cache_bands));
+        let generations = Arc::new(Generations::new());
+        let stored = Arc::new(StoredBands::new());
+        let prefetcher = Prefetcher::new(store.clone(), cache.clone(), config.prefetch_bandwidth);
+        let concurrency = match config.read_only {
+            true => 0,
+            false => config.upload_concurrency,
+        };
+        let uploader = Uploader::new(
+            store.clone(),
+            cache.clone(),
+            generations.clone(),
+            stored.clone(),
+            concurrency,
+            config.max_dirty_bytes,
+            config.upload_retry.clone(),
+        );
 
         S3TMFS {
+            config,
             next_inode,
+            next_fh: 1,
             inode_map,
-            name_map,
+            name_map: BTreeMap::new(),
+            backup_times: HashMap::new(),
+            sources: HashMap::new(),
+            xattrs: HashMap::new(),
+            symlinks: HashMap::new(),
+            metadata_dirty: false,
+            epoch: 0,
+            generations,
+            stored,
+            open_files: 0,
+            open_handles: HashMap::new(),
+            orphans: HashSet::new(),
+            snapshot_pending: false,
+            sweeper: None,
+            dedup: None,
+            locks: LockTable::new(),
+            store,
+            cache,
+            prefetcher,
+            uploader,
+            read_buf: Vec::new(),
+        }
+    }
+
+    // Collect the chunks of the store splitting bands along with the other
+    // garbage. The store given to new() writes through it.
+    pub fn set_dedup(&mut self, dedup: Arc<DedupStore>) {
+        self.dedup = Some(dedup);
+    }
+
+    // Wait for background fetches and uploads to complete
+    pub fn wait_idle(&self) {
+        self.prefetcher.wait_idle();
+        self.uploader.wait_idle();
+    }
+
+    // Where a band is stored, as an object and the range of it holding the
+    // band. None if nothing is stored for the band.
+    fn band_location(&self, id: BandId) -> Option<(String, Range<u64>)> {
+        let band_size = self.config.band_size;
+        match self.sources.get(&id.0) {
+            Some(source) if !source.bands.contains(&id.1) => {
+                let start = id.1 * band_size;
+                if start >= source.len {
+                    return None;
+                }
+                Some((
+                    source.key.clone(),
+                    start..(start + band_size).min(source.len),
+                ))
+            }
+            _ => Some((self.generations.key(id), 0..band_size)),
+        }
+    }
+
+    // Read a band, or part of it, from the store. Bands which were never
+    // written read as empty.
+    fn fetch_band(&self, id: BandId, range: Option<Range<u64>>) -> Result<Vec<u8>, i32> {
+        let Some((key, extent)) = self.band_location(id) else {
+            return Ok(Vec::new());
+        };
+        let range = match range {
+            Some(range) => {
+                let end = (extent.start + range.end).min(extent.end);
+                (extent.start + range.start).min(end)..end
+            }
+            None => extent,
+        };
+
+        match self.store.get(&key, Some(range)) {
+            Ok(data) => Ok(data),
+            Err(BackendError::NotFound) => Ok(Vec::new()),
+            Err(err) => {
+                println!("\t{err}");
+                Err(err.errno())
+            }
+        }
+    }
+
+    // Make sure a band is in the cache
+    fn load_band(&self, id: BandId) -> Result<(), i32> {
+        if self.cache.contains(id) {
+            return Ok(());
+        }
+
+        let data = self.fetch_band(id, None)?;
+        self.cache.insert(id, data);
+        Ok(())
+    }
+
+    // Read part of a file, which must lie within the file
+    fn read_data(&self, ino: u64, start: u64, end: u64, sequential: bool) -> Result<Vec<u8>, i32> {
+        let band_size = self.config.band_size;
+        let mut buf = vec![0; (end - start) as usize];
+        let mut pos = start;
+        while pos < end {
+            let id = (ino, pos / band_size);
+            let band_offset = pos % band_size;
+            let len = (band_size - band_offset).min(end - pos);
+            let chunk = &mut buf[(pos - start) as usize..(pos - start + len) as usize];
+
+            // Sequential readers get whole bands cached for the reads that
+            // follow, random reads only fetch the range asked for
+            let mut cached = self.cache.read(id, band_offset as usize, chunk);
+            if !cached && sequential {
+                self.load_band(id)?;
+                cached = self.cache.read(id, band_offset as usize, chunk);
+            }
+            if !cached {
+                let data = self.fetch_band(id, Some(band_offset..band_offset + len))?;
+                chunk[..data.len()].copy_from_slice(&data);
+            }
+
+            pos += len;
+        }
+        Ok(buf)
+    }
+
+    // Write to a file through the cache, extending it if needed
+    fn write_data(&mut self, ino: u64, start: u64, data: &[u8]) -> Result<(), i32> {
+        let file_size = self.inode_map[&ino].size;
+        let band_size = self.config.band_size;
+        let mut pos = 0;
+        while pos < data.len() {
+            let file_pos = start + pos as u64;
+            let id = (ino, file_pos / band_size);
+            let band_offset = (file_pos % band_size) as usize;
+            let len = (band_size as usize - band_offset).min(data.len() - pos);
+
+            let chunk = &data[pos..pos + len];
+            if !self.cache.write(id, band_offset, chunk, None) {
+                // Bands past the end of the file have nothing stored yet
+                let base = if id.1 * band_size >= file_size {
+                    Vec::new()
+                } else {
+                    self.fetch_band(id, None)?
+                };
+                self.cache.write(id, band_offset, chunk, Some(base));
+            }
+            self.band_written(id);
+
+            // Upload bands once the writer has moved past them
+            if band_offset + len == band_size as usize {
+                self.uploader.schedule(id);
+            }
+
+            pos += len;
         }
+
+        self.extend(ino, start + data.len() as u64);
+        Ok(())
     }
+
+    // Update the attributes of a file after its contents changed up to `end`
+    fn extend(&mut self, ino: u64, end: u64) {
+        let attr = self.inode_map.get_mut(&ino).unwrap();
+        attr.size = attr.size.max(end);
+        attr.blocks = attr.size.div_ceil(512);
+        attr.mtime = SystemTime::now();
+        attr.ctime = attr.mtime;
+        self.metadata_dirty = true;
+    }
+
+    // Copy a whole band of one file over a band of another inside the store
+    fn copy_band(&mut self, src: BandId, dst: BandId) -> Result<(), i32> {
+        self.uploader.forget_bands(dst.0, dst.1..dst.1 + 1);
+        self.cache.remove_bands(dst.0, dst.1..dst.1 + 1);
+
+        // Band objects are copied whole, imported files by range
+        let dst_key = band_key(dst, self.write_generation(dst));
+        let result = match self.band_location(src) {
+            Some((key, _)) if key == self.generations.key(src) => {
+                self.store.copy(&key, None, &dst_key)
+            }
+            Some((key, range)) => self.store.copy(&key, Some(range), &dst_key),
+            None => Err(BackendError::NotFound),
+        };
+        let result = match result {
+            // Nothing is stored for bands never written
+            Err(BackendError::NotFound) => self
+                .store
+                .delete(&dst_key)
+                .map(|_| self.stored.remove(dst.0, &dst_key)),
+            result => result.map(|_| self.stored.insert(dst.0, dst_key)),
+        };
+        if let Err(err) = result {
+            println!("\t{err}");
+            return Err(err.errno());
+        }
+
+        self.band_written(dst);
+        Ok(())
+    }
+
+    // Bands of a file holding data: those of an imported object, those with
+    // a band object stored and those waiting to be uploaded. Others read as
+    // zeros.
+    fn data_bands(&self, ino: u64) -> Result<BTreeSet<u64>, i32> {
+        let count = self.inode_map[&ino].size.div_ceil(self.config.band_size);
+        let list = || {
+            let listing = self.store.list(&format!("{BAND_PREFIX}{ino:x}/"))?;
+            Ok(listing.into_iter().map(|object| object.key).collect())
+        };
+        let stored = self.stored.keys(ino, list).map_err(|err: BackendError| {
+            println!("\t{err}");
+            err.errno()
+        })?;
+
+        let mut bands: BTreeSet<u64> = (0..count)
+            .filter(|index| {
+                let id = (ino, *index);
+                match self.band_location(id) {
+                    Some((key, _)) => key != self.generations.key(id) || stored.contains(&key),
+                    None => false,
+                }
+            })
+            .collect();
+        bands.extend(
+            self.cache
+                .dirty_bands(ino)
+                .into_iter()
+                .map(|id| id.1)
+                .filter(|index| *index < count),
+        );
+        Ok(bands)
+    }
+
+    // Drop the contents of a band, which reads as zeros from then on. A band
+    // a snapshot refers to moves to a new generation with nothing stored.
+    fn drop_band(&mut self, id: BandId) -> Result<(), i32> {
+        self.uploader.forget_bands(id.0, id.1..id.1 + 1);
+        self.cache.remove_bands(id.0, id.1..id.1 + 1);
+        let key = band_key(id, self.write_generation(id));
+        if let Err(err) = self.store.delete(&key) {
+            println!("\t{err}");
+            return Err(err.errno());
+        }
+        self.stored.remove(id.0, &key);
+        self.band_written(id);
+        Ok(())
+    }
+
+    // Zero a range within a file. Bands it covers up to the end of the file
+    // are dropped, others have zeros written over the range.
+    fn punch_hole(&mut self, ino: u64, start: u64, end: u64) -> Result<(), i32> {
+        let band_size = self.config.band_size;
+        let size = self.inode_map[&ino].size;
+        let mut pos = start;
+        while pos < end {
+            let band_start = pos - pos % band_size;
+            let hole_end = end.min(band_start + band_size);
+            if pos == band_start && hole_end >= size.min(band_start + band_size) {
+                self.drop_band((ino, pos / band_size))?;
+            } else {
+                self.write_data(ino, pos, &vec![0; (hole_end - pos) as usize])?;
+            }
+            pos = hole_end;
+        }
+        self.extend(ino, end);
+        Ok(())
+    }
+
+    // Record that a band was written. Bands of imported files are stored as
+    // band objects from then on.
+    fn band_written(&mut self, id: BandId) {
+        if let Some(source) = self.sources.get_mut(&id.0) {
+            if source.bands.insert(id.1) {
+                self.metadata_dirty = true;
+            }
+        }
+        let generation = self.write_generation(id);
+        if generation != self.generations.get(id) {
+            self.generations.set(id, generation);
+            self.metadata_dirty = true;
+        }
+        self.snapshot_pending = true;
+    }
+
+    // Generation a band is written to. Bands written since the last snapshot
+    // are written in place, others move to the current epoch.
+    fn write_generation(&self, id: BandId) -> u64 {
+        self.generations.get(id).max(self.epoch)
+    }
+
+    // Keys of the band objects of a range of bands which may be deleted once
+    // the bands are no longer part of their file. Objects a snapshot refers
+    // to are kept.
+    fn unreferenced_bands(&self, ino: u64, bands: Range<u64>) -> Vec<String> {
+        bands
+            .map(|index| (ino, index))
+            .filter(|id| self.generations.get(*id) == self.epoch)
+            .map(|id| self.generations.key(id))
+            .collect()
+    }
+
+    // Fail modifications of a read-only mount
+    fn check_writable(&self) -> Result<(), i32> {
+        if self.config.read_only {
+            println!("\tEROFS");
+            return Err(EROFS);
+        }
+        Ok(())
+    }
+
+    // Check that an inode exists and is a directory
+    fn check_dir(&self, ino: u64) -> Result<(), i32> {
+        match self.inode_map.get(&ino) {
+            Some(attr) if attr.kind == FileType::Directory => Ok(()),
+            Some(_) => Err(ENOTDIR),
+            None => Err(ENOENT),
+        }
+    }
+
+    // Size of a regular file, whose data may be read or written
+    fn file_size(&self, ino: u64) -> Result<u64, i32> {
+        match self.inode_map.get(&ino) {
+            Some(attr) if attr.kind == FileType::RegularFile => Ok(attr.size),
+            Some(attr) if attr.kind == FileType::Directory => {
+                println!("\tEISDIR");
+                Err(EISDIR)
+            }
+            Some(_) => {
+                println!("\tEINVAL");
+                Err(EINVAL)
+            }
+            None => {
+                println!("\tENOENT");
+                Err(ENOENT)
+            }
+        }
+    }
+
+    // Entries of a directory in name order
+    fn children(&self, parent: u64) -> impl Iterator<Item = (&String, u64)> {
+        self.name_map
+            .range((parent, String::new())..)
+            .take_while(move |((dir, _), _)| *dir == parent)
+            .map(|((_, name), ino)| (name, *ino))
+    }
+
+    // Directory holding an inode, the root is its own parent
+    fn parent_of(&self, ino: u64) -> u64 {
+        self.name_map
+            .iter()
+            .find(|(_, child)| **child == ino)
+            .map_or(FUSE_ROOT_ID, |((parent, _), _)| *parent)
+    }
+
+    // Add an entry to a directory, counting subdirectories as links to it
+    fn add_entry(&mut self, parent: u64, name: &str, attr: FileAttr) {
+        if attr.kind == FileType::Directory {
+            if let Some(dir) = self.inode_map.get_mut(&parent) {
+                dir.nlink += 1;
+            }
+        }
+        self.inode_map.insert(attr.ino, attr);
+        self.name_map.insert((parent, name.to_string()), attr.ino);
+    }
+
+    // Remove an entry from a directory. Everything kept about its inode is
+    // removed along with the last link to it.
+    fn remove_entry(&mut self, parent: u64, name: &str) -> Option<Detached> {
+        let ino = self.name_map.remove(&(parent, name.to_string()))?;
+        let attr = self.inode_map.get_mut(&ino)?;
+        if attr.kind != FileType::Directory && attr.nlink > 1 {
+            attr.nlink -= 1;
+            return Some(Detached {
+                attr: *attr,
+                last_link: false,
+                backup_time: None,
+                source: None,
+                xattrs: None,
+                symlink: None,
+            });
+        }
+
+        let attr = self.inode_map.remove(&ino)?;
+        if attr.kind == FileType::Directory {
+            if let Some(dir) = self.inode_map.get_mut(&parent) {
+                dir.nlink -= 1;
+            }
+        }
+        Some(Detached {
+            attr,
+            last_link: true,
+            backup_time: self.backup_times.remove(&ino),
+            source: self.sources.remove(&ino),
+            xattrs: self.xattrs.remove(&ino),
+            symlink: self.symlinks.remove(&ino),
+        })
+    }
+
+    // Undo remove_entry()
+    fn restore_entry(&mut self, parent: u64, name: &str, detached: Detached) {
+        let ino = detached.attr.ino;
+        if !detached.last_link {
+            self.name_map.insert((parent, name.to_string()), ino);
+            self.inode_map.get_mut(&ino).unwrap().nlink += 1;
+            return;
+        }
+
+        self.add_entry(parent, name, detached.attr);
+        if let Some(time) = detached.backup_time {
+            self.backup_times.insert(ino, time);
+        }
+        if let Some(source) = detached.source {
+            self.sources.insert(ino, source);
+        }
+        if let Some(xattrs) = detached.xattrs {
+            self.xattrs.insert(ino, xattrs);
+        }
+        if let Some(target) = detached.symlink {
+            self.symlinks.insert(ino, target);
+        }
+    }
+
+    // Inode of a directory entry
+    fn entry(&self, parent: u64, name: &str) -> Result<u64, i32> {
+        match self.name_map.get(&(parent, name.to_string())) {
+            Some(ino) => Ok(*ino),
+            None => {
+                println!("\tENOENT");
+                Err(ENOENT)
+            }
+        }
+    }
+
+    // Whether an inode is a directory containing another, or the same inode
+    fn contains(&self, ino: u64, mut other: u64) -> bool {
+        loop {
+            if other == ino {
+                return true;
+            }
+            if other == FUSE_ROOT_ID {
+                return false;
+            }
+            other = self.parent_of(other);
+        }
+    }
+
+    // Account for a subdirectory moving from one directory to another
+    fn move_link(&mut self, ino: u64, from: u64, to: u64) {
+        if from != to && self.inode_map[&ino].kind == FileType::Directory {
+            self.inode_map.get_mut(&from).unwrap().nlink -= 1;
+            self.inode_map.get_mut(&to).unwrap().nlink += 1;
+        }
+    }
+
+    // Move a directory entry to a name which is not in use
+    fn move_entry(&mut self, parent: u64, name: &str, newparent: u64, newname: &str) {
+        let ino = self.name_map.remove(&(parent, name.to_string())).unwrap();
+        self.name_map.insert((newparent, newname.to_string()), ino);
+        self.move_link(ino, parent, newparent);
+    }
+
+    // Swap the inodes of two directory entries
+    fn swap_entries(&mut self, parent: u64, name: &str, newparent: u64, newname: &str) {
+        let a = self.name_map[&(parent, name.to_string())];
+        let b = self.name_map[&(newparent, newname.to_string())];
+        self.name_map.insert((parent, name.to_string()), b);
+        self.name_map.insert((newparent, newname.to_string()), a);
+        self.move_link(a, parent, newparent);
+        self.move_link(b, newparent, parent);
+    }
+
+    // Rename an entry, replacing the target if it exists and `replace` is
+    // set. Only the metadata changes, as file contents are stored by inode.
+    fn rename_entry(
+        &mut self,
+        parent: u64,
+        name: &str,
+        newparent: u64,
+        newname: &str,
+        replace: bool,
+    ) -> Result<(), i32> {
+        let ino = self.entry(parent, name)?;
+        self.check_dir(newparent)?;
+        let is_dir = self.check_dir(ino).is_ok();
+        if is_dir && self.contains(ino, newparent) {
+            println!("\tEINVAL");
+            return Err(EINVAL);
+        }
+
+        let target = self
+            .name_map
+            .get(&(newparent, newname.to_string()))
+            .copied();
+        if let Some(target) = target {
+            if !replace {
+                println!("\tEEXIST");
+                return Err(EEXIST);
+            }
+            if target == ino {
+                return Ok(());
+            }
+            match (is_dir, self.check_dir(target).is_ok()) {
+                (true, false) => return Err(ENOTDIR),
+                (false, true) => return Err(EISDIR),
+                (true, true) if self.children(target).next().is_some() => return Err(ENOTEMPTY),
+                _ => (),
+            }
+        }
+
+        let replaced = target.and_then(|_| self.remove_entry(newparent, newname));
+        let dropped = replaced
+            .as_ref()
+            .filter(|detached| detached.last_link)
+            .map(|detached| (detached.attr, detached.source.clone()));
+        self.move_entry(parent, name, newparent, newname);
+        let ctime = self.touch_ctime(ino);
+        self.commit_or_undo(|fs| {
+            fs.move_entry(newparent, newname, parent, name);
+            fs.inode_map.get_mut(&ino).unwrap().ctime = ctime;
+            if let Some(detached) = replaced {
+                fs.restore_entry(newparent, newname, detached);
+            }
+        })?;
+
+        // The contents of a file replaced are dropped once its last link is
+        // gone from the stored tree
+        if let Some((attr, source)) = dropped {
+            if attr.kind == FileType::RegularFile {
+                self.unlinked(attr, source);
+            }
+        }
+        Ok(())
+    }
+
+    // Atomically swap two entries, which must both exist
+    fn exchange_entries(
+        &mut self,
+        parent: u64,
+        name: &str,
+        newparent: u64,
+        newname: &str,
+    ) -> Result<(), i32> {
+        let a = self.entry(parent, name)?;
+        let b = self.entry(newparent, newname)?;
+        if self.contains(a, newparent) || self.contains(b, parent) {
+            println!("\tEINVAL");
+            return Err(EINVAL);
+        }
+
+        self.swap_entries(parent, name, newparent, newname);
+        let ctimes = (self.touch_ctime(a), self.touch_ctime(b));
+        self.commit_or_undo(|fs| {
+            fs.swap_entries(parent, name, newparent, newname);
+            fs.inode_map.get_mut(&a).unwrap().ctime = ctimes.0;
+            fs.inode_map.get_mut(&b).unwrap().ctime = ctimes.1;
+        })
+    }
+
+    // Delete the stored contents of a file removed from the tree
+    fn drop_contents(&self, ino: u64, size: u64, source: Option<Source>) {
+        self.uploader.forget(ino);
+        self.cache.remove_inode(ino);
+        let bands = 0..size.div_ceil(self.config.band_size);
+        let mut keys = self.unreferenced_bands(ino, bands.clone());
+        self.generations.remove_bands(ino, bands);
+        // Imported objects predate any snapshot
+        if self.epoch == 0 {
+            keys.extend(source.map(|source| source.key));
+        }
+        for key in keys {
+            if let Err(err) = self.store.delete(&key) {
+                println!("\t{err}");
+            }
+        }
+        self.stored.forget(ino);
+    }
+
+    // Drop the contents of a file whose last link is gone from the stored
+    // tree, or keep the file for its open handles until the last is closed
+    fn unlinked(&mut self, mut attr: FileAttr, source: Option<Source>) {
+        let ino = attr.ino;
+        if !self.open_handles.contains_key(&ino) {
+            self.drop_contents(ino, attr.size, source);
+            return;
+        }
+        attr.nlink = 0;
+        self.inode_map.insert(ino, attr);
+        if let Some(source) = source {
+            self.sources.insert(ino, source);
+        }
+        self.orphans.insert(ino);
+    }
+
+    fn set_xattr(&mut self, ino: u64, name: &str, value: Vec<u8>) {
+        self.xattrs
+            .entry(ino)
+            .or_default()
+            .insert(name.to_string(), value);
+    }
+
+    // Set the status change time of an inode to now, returning the previous
+    // one
+    fn touch_ctime(&mut self, ino: u64) -> SystemTime {
+        let attr = self.inode_map.get_mut(&ino).unwrap();
+        std::mem::replace(&mut attr.ctime, SystemTime::now())
+    }
+
+    // Replace the in-memory tree with the one stored, or the one of the
+    // snapshot mounted. Returns false if nothing was stored yet.
+    fn load_metadata(&mut self) -> Result<bool, BackendError> {
+        let metadata = match &self.config.snapshot {
+            Some(id) => {
+                let manifest = Manifest::load(&*self.store, id)?;
+                self.check_objects(&manifest)?;
+                Some(manifest.metadata)
+            }
+            None => Metadata::load(&*self.store)?,
+        };
+        let Some(metadata) = metadata else {
+            return Ok(false);
+        };
+
+        self.next_inode = metadata.next_inode;
+        self.inode_map = metadata.inodes.into_iter().collect();
+        self.name_map = metadata
+            .names
+            .into_iter()
+            .map(|(parent, name, ino)| ((parent, name), ino))
+            .collect();
+        self.backup_times = metadata.backup_times.into_iter().collect();
+        self.sources = metadata.sources.into_iter().collect();
+        self.xattrs = metadata.xattrs.into_iter().collect();
+        self.symlinks = metadata.symlinks.into_iter().collect();
+        self.epoch = metadata.epoch;
+        self.generations.replace(metadata.generations);
+        Ok(true)
+    }
+
+    // Warn about objects of a snapshot which are missing or were replaced,
+    // whose files would read differently than when it was taken
+    fn check_objects(&self, manifest: &Manifest) -> Result<(), BackendError> {
+        let etags: HashMap<String, String> = self
+            .store
+            .list("")?
+            .into_iter()
+            .map(|object| (object.key, object.etag))
+            .collect();
+        for (key, etag) in &manifest.objects {
+            // The version recorded is read whatever the object holds now
+            if manifest.versions.contains_key(key) {
+                continue;
+            }
+            match etags.get(key) {
+                Some(current) if current == etag => (),
+                Some(_) => println!("\tWARNING: {key} changed since snapshot {}", manifest.id),
+                None => println!("\tWARNING: {key} of snapshot {} is missing", manifest.id),
+            }
+        }
+        Ok(())
+    }
+
+    // Build the tree from the objects in the store, so a bucket written by
+    // other tools can be mounted. Keys are split into directories at '/',
+    // keys ending with '/' are directory markers. The objects the filesystem
+    // stores itself are skipped.
+    fn import(&mut self) -> Result<(), BackendError> {
+        'objects: for object in self.store.list("")? {
+            if object.key == METADATA_KEY
+                || object.key == LEASE_KEY
+                || object.key.starts_with(BAND_PREFIX)
+                || object.key.starts_with(SNAPSHOT_PREFIX)
+            {
+                continue;
+            }
+
+            let mut components: Vec<&str> =
+                object.key.split('/').filter(|c| !c.is_empty()).collect();
+            let file = match object.key.ends_with('/') {
+                true => None,
+                false => components.pop(),
+            };
+
+            let mut parent = FUSE_ROOT_ID;
+            for name in components {
+                parent = match self.name_map.get(&(parent, name.to_string())) {
+                    Some(ino) if self.check_dir(*ino).is_ok() => *ino,
+                    Some(_) => {
+                        println!("\tskipping {}: not a directory", object.key);
+                        continue 'objects;
+                    }
+                    None => {
+                        let attr = imported_attr(
+                            self.next_inode,
+                            FileType::Directory,
+                            0,
+                            object.last_modified,
+                        );
+                        self.next_inode += 1;
+                        self.add_entry(parent, name, attr);
+                        attr.ino
+                    }
+                };
+            }
+
+            if let Some(name) = file {
+                if self.name_map.contains_key(&(parent, name.to_string())) {
+                    println!("\tskipping {}: already exists", object.key);
+                    continue;
+                }
+                let attr = imported_attr(
+                    self.next_inode,
+                    FileType::RegularFile,
+                    object.size,
+                    object.last_modified,
+                );
+                self.next_inode += 1;
+                self.add_entry(parent, name, attr);
+                self.sources.insert(
+                    attr.ino,
+                    Source {
+                        key: object.key.clone(),
+                        len: object.size,
+                        bands: BTreeSet::new(),
+                    },
+                );
+            }
+        }
+
+        // A read-only mount shows the tree without storing it
+        self.metadata_dirty = self.inode_map.len() > 1 && !self.config.read_only;
+        Ok(())
+    }
+
+    // Everything kept about the tree, as stored
+    fn metadata(&self) -> Metadata {
+        Metadata {
+            next_inode: self.next_inode,
+            inodes: self
+                .inode_map
+                .iter()
+                .filter(|(k, _)| !self.orphans.contains(k))
+                .map(|(k, v)| (*k, *v))
+                .collect(),
+            names: self
+                .name_map
+                .iter()
+                .map(|((parent, name), ino)| (*parent, name.clone(), *ino))
+                .collect(),
+            backup_times: self
+                .backup_times
+                .iter()
+                .filter(|(k, _)| !self.orphans.contains(k))
+                .map(|(k, v)| (*k, *v))
+                .collect(),
+            sources: self
+                .sources
+                .iter()
+                .filter(|(k, _)| !self.orphans.contains(k))
+                .map(|(k, v)| (*k, v.clone()))
+                .collect(),
+            xattrs: self
+                .xattrs
+                .iter()
+                .filter(|(k, _)| !self.orphans.contains(k))
+                .map(|(k, v)| (*k, v.clone()))
+                .collect(),
+            symlinks: self.symlinks.iter().map(|(k, v)| (*k, v.clone())).collect(),
+            epoch: self.epoch,
+            generations: self.generations.to_map(),
+            ..Metadata::default()
+        }
+    }
+
+    // Store the metadata. File contents are uploaded separately, so callers
+    // flush the bands of a file first when its size must be durable.
+    fn commit(&mut self) -> Result<(), i32> {
+        let metadata = self.metadata();
+        match metadata.save(&*self.store) {
+            Ok(()) => {
+                self.metadata_dirty = false;
+                Ok(())
+            }
+            Err(err) => {
+                println!("\tcommit failed: {err}");
+                self.metadata_dirty = true;
+                Err(err.errno())
+            }
+        }
+    }
+
+    // Store the metadata after a change, undoing the change if that fails
+    fn commit_or_undo(&mut self, undo: impl FnOnce(&mut S3TMFS)) -> Result<(), i32> {
+        let result = self.commit();
+        if result.is_err() {
+            undo(self);
+        }
+        result
+    }
+
+    // Upload every modified band and store the metadata
+    fn sync(&mut self) -> Result<(), i32> {
+        let inodes: Vec<u64> = self.inode_map.keys().copied().collect();
+        for ino in inodes {
+            self.uploader.flush(ino)?;
+        }
+        self.commit()
+    }
+
+    // Record the current state of the filesystem in a manifest, returning
+    // its id. Bands are written to a new generation from then on, so the
+    // objects the manifest refers to are never overwritten.
+    pub fn snapshot(&mut self) -> Result<String, i32> {
+        self.check_writable()?;
+        self.sync()?;
+
+        // The new epoch is stored before the manifest, so no band it refers
+        // to can be written in place even if storing the manifest fails
+        self.epoch += 1;
+        if let Err(err) = self.commit() {
+            self.epoch -= 1;
+            return Err(err);
+        }
+
+        let keys = self.referenced_objects();
+        let listing = self.store.list("").map_err(|err| {
+            println!("\t{err}");
+            err.errno()
+        })?;
+        let versions = self.object_versions(&keys);
+
+        let now = SystemTime::now();
+        let manifest = Manifest {
+            id: snapshot_id(now),
+            created: now,
+            metadata: self.metadata(),
+            objects: listing
+                .into_iter()
+                .filter(|object| keys.contains(&object.key))
+                .map(|object| (object.key, object.etag))
+                .collect(),
+            versions,
+        };
+        if let Err(err) = manifest.save(&*self.store) {
+            println!("\tsnapshot failed: {err}");
+            return Err(err.errno());
+        }
+
+        self.snapshot_pending = false;
+        println!(
+            "\tsnapshot {} objects={}",
+            manifest.id,
+            manifest.objects.len()
+        );
+        Ok(manifest.id)
+    }
+
+    // Objects holding the contents of every file
+    fn referenced_objects(&self) -> BTreeSet<String> {
+        let band_size = self.config.band_size;
+        let mut keys = BTreeSet::new();
+        for attr in self.inode_map.values() {
+            if attr.kind == FileType::RegularFile {
+                for index in 0..attr.size.div_ceil(band_size) {
+                    keys.extend(self.band_location((attr.ino, index)).map(|(key, _)| key));
+                }
+            }
+        }
+        keys
+    }
+
+    // Find the snapshots the retention policy no longer keeps, and the band
+    // objects neither the filesystem nor a snapshot kept refers to. Objects
+    // imported from the bucket are left alone.
+    pub fn collect_garbage(&self, retention: &Retention) -> Result<Garbage, BackendError> {
+        collect(
+            &*self.store,
+            self.dedup.as_deref(),
+            self.referenced_objects(),
+            self.epoch,
+            retention,
+        )
+    }
+
+    // Collect garbage and delete it in a background thread, unless the
+    // garbage found last time is still being deleted. Only finding the
+    // objects the filesystem refers to takes the filesystem.
+    fn start_gc(&mut self) {
+        let Some(retention) = &self.config.retention else {
+            return;
+        };
+        if self
+            .sweeper
+            .as_ref()
+            .is_some_and(|sweeper| !sweeper.is_finished())
+        {
+            return;
+        }
+
+        let retention = retention.clone();
+        let referenced = self.referenced_objects();
+        let epoch = self.epoch;
+        let store = self.store.clone();
+        let dedup = self.dedup.clone();
+        self.sweeper = Some(thread::spawn(move || {
+            let collected = collect(&*store, dedup.as_deref(), referenced, epoch, &retention);
+            let result = collected.and_then(|garbage| {
+                println!("\tgc {}", garbage.report());
+                garbage.sweep(&*store)
+            });
+            if let Err(err) = result {
+                println!("\tgc failed: {err}");
+            }
+            if let Some(dedup) = dedup {
+                dedup.end_marking();
+            }
+        }));
+    }
+
+    // Version ids of objects, if the bucket keeps versions. Those this mount
+    // didn't write are looked up by listing the versions in the bucket.
+    fn object_versions(&self, keys: &BTreeSet<String>) -> BTreeMap<String, String> {
+        let mut versions: BTreeMap<String, String> = keys
+            .iter()
+            .filter_map(|key| Some((key.clone(), self.store.version(key)?)))
+            .collect();
+        if versions.len() == keys.len() {
+            return versions;
+        }
+
+        match self.store.list_versions("") {
+            Ok(listing) => {
+                for version in listing {
+                    // Buckets without versioning list objects as version null
+                    if version.latest
+                        && !version.deleted
+                        && version.version_id != "null"
+                        && keys.contains(&version.key)
+                    {
+                        versions.entry(version.key).or_insert(version.version_id);
+                    }
+                }
+            }
+            Err(err) => println!("\tnot recording object versions: {err}"),
+        }
+        versions
+    }
+
+    // Discard the contents of a file past a new, smaller size
+    fn truncate(&mut self, ino: u64, old_size: u64, new_size: u64) -> Result<(), i32> {
+        let band_size = self.config.band_size;
+        let kept = new_size.div_ceil(band_size);
+
+        self.uploader.forget_bands(ino, kept..u64::MAX);
+        self.cache.remove_bands(ino, kept..u64::MAX);
+        for key in self.unreferenced_bands(ino, kept..old_size.div_ceil(band_size)) {
+            if let Err(err) = self.store.delete(&key) {
+                println!("\t{err}");
+                return Err(err.errno());
+            }
+            self.stored.remove(ino, &key);
+        }
+        self.generations.remove_bands(ino, kept..u64::MAX);
+        if let Some(source) = self.sources.get_mut(&ino) {
+            source.len = source.len.min(new_size);
+            source.bands.retain(|index| *index < kept);
+        }
+
+        // Cut the last band kept short, so the file reads as zeros past the
+        // new size if it grows again
+        let len = new_size % band_size;
+        if len != 0 {
+            let id = (ino, new_size / band_size);
+            if !self.cache.truncate(id, len as usize, None) {
+                let base = self.fetch_band(id, Some(0..len))?;
+                self.cache.truncate(id, len as usize, Some(base));
+            }
+            self.band_written(id);
+            self.uploader.schedule(id);
+        }
+        Ok(())
+    }
+}
+
+// Attributes of a file or directory imported from an object listing
+fn imported_attr(ino: u64, kind: FileType, size: u64, time: SystemTime) -> FileAttr {
+    let (perm, nlink) = match kind {
+        FileType::Directory => (0o755, 2),
+        _ => (0o644, 1),
+    };
+    FileAttr {
+        ino,
+        size,
+        blocks: size.div_ceil(512),
+        atime: time,
+        mtime: time,
+        ctime: time,
+        crtime: time,
+        kind,
+        perm,
+        nlink,
+        uid: ROOT_DIR_ATTR.uid,
+        gid: ROOT_DIR_ATTR.gid,
+        rdev: 0,
+        flags: 0,
+        blksize: 512,
+    }
+}
+
+// Resolve a time given to setattr
+fn resolve_time(time: TimeOrNow, now: SystemTime) -> SystemTime {
+    match time {
+        TimeOrNow::SpecificTime(time) => time,
+        TimeOrNow::Now => now,
+    }
+}
+
+// Reply to a request for an attribute value or name list: only the size when
+// probed with a size of 0, ERANGE if the caller's buffer is too small
+fn xattr_reply(data: Vec<u8>, size: u32) -> Result<ReplyXattr, i32> {
+    if size == 0 {
+        println!("\tok size={}", data.len());
+        Ok(ReplyXattr::Size(data.len() as u32))
+    } else if (size as usize) < data.len() {
+        println!("\tERANGE");
+        Err(ERANGE)
+    } else {
+        println!("\tok size={}", data.len());
+        Ok(ReplyXattr::Data(data))
+    }
+}
+
+// A name given by the kernel as a string. Names are kept as strings in the
+// stored metadata and as object keys, so others are rejected.
+fn utf8_name(name: &std::ffi::OsStr) -> Result<&str, i32> {
+    name.to_str().ok_or_else(|| {
+        println!("\tEINVAL: name is not UTF-8");
+        EINVAL
+    })
+}
+
+// Log an operation the filesystem does not implement and return the errno to
+// reply with, so the kernel gets an error rather than a dead mount
+fn unsupported(op: &str, errno: i32) -> i32 {
+    println!("\tWARNING: {op} is not supported");
+    errno
 }
 
 impl WrappedFilesystem for S3TMFS {
     fn fuse_init(&mut self) -> Result<(), libc::c_int> {
         println!(">>> init");
-        Ok(())
+
+        // Without stored metadata the tree is built from the objects found
+        let result = match self.load_metadata() {
+            Ok(true) => Ok(()),
+            Ok(false) => self.import(),
+            Err(err) => Err(err),
+        };
+
+        match result {
+            Ok(()) => {
+                println!("\tok inodes={}", self.inode_map.len());
+                Ok(())
+            }
+            Err(err) => {
+                println!("\tloading metadata failed: {err}");
+                Err(err.errno())
+            }
+        }
     }
 
-    fn fuse_getattr(&mut self, ino: u64) -> Result<ReplyAttr, i32> {
+    fn fuse_getattr(&mut self, ino: u64) -> Result<ReplyAttr<'_>, i32> {
         println!(">>> getattr ino={ino}");
 
         match self.inode_map.get(&ino) {
@@ -84,17 +1282,22 @@ impl WrappedFilesystem for S3TMFS {
         }
     }
 
-    fn fuse_lookup(&mut self, parent: u64, name: &std::ffi::OsStr) -> Result<ReplyEntry, i32> {
-        let name_str = name.to_str().unwrap();
+    fn fuse_lookup(&mut self, parent: u64, name: &std::ffi::OsStr) -> Result<ReplyEntry<'_>, i32> {
+        let name_str = utf8_name(name)?;
         println!(">>> lookup parent={parent} name={}", name_str);
 
-        match self.name_map.get(name_str) {
-            Some(ino) => {
-                println!("\tok ino={ino}");
-                let attr = self.inode_map.get(ino);
+        let ino = match name_str {
+            "." => Some(parent),
+            ".." => Some(self.parent_of(parent)),
+            _ => self.name_map.get(&(parent, name_str.to_string())).copied(),
+        };
+
+        match ino.and_then(|ino| self.inode_map.get(&ino)) {
+            Some(attr) => {
+                println!("\tok ino={}", attr.ino);
                 Ok(ReplyEntry {
                     ttl: &TTL,
-                    attr: attr.unwrap(),
+                    attr,
                     generation: 1,
                 })
             }
@@ -113,8 +1316,15 @@ impl WrappedFilesystem for S3TMFS {
         _umask: u32,
         _flags: i32,
     ) -> Result<ReplyCreate, i32> {
-        let name_str = name.to_str().unwrap();
+        let name_str = utf8_name(name)?;
         println!(">>> create parent={parent}, name={}", name_str);
+        self.check_writable()?;
+
+        self.check_dir(parent)?;
+        if self.name_map.contains_key(&(parent, name_str.to_string())) {
+            println!("\tEEXIST");
+            return Err(EEXIST);
+        }
 
         let attrs: FileAttr = FileAttr {
             ino: self.next_inode,
@@ -134,16 +1344,22 @@ impl WrappedFilesystem for S3TMFS {
             blksize: 512,
         };
 
-        self.inode_map.insert(attrs.ino, attrs);
-        self.name_map.insert(name_str.to_string(), attrs.ino);
+        self.add_entry(parent, name_str, attrs);
+        self.next_inode += 1;
+        self.commit_or_undo(|fs| {
+            fs.remove_entry(parent, name_str);
+        })?;
 
-        self.next_inode = self.next_inode + 1;
+        let fh = self.next_fh;
+        self.next_fh += 1;
+        self.open_files += 1;
+        *self.open_handles.entry(attrs.ino).or_default() += 1;
 
         Ok(ReplyCreate {
-            ttl: TTL.clone(),
+            ttl: TTL,
             attr: attrs,
             generation: 0,
-            fh: 1,
+            fh,
             flags: 1,
         })
     }
@@ -160,56 +1376,187 @@ impl WrappedFilesystem for S3TMFS {
         }
     }
 
-    fn fuse_bmap(&mut self, _ino: u64, _blocksize: u32, _idx: u64) -> Result<ReplyBmap, i32> {
-        panic!();
+    fn fuse_bmap(&mut self, ino: u64, _blocksize: u32, _idx: u64) -> Result<ReplyBmap, i32> {
+        println!(">>> bmap ino={ino}");
+        Err(unsupported("bmap", ENOSYS))
     }
 
     fn fuse_copy_file_range(
         &mut self,
-        _ino_in: u64,
-        _fh_in: u64,
-        _offset_in: i64,
-        _ino_out: u64,
-        _fh_out: u64,
-        _offset_out: i64,
-        _len: u64,
-        _flags: u32,
+        ino_in: u64,
+        fh_in: u64,
+        offset_in: i64,
+        ino_out: u64,
+        fh_out: u64,
+        offset_out: i64,
+        len: u64,
+        flags: u32,
     ) -> Result<ReplyWrite, i32> {
-        panic!();
+        println!(
+            ">>> copy_file_range ino_in={ino_in}, fh_in={fh_in}, offset_in={offset_in}, ino_out={ino_out}, fh_out={fh_out}, offset_out={offset_out}, len={len}"
+        );
+        self.check_writable()?;
+
+        let in_size = match (self.inode_map.get(&ino_in), self.inode_map.get(&ino_out)) {
+            (Some(a), Some(b))
+                if a.kind == FileType::Directory || b.kind == FileType::Directory =>
+            {
+                println!("\tEISDIR");
+                return Err(EISDIR);
+            }
+            (Some(attr), Some(_)) => attr.size,
+            _ => {
+                println!("\tENOENT");
+                return Err(ENOENT);
+            }
+        };
+
+        if offset_in < 0 || offset_out < 0 || flags != 0 {
+            println!("\tEINVAL");
+            return Err(EINVAL);
+        }
+        let start_in = offset_in as u64;
+        let start_out = offset_out as u64;
+        let len = len
+            .min(in_size.saturating_sub(start_in))
+            .min(u32::MAX as u64);
+        let (Some(end_in), Some(end_out)) = (start_in.checked_add(len), start_out.checked_add(len))
+        else {
+            println!("\tEINVAL");
+            return Err(EINVAL);
+        };
+        if ino_in == ino_out && start_in < end_out && start_out < end_in {
+            println!("\tEINVAL");
+            return Err(EINVAL);
+        }
+
+        self.uploader.throttle(ino_out)?;
+
+        // Bands lined up in both files are copied inside the store, so their
+        // stored contents must be up to date
+        let band_size = self.config.band_size;
+        let aligned = start_in.is_multiple_of(band_size) && start_out.is_multiple_of(band_size);
+        if aligned {
+            self.uploader.flush(ino_in)?;
+        }
+
+        let mut done = 0;
+        while done < len {
+            let pos_in = start_in + done;
+            let pos_out = start_out + done;
+            let chunk = (band_size - pos_in % band_size).min(len - done);
+
+            // A band is copied whole if it replaces everything the target
+            // band holds
+            let out_size = self.inode_map[&ino_out].size;
+            let whole =
+                chunk == band_size || (pos_in + chunk == in_size && pos_out + chunk >= out_size);
+            if aligned && whole {
+                self.copy_band((ino_in, pos_in / band_size), (ino_out, pos_out / band_size))?;
+                self.extend(ino_out, pos_out + chunk);
+            } else {
+                let data = self.read_data(ino_in, pos_in, pos_in + chunk, false)?;
+                self.write_data(ino_out, pos_out, &data)?;
+            }
+            done += chunk;
+        }
+
+        println!("\tok len={len}");
+        Ok(ReplyWrite { size: len as u32 })
     }
 
     fn fuse_destroy(&mut self) {
         println!(">>> destroy");
+
+        self.prefetcher.wait_idle();
+        for ino in self.inode_map.keys() {
+            if let Err(err) = self.uploader.flush(*ino) {
+                println!("\tflush ino={ino} failed errno={err}");
+            }
+        }
+        if self.metadata_dirty {
+            let _ = self.commit();
+        }
+        if self.snapshot_pending && self.config.auto_snapshot {
+            if let Err(err) = self.snapshot() {
+                println!("\tsnapshot failed errno={err}");
+            }
+        }
+        if let Some(sweeper) = self.sweeper.take() {
+            sweeper.join().unwrap();
+        }
     }
 
     #[cfg(feature = "macos")]
     fn fuse_exchange(
         &mut self,
-        _parent: u64,
-        _name: &std::ffi::OsStr,
-        _newparent: u64,
-        _newname: &std::ffi::OsStr,
+        parent: u64,
+        name: &std::ffi::OsStr,
+        newparent: u64,
+        newname: &std::ffi::OsStr,
         _options: u64,
     ) -> Result<(), i32> {
-        panic!();
+        let name_str = utf8_name(name)?;
+        let newname_str = utf8_name(newname)?;
+        println!(
+            ">>> exchange parent={parent}, name={name_str}, newparent={newparent}, newname={newname_str}"
+        );
+        self.check_writable()?;
+
+        self.exchange_entries(parent, name_str, newparent, newname_str)?;
+        println!("\tok");
+        Ok(())
     }
 
     fn fuse_fallocate(
         &mut self,
-        _ino: u64,
+        ino: u64,
         _fh: u64,
-        _offset: i64,
-        _length: i64,
-        _mode: i32,
+        offset: i64,
+        length: i64,
+        mode: i32,
     ) -> Result<(), i32> {
-        panic!();
+        println!(">>> fallocate ino={ino}, offset={offset}, length={length}, mode={mode}");
+        self.check_writable()?;
+
+        let Some(attr) = self.inode_map.get(&ino) else {
+            println!("\tENOENT");
+            return Err(ENOENT);
+        };
+        if offset < 0 || length <= 0 {
+            println!("\tEINVAL");
+            return Err(EINVAL);
+        }
+        let size = attr.size;
+        let start = offset as u64;
+        let end = start.saturating_add(length as u64);
+
+        // Space is never reserved, ranges without data read as zeros
+        match mode {
+            0 if end > size => self.extend(ino, end),
+            0 | FALLOC_FL_KEEP_SIZE => (),
+            _ if mode == FALLOC_FL_PUNCH_HOLE | FALLOC_FL_KEEP_SIZE => {
+                self.punch_hole(ino, start, end.min(size))?
+            }
+            _ => return Err(unsupported("fallocate", EOPNOTSUPP)),
+        }
+
+        println!("\tok");
+        Ok(())
     }
 
-    fn fuse_flush(&mut self, ino: u64, fh: u64, _lock_owner: u64) -> Result<(), i32> {
+    fn fuse_flush(&mut self, ino: u64, fh: u64, lock_owner: u64) -> Result<(), i32> {
         println!(">>> flush ino={ino} fh={fh}");
 
+        // POSIX locks are dropped when any descriptor of their owner is closed
+        self.locks.release(ino, lock_owner);
+
         match self.inode_map.get(&ino) {
             Some(_) => {
+                self.uploader.flush(ino)?;
+                if self.metadata_dirty {
+                    self.commit()?;
+                }
                 println!("\tok");
                 Ok(())
             }
@@ -224,115 +1571,337 @@ impl WrappedFilesystem for S3TMFS {
         println!(">>> forget ino={ino}");
     }
 
-    fn fuse_fsync(&mut self, _ino: u64, _fh: u64, _datasync: bool) -> Result<(), i32> {
-        panic!();
+    fn fuse_fsync(&mut self, ino: u64, fh: u64, _datasync: bool) -> Result<(), i32> {
+        println!(">>> fsync ino={ino} fh={fh}");
+
+        match self.inode_map.get(&ino) {
+            Some(_) => {
+                self.uploader.flush(ino)?;
+                if self.metadata_dirty {
+                    self.commit()?;
+                }
+                println!("\tok");
+                Ok(())
+            }
+            _ => {
+                println!("\tENOENT");
+                Err(ENOENT)
+            }
+        }
     }
 
-    fn fuse_fsyncdir(&mut self, _ino: u64, _fh: u64, _datasync: bool) -> Result<(), i32> {
-        panic!();
+    fn fuse_fsyncdir(&mut self, ino: u64, _fh: u64, _datasync: bool) -> Result<(), i32> {
+        println!(">>> fsyncdir ino={ino}");
+        Err(unsupported("fsyncdir", ENOSYS))
     }
 
     fn fuse_getlk(
         &mut self,
-        _ino: u64,
+        ino: u64,
         _fh: u64,
-        _lock_owner: u64,
-        _start: u64,
-        _end: u64,
-        _typ: i32,
-        _pid: u32,
+        lock_owner: u64,
+        start: u64,
+        end: u64,
+        typ: i32,
+        pid: u32,
     ) -> Result<ReplyLock, i32> {
-        panic!();
+        println!(">>> getlk ino={ino}, owner={lock_owner}, start={start}, end={end}, typ={typ}");
+
+        if !self.inode_map.contains_key(&ino) {
+            println!("\tENOENT");
+            return Err(ENOENT);
+        }
+        if typ != F_RDLCK && typ != F_WRLCK {
+            println!("\tEINVAL");
+            return Err(EINVAL);
+        }
+
+        let lock = Lock {
+            owner: lock_owner,
+            start,
+            end,
+            typ,
+            pid,
+        };
+        // The lock which would block the one asked for, or F_UNLCK if none
+        let reply = match self.locks.conflict(ino, &lock) {
+            Some(held) => ReplyLock {
+                start: held.start,
+                end: held.end,
+                typ: held.typ,
+                pid: held.pid,
+            },
+            None => ReplyLock {
+                start,
+                end,
+                typ: F_UNLCK,
+                pid: 0,
+            },
+        };
+
+        println!("\tok typ={} pid={}", reply.typ, reply.pid);
+        Ok(reply)
     }
 
     fn fuse_getxattr(
         &mut self,
         ino: u64,
         name: &std::ffi::OsStr,
-        _size: u32,
+        size: u32,
     ) -> Result<ReplyXattr, i32> {
-        println!(">>> getxattr ino={ino}, name={}", name.to_str().unwrap());
+        let name_str = utf8_name(name)?;
+        println!(">>> getxattr ino={ino}, name={name_str}, size={size}");
+
+        if !self.inode_map.contains_key(&ino) {
+            println!("\tENOENT");
+            return Err(ENOENT);
+        }
+        match self
+            .xattrs
+            .get(&ino)
+            .and_then(|xattrs| xattrs.get(name_str))
+        {
+            Some(value) => xattr_reply(value.clone(), size),
+            None => {
+                println!("\tENOATTR");
+                Err(ENOATTR)
+            }
+        }
+    }
+
+    #[cfg(feature = "macos")]
+    fn fuse_getxtimes(&mut self, ino: u64) -> Result<ReplyXTimes, i32> {
+        println!(">>> getxtimes ino={ino}");
 
         match self.inode_map.get(&ino) {
-            Some(_) => {
+            Some(attr) => {
                 println!("\tok");
-                Ok(ReplyXattr { size: 0 })
+                Ok(ReplyXTimes {
+                    bkuptime: self.backup_times.get(&ino).copied().unwrap_or(UNIX_EPOCH),
+                    crtime: attr.crtime,
+                })
             }
-            _ => {
+            None => {
                 println!("\tENOENT");
                 Err(ENOENT)
             }
         }
     }
 
-    #[cfg(feature = "macos")]
-    fn fuse_getxtimes(&mut self, _ino: u64) -> Result<ReplyXTimes, i32> {
-        panic!();
-    }
-
     fn fuse_ioctl(
         &mut self,
-        _ino: u64,
+        ino: u64,
         _fh: u64,
         _flags: u32,
-        _cmd: u32,
+        cmd: u32,
         _in_data: &[u8],
         _out_size: u32,
-    ) -> Result<ReplyIoctl, i32> {
-        panic!();
+    ) -> Result<ReplyIoctl<'_>, i32> {
+        println!(">>> ioctl ino={ino}, cmd={cmd}");
+        Err(unsupported("ioctl", ENOSYS))
     }
 
     fn fuse_link(
         &mut self,
-        _ino: u64,
-        _newparent: u64,
-        _newname: &std::ffi::OsStr,
-    ) -> Result<ReplyEntry, i32> {
-        panic!();
+        ino: u64,
+        newparent: u64,
+        newname: &std::ffi::OsStr,
+    ) -> Result<ReplyEntry<'_>, i32> {
+        let newname_str = utf8_name(newname)?;
+        println!(">>> link ino={ino}, newparent={newparent}, newname={newname_str}");
+        self.check_writable()?;
+
+        match self.inode_map.get(&ino) {
+            Some(attr) if attr.kind == FileType::Directory => {
+                println!("\tEPERM");
+                return Err(EPERM);
+            }
+            Some(_) => (),
+            None => {
+                println!("\tENOENT");
+                return Err(ENOENT);
+            }
+        }
+        self.check_dir(newparent)?;
+        if self
+            .name_map
+            .contains_key(&(newparent, newname_str.to_string()))
+        {
+            println!("\tEEXIST");
+            return Err(EEXIST);
+        }
+
+        self.name_map
+            .insert((newparent, newname_str.to_string()), ino);
+        self.inode_map.get_mut(&ino).unwrap().nlink += 1;
+        let ctime = self.touch_ctime(ino);
+        self.commit_or_undo(|fs| {
+            fs.name_map.remove(&(newparent, newname_str.to_string()));
+            let attr = fs.inode_map.get_mut(&ino).unwrap();
+            attr.nlink -= 1;
+            attr.ctime = ctime;
+        })?;
+
+        println!("\tok nlink={}", self.inode_map[&ino].nlink);
+        Ok(ReplyEntry {
+            ttl: &TTL,
+            attr: &self.inode_map[&ino],
+            generation: 1,
+        })
     }
 
-    fn fuse_listxattr(&mut self, _ino: u64, _sizee: u32) -> Result<ReplyXattr, i32> {
-        panic!();
+    fn fuse_listxattr(&mut self, ino: u64, size: u32) -> Result<ReplyXattr, i32> {
+        println!(">>> listxattr ino={ino}, size={size}");
+
+        if !self.inode_map.contains_key(&ino) {
+            println!("\tENOENT");
+            return Err(ENOENT);
+        }
+
+        // Names are listed one after the other, each terminated by a NUL
+        let mut names = Vec::new();
+        for name in self.xattrs.get(&ino).into_iter().flat_map(|x| x.keys()) {
+            names.extend_from_slice(name.as_bytes());
+            names.push(0);
+        }
+        xattr_reply(names, size)
     }
 
     fn fuse_lseek(
         &mut self,
-        _ino: u64,
+        ino: u64,
         _fh: u64,
-        _offset: i64,
-        _whence: i32,
+        offset: i64,
+        whence: i32,
     ) -> Result<ReplyLseek, i32> {
-        panic!();
+        println!(">>> lseek ino={ino}, offset={offset}, whence={whence}");
+
+        let Some(attr) = self.inode_map.get(&ino) else {
+            println!("\tENOENT");
+            return Err(ENOENT);
+        };
+        let size = attr.size;
+        if whence == SEEK_SET || whence == SEEK_END {
+            let base = if whence == SEEK_END { size as i64 } else { 0 };
+            return match base.checked_add(offset) {
+                Some(offset) if offset >= 0 => {
+                    println!("\tok offset={offset}");
+                    Ok(ReplyLseek { offset })
+                }
+                _ => {
+                    println!("\tEINVAL");
+                    Err(EINVAL)
+                }
+            };
+        }
+        if whence != SEEK_DATA && whence != SEEK_HOLE {
+            println!("\tEINVAL");
+            return Err(EINVAL);
+        }
+        if offset < 0 || offset as u64 >= size {
+            println!("\tENXIO");
+            return Err(ENXIO);
+        }
+
+        // Data is found a band at a time, so holes within a band stored are
+        // taken for data. The end of the file counts as a hole.
+        let band_size = self.config.band_size;
+        let offset = offset as u64;
+        let data = self.data_bands(ino)?;
+        let first = offset / band_size;
+        let found = match whence {
+            SEEK_DATA => data.range(first..).next().map(|index| index * band_size),
+            _ => Some((first..).find(|index| !data.contains(index)).unwrap() * band_size),
+        };
+        match found {
+            Some(found) => {
+                let offset = found.clamp(offset, size) as i64;
+                println!("\tok offset={offset}");
+                Ok(ReplyLseek { offset })
+            }
+            None => {
+                println!("\tENXIO");
+                Err(ENXIO)
+            }
+        }
     }
 
     fn fuse_mkdir(
         &mut self,
-        _parent: u64,
-        _name: &std::ffi::OsStr,
+        parent: u64,
+        name: &std::ffi::OsStr,
         _mode: u32,
         _umask: u32,
-    ) -> Result<ReplyEntry, i32> {
-        panic!();
+    ) -> Result<ReplyEntry<'_>, i32> {
+        let name_str = utf8_name(name)?;
+        println!(">>> mkdir parent={parent}, name={}", name_str);
+        self.check_writable()?;
+
+        self.check_dir(parent)?;
+        if self.name_map.contains_key(&(parent, name_str.to_string())) {
+            println!("\tEEXIST");
+            return Err(EEXIST);
+        }
+
+        let now = SystemTime::now();
+        let attr = FileAttr {
+            ino: self.next_inode,
+            atime: now,
+            mtime: now,
+            ctime: now,
+            crtime: now,
+            ..ROOT_DIR_ATTR
+        };
+        self.add_entry(parent, name_str, attr);
+        self.next_inode += 1;
+        self.commit_or_undo(|fs| {
+            fs.remove_entry(parent, name_str);
+        })?;
+
+        println!("\tok ino={}", attr.ino);
+        Ok(ReplyEntry {
+            ttl: &TTL,
+            attr: &self.inode_map[&attr.ino],
+            generation: 1,
+        })
     }
 
     fn fuse_mknod(
         &mut self,
-        _parent: u64,
+        parent: u64,
         _name: &std::ffi::OsStr,
         _mode: u32,
         _umask: u32,
         _rdev: u32,
-    ) -> Result<ReplyEntry, i32> {
-        panic!();
+    ) -> Result<ReplyEntry<'_>, i32> {
+        println!(">>> mknod parent={parent}");
+        self.check_writable()?;
+        Err(unsupported("mknod", EOPNOTSUPP))
     }
 
     fn fuse_open(&mut self, ino: u64, flags: i32) -> Result<ReplyOpen, i32> {
-        println!(">>> TODO: open ino={ino}, flags={flags}");
-        Ok(ReplyOpen { fh: 1, flags: 0 })
+        println!(">>> open ino={ino}, flags={flags}");
+
+        if flags & libc::O_ACCMODE != libc::O_RDONLY || flags & libc::O_TRUNC != 0 {
+            self.check_writable()?;
+        }
+        let fh = self.next_fh;
+        self.next_fh += 1;
+        self.open_files += 1;
+        *self.open_handles.entry(ino).or_default() += 1;
+
+        Ok(ReplyOpen { fh, flags: 0 })
     }
 
-    fn fuse_opendir(&mut self, _ino: u64, _flags: i32) -> Result<ReplyOpen, i32> {
-        panic!();
+    fn fuse_opendir(&mut self, ino: u64, _flags: i32) -> Result<ReplyOpen, i32> {
+        println!(">>> opendir ino={ino}");
+
+        self.check_dir(ino)?;
+        let fh = self.next_fh;
+        self.next_fh += 1;
+
+        println!("\tok fh={fh}");
+        Ok(ReplyOpen { fh, flags: 0 })
     }
 
     fn fuse_read(
@@ -343,26 +1912,93 @@ impl WrappedFilesystem for S3TMFS {
         size: u32,
         _flags: i32,
         _lock_owner: Option<u64>,
-    ) -> Result<ReplyData, i32> {
-        println!(">>> TODO: read ino={ino}, fh={fh}, offset={offset}, size={size}");
-        Ok(ReplyData { data: &[] })
+    ) -> Result<ReplyData<'_>, i32> {
+        println!(">>> read ino={ino}, fh={fh}, offset={offset}, size={size}");
+
+        let file_size = self.file_size(ino)?;
+
+        let band_size = self.config.band_size;
+        let start = offset as u64;
+        let end = (start + size as u64).min(file_size).max(start);
+        let sequential = self.prefetcher.observe(fh, start, end - start);
+        let buf = self.read_data(ino, start, end, sequential)?;
+
+        // Fetch the bands following this read in the background
+        if sequential && end > start {
+            let next = (end - 1) / band_size + 1;
+            let last = file_size.div_ceil(band_size);
+            for index in next..(next + self.config.prefetch_depth).min(last) {
+                let id = (ino, index);
+                if let Some((key, range)) = self.band_location(id) {
+                    self.prefetcher.fetch(id, key, range);
+                }
+            }
+        }
+
+        println!("\tok len={}", buf.len());
+        self.read_buf = buf;
+        Ok(ReplyData {
+            data: &self.read_buf,
+        })
     }
 
-    fn fuse_readdir(&mut self, _ino: u64, _fh: u64, _offset: i64) -> Result<ReplyDirectory, i32> {
-        panic!();
+    fn fuse_readdir(&mut self, ino: u64, fh: u64, offset: i64) -> Result<ReplyDirectory, i32> {
+        println!(">>> readdir ino={ino}, fh={fh}, offset={offset}");
+
+        self.check_dir(ino)?;
+        let dots = [
+            (".".to_string(), ino),
+            ("..".to_string(), self.parent_of(ino)),
+        ];
+        let children = self.children(ino).map(|(name, ino)| (name.clone(), ino));
+
+        // Offsets are positions in the listing
+        let entries: Vec<DirectoryEntry> = dots
+            .into_iter()
+            .chain(children)
+            .enumerate()
+            .skip(offset.max(0) as usize)
+            .map(|(index, (name, ino))| DirectoryEntry {
+                ino,
+                offset: index as i64 + 1,
+                kind: self.inode_map[&ino].kind,
+                name: name.into(),
+            })
+            .collect();
+
+        println!("\tok entries={}", entries.len());
+        Ok(ReplyDirectory { entries })
     }
 
     fn fuse_readdirplus(
         &mut self,
-        _ino: u64,
+        ino: u64,
         _fh: u64,
-        _offset: i64,
+        offset: i64,
     ) -> Result<ReplyDirectoryPlus, i32> {
-        panic!();
+        println!(">>> readdirplus ino={ino}, offset={offset}");
+        Err(unsupported("readdirplus", ENOSYS))
     }
 
-    fn fuse_readlink(&mut self, _ino: u64) -> Result<ReplyData, i32> {
-        panic!();
+    fn fuse_readlink(&mut self, ino: u64) -> Result<ReplyData<'_>, i32> {
+        println!(">>> readlink ino={ino}");
+
+        if !self.inode_map.contains_key(&ino) {
+            println!("\tENOENT");
+            return Err(ENOENT);
+        }
+        match self.symlinks.get(&ino) {
+            Some(target) => {
+                println!("\tok target={target}");
+                Ok(ReplyData {
+                    data: target.as_bytes(),
+                })
+            }
+            None => {
+                println!("\tEINVAL");
+                Err(EINVAL)
+            }
+        }
     }
 
     fn fuse_release(
@@ -370,13 +2006,49 @@ impl WrappedFilesystem for S3TMFS {
         ino: u64,
         fh: u64,
         _flags: i32,
-        _lock_owner: Option<u64>,
+        lock_owner: Option<u64>,
         _flush: bool,
     ) -> Result<(), i32> {
         println!(">>> release ino={ino}, fh={fh}");
 
+        self.prefetcher.forget(fh);
+        if let Some(owner) = lock_owner {
+            self.locks.release(ino, owner);
+        }
+
+        // Time Machine closes every file of the image when detaching it at
+        // the end of a backup. Only changes to file contents count, not
+        // metadata commits.
+        self.open_files = self.open_files.saturating_sub(1);
+        if self.open_files == 0 && self.snapshot_pending && self.config.auto_snapshot {
+            match self.snapshot() {
+                Ok(_) => self.start_gc(),
+                Err(err) => println!("\tsnapshot failed errno={err}"),
+            }
+        }
+
+        if let Some(handles) = self.open_handles.get_mut(&ino) {
+            *handles -= 1;
+            if *handles == 0 {
+                self.open_handles.remove(&ino);
+            }
+        }
+        if !self.open_handles.contains_key(&ino) && self.orphans.remove(&ino) {
+            let attr = self.inode_map.remove(&ino).unwrap();
+            let source = self.sources.remove(&ino);
+            self.backup_times.remove(&ino);
+            self.xattrs.remove(&ino);
+            self.drop_contents(ino, attr.size, source);
+            println!("\tok dropped ino={ino}");
+            return Ok(());
+        }
+
         match self.inode_map.get(&ino) {
             Some(_) => {
+                // Upload in the background, errors are reported by flush
+                for id in self.cache.dirty_bands(ino) {
+                    self.uploader.schedule(id);
+                }
                 println!("\tok");
                 Ok(())
             }
@@ -387,27 +2059,89 @@ impl WrappedFilesystem for S3TMFS {
         }
     }
 
-    fn fuse_releasedir(&mut self, _ino: u64, _fh: u64, _flags: i32) -> Result<(), i32> {
-        panic!();
+    fn fuse_releasedir(&mut self, ino: u64, fh: u64, _flags: i32) -> Result<(), i32> {
+        println!(">>> releasedir ino={ino}, fh={fh}");
+        Ok(())
     }
 
-    fn fuse_removexattr(&mut self, _ino: u64, _name: &std::ffi::OsStr) -> Result<(), i32> {
-        panic!();
+    fn fuse_removexattr(&mut self, ino: u64, name: &std::ffi::OsStr) -> Result<(), i32> {
+        let name_str = utf8_name(name)?;
+        println!(">>> removexattr ino={ino}, name={name_str}");
+        self.check_writable()?;
+
+        if !self.inode_map.contains_key(&ino) {
+            println!("\tENOENT");
+            return Err(ENOENT);
+        }
+        let Some(value) = self.xattrs.get_mut(&ino).and_then(|x| x.remove(name_str)) else {
+            println!("\tENOATTR");
+            return Err(ENOATTR);
+        };
+
+        let ctime = self.touch_ctime(ino);
+        self.commit_or_undo(|fs| {
+            fs.set_xattr(ino, name_str, value);
+            fs.inode_map.get_mut(&ino).unwrap().ctime = ctime;
+        })?;
+
+        println!("\tok");
+        Ok(())
     }
 
     fn fuse_rename(
         &mut self,
-        _parent: u64,
-        _name: &std::ffi::OsStr,
-        _newparent: u64,
-        _newname: &std::ffi::OsStr,
-        _flags: u32,
+        parent: u64,
+        name: &std::ffi::OsStr,
+        newparent: u64,
+        newname: &std::ffi::OsStr,
+        flags: u32,
     ) -> Result<(), i32> {
-        panic!();
+        let name_str = utf8_name(name)?;
+        let newname_str = utf8_name(newname)?;
+        println!(
+            ">>> rename parent={parent}, name={name_str}, newparent={newparent}, newname={newname_str}, flags={flags}"
+        );
+        self.check_writable()?;
+
+        match flags {
+            0 => self.rename_entry(parent, name_str, newparent, newname_str, true)?,
+            RENAME_NOREPLACE => {
+                self.rename_entry(parent, name_str, newparent, newname_str, false)?
+            }
+            RENAME_EXCHANGE => self.exchange_entries(parent, name_str, newparent, newname_str)?,
+            _ => {
+                println!("\tEINVAL");
+                return Err(EINVAL);
+            }
+        }
+
+        println!("\tok");
+        Ok(())
     }
 
-    fn fuse_rmdir(&mut self, _parent: u64, _name: &std::ffi::OsStr) -> Result<(), i32> {
-        panic!();
+    fn fuse_rmdir(&mut self, parent: u64, name: &std::ffi::OsStr) -> Result<(), i32> {
+        let name_str = utf8_name(name)?;
+        println!(">>> rmdir parent={parent}, name={}", name_str);
+        self.check_writable()?;
+
+        let ino = match self.name_map.get(&(parent, name_str.to_string())) {
+            Some(ino) => *ino,
+            None => {
+                println!("\tENOENT");
+                return Err(ENOENT);
+            }
+        };
+        self.check_dir(ino)?;
+        if self.children(ino).next().is_some() {
+            println!("\tENOTEMPTY");
+            return Err(ENOTEMPTY);
+        }
+
+        let detached = self.remove_entry(parent, name_str).unwrap();
+        self.commit_or_undo(|fs| fs.restore_entry(parent, name_str, detached))?;
+
+        println!("\tok");
+        Ok(())
     }
 
     fn fuse_setattr(
@@ -425,89 +2159,232 @@ impl WrappedFilesystem for S3TMFS {
         chgtime: Option<std::time::SystemTime>,
         bkuptime: Option<std::time::SystemTime>,
         flags: Option<u32>,
-    ) -> Result<ReplyAttr, i32> {
-        println!(">>> TODO: setattr ino={ino}");
-
-        // Look up file
-        let opt_attr = self.inode_map.get_mut(&ino);
-        if let None = opt_attr {
-            return Err(ENOENT);
-        }
+    ) -> Result<ReplyAttr<'_>, i32> {
+        println!(">>> setattr ino={ino}");
+        self.check_writable()?;
 
-        // Mutate file attribute
-        let attr = opt_attr.unwrap();
+        let mut attr = match self.inode_map.get(&ino) {
+            Some(attr) => *attr,
+            None => {
+                println!("\tENOENT");
+                return Err(ENOENT);
+            }
+        };
+        let now = SystemTime::now();
+        let mut changed = false;
+        let mut old_bkuptime = None;
 
+        // Every field given is applied
+        if let Some(size) = size {
+            println!("\t size={size}");
+            if attr.kind == FileType::Directory {
+                println!("\tEISDIR");
+                return Err(EISDIR);
+            }
+            if size < attr.size {
+                self.truncate(ino, attr.size, size)?;
+            }
+            if size != attr.size {
+                attr.size = size;
+                attr.blocks = size.div_ceil(512);
+                attr.mtime = now;
+            }
+            changed = true;
+        }
         if let Some(mode) = mode {
-            println!("\t mode={mode}");
-            panic!();
-        } else if let Some(uid) = uid {
+            println!("\t mode={mode:o}");
+            attr.perm = (mode & 0o7777) as u16;
+            changed = true;
+        }
+        if let Some(uid) = uid {
             println!("\t uid={uid}");
-            panic!();
-        } else if let Some(gid) = gid {
+            attr.uid = uid;
+            changed = true;
+        }
+        if let Some(gid) = gid {
             println!("\t gid={gid}");
-            panic!();
-        } else if let Some(size) = size {
-            println!("\t size={size}");
-            attr.size = size;
-        } else if let Some(_) = atime {
-            println!("\t atime=?");
-            panic!();
-        } else if let Some(_) = mtime {
-            println!("\t mtime=?");
-            panic!();
-        } else if let Some(ctime) = ctime {
-            println!("\t ctime={}", ctime.elapsed().unwrap().as_millis());
-            panic!();
-        } else if let Some(fh) = fh {
-            println!("\t TODO: fh={fh}");
-        } else if let Some(crtime) = crtime {
-            println!("\t crtime={}", crtime.elapsed().unwrap().as_millis());
-            panic!();
-        } else if let Some(chgtime) = chgtime {
-            println!("\t chgtime={}", chgtime.elapsed().unwrap().as_millis());
-            panic!();
-        } else if let Some(bkuptime) = bkuptime {
-            println!("\t bkuptime={}", bkuptime.elapsed().unwrap().as_millis());
-            panic!();
-        } else if let Some(flags) = flags {
-            println!("\t flags={flags}");
-            panic!();
-        } else {
-            // Not sure what to do...
-            panic!();
+            attr.gid = gid;
+            changed = true;
+        }
+        if let Some(atime) = atime {
+            attr.atime = resolve_time(atime, now);
+            println!("\t atime={:?}", attr.atime);
+            changed = true;
+        }
+        if let Some(mtime) = mtime {
+            attr.mtime = resolve_time(mtime, now);
+            println!("\t mtime={:?}", attr.mtime);
+            changed = true;
+        }
+        if let Some(crtime) = crtime {
+            println!("\t crtime={crtime:?}");
+            attr.crtime = crtime;
+            changed = true;
         }
+        if let Some(bkuptime) = bkuptime {
+            println!("\t bkuptime={bkuptime:?}");
+            old_bkuptime = Some(self.backup_times.insert(ino, bkuptime));
+            changed = true;
+        }
+        if let Some(flags) = flags {
+            println!("\t flags={flags:#x}");
+            attr.flags = flags;
+            changed = true;
+        }
+        if let Some(fh) = fh {
+            println!("\t fh={fh}");
+        }
+
+        // The status change time follows any change unless it is given, macOS
+        // calls it chgtime
+        if let Some(ctime) = ctime.or(chgtime) {
+            println!("\t ctime={ctime:?}");
+            attr.ctime = ctime;
+        } else if changed {
+            attr.ctime = now;
+        }
+
+        // A file shrunk keeps its new size if storing the metadata fails, as
+        // the bands past it are gone
+        let old = self.inode_map.insert(ino, attr).unwrap();
+        self.commit_or_undo(|fs| {
+            let restored = match old.size > attr.size {
+                true => FileAttr {
+                    size: attr.size,
+                    blocks: attr.blocks,
+                    ..old
+                },
+                false => old,
+            };
+            fs.inode_map.insert(ino, restored);
+            match old_bkuptime {
+                Some(Some(time)) => {
+                    fs.backup_times.insert(ino, time);
+                }
+                Some(None) => {
+                    fs.backup_times.remove(&ino);
+                }
+                None => (),
+            }
+        })?;
 
-        Ok(ReplyAttr { ttl: &TTL, attr })
+        println!("\tok");
+        Ok(ReplyAttr {
+            ttl: &TTL,
+            attr: &self.inode_map[&ino],
+        })
     }
 
     fn fuse_setlk(
         &mut self,
-        _ino: u64,
+        ino: u64,
         _fh: u64,
-        _lock_owner: u64,
-        _start: u64,
-        _end: u64,
-        _typ: i32,
-        _pid: u32,
-        _sleep: bool,
+        lock_owner: u64,
+        start: u64,
+        end: u64,
+        typ: i32,
+        pid: u32,
+        sleep: bool,
     ) -> Result<(), i32> {
-        panic!();
+        println!(
+            ">>> setlk ino={ino}, owner={lock_owner}, start={start}, end={end}, typ={typ}, sleep={sleep}"
+        );
+
+        if !self.inode_map.contains_key(&ino) {
+            println!("\tENOENT");
+            return Err(ENOENT);
+        }
+        if typ != F_RDLCK && typ != F_WRLCK && typ != F_UNLCK {
+            println!("\tEINVAL");
+            return Err(EINVAL);
+        }
+
+        let lock = Lock {
+            owner: lock_owner,
+            start,
+            end,
+            typ,
+            pid,
+        };
+        // Requests are handled one at a time, so waiting for a conflicting
+        // lock would stall the filesystem. Blocking requests fail with
+        // ENOLCK, as EAGAIN is only for those asked not to wait.
+        if let Err(held) = self.locks.set(ino, lock) {
+            if sleep {
+                println!("\tENOLCK held by pid={}", held.pid);
+                return Err(ENOLCK);
+            }
+            println!("\tEAGAIN held by pid={}", held.pid);
+            return Err(EAGAIN);
+        }
+
+        println!("\tok");
+        Ok(())
     }
 
     #[cfg(feature = "macos")]
     fn fuse_setvolname(&mut self, _name: &std::ffi::OsStr) -> Result<(), i32> {
-        panic!();
+        println!(">>> setvolname");
+        self.check_writable()?;
+        Err(unsupported("setvolname", ENOSYS))
     }
 
     fn fuse_setxattr(
         &mut self,
         ino: u64,
         name: &std::ffi::OsStr,
-        _value: &[u8],
-        _flags: i32,
-        _position: u32,
+        value: &[u8],
+        flags: i32,
+        position: u32,
     ) -> Result<(), i32> {
-        println!(">>> setxattr ino={ino}, name={}", name.to_str().unwrap());
+        let name_str = utf8_name(name)?;
+        println!(
+            ">>> setxattr ino={ino}, name={name_str}, size={}, flags={flags}, position={position}",
+            value.len()
+        );
+        self.check_writable()?;
+
+        if !self.inode_map.contains_key(&ino) {
+            println!("\tENOENT");
+            return Err(ENOENT);
+        }
+        let old = self.xattrs.get(&ino).and_then(|x| x.get(name_str)).cloned();
+        match &old {
+            Some(_) if flags & libc::XATTR_CREATE != 0 => {
+                println!("\tEEXIST");
+                return Err(EEXIST);
+            }
+            None if flags & libc::XATTR_REPLACE != 0 => {
+                println!("\tENOATTR");
+                return Err(ENOATTR);
+            }
+            _ => (),
+        }
+
+        // macOS writes resource forks in pieces at a position in the value
+        let mut new = match position {
+            0 => Vec::new(),
+            _ => old.clone().unwrap_or_default(),
+        };
+        let end = position as usize + value.len();
+        if new.len() < end {
+            new.resize(end, 0);
+        }
+        new[position as usize..end].copy_from_slice(value);
+        self.set_xattr(ino, name_str, new);
+
+        let ctime = self.touch_ctime(ino);
+        self.commit_or_undo(|fs| {
+            match old {
+                Some(value) => fs.set_xattr(ino, name_str, value),
+                None => {
+                    fs.xattrs.get_mut(&ino).unwrap().remove(name_str);
+                }
+            }
+            fs.inode_map.get_mut(&ino).unwrap().ctime = ctime;
+        })?;
+
+        println!("\tok");
         Ok(())
     }
 
@@ -527,29 +2404,97 @@ impl WrappedFilesystem for S3TMFS {
 
     fn fuse_symlink(
         &mut self,
-        _parent: u64,
-        _link_name: &std::ffi::OsStr,
-        _target: &std::path::Path,
-    ) -> Result<ReplyEntry, i32> {
-        panic!();
+        parent: u64,
+        link_name: &std::ffi::OsStr,
+        target: &std::path::Path,
+    ) -> Result<ReplyEntry<'_>, i32> {
+        let name_str = utf8_name(link_name)?;
+        println!(
+            ">>> symlink parent={parent}, name={name_str}, target={}",
+            target.display()
+        );
+        self.check_writable()?;
+
+        self.check_dir(parent)?;
+        if self.name_map.contains_key(&(parent, name_str.to_string())) {
+            println!("\tEEXIST");
+            return Err(EEXIST);
+        }
+        // Targets are kept as strings in the stored metadata
+        let Some(target) = target.to_str() else {
+            println!("\tEINVAL");
+            return Err(EINVAL);
+        };
+
+        let now = SystemTime::now();
+        let attr = FileAttr {
+            ino: self.next_inode,
+            size: target.len() as u64,
+            atime: now,
+            mtime: now,
+            ctime: now,
+            crtime: now,
+            kind: FileType::Symlink,
+            perm: 0o777,
+            nlink: 1,
+            ..ROOT_DIR_ATTR
+        };
+        self.add_entry(parent, name_str, attr);
+        self.symlinks.insert(attr.ino, target.to_string());
+        self.next_inode += 1;
+        self.commit_or_undo(|fs| {
+            fs.remove_entry(parent, name_str);
+        })?;
+
+        println!("\tok ino={}", attr.ino);
+        Ok(ReplyEntry {
+            ttl: &TTL,
+            attr: &self.inode_map[&attr.ino],
+            generation: 1,
+        })
     }
 
     fn fuse_unlink(&mut self, parent: u64, name: &std::ffi::OsStr) -> Result<(), i32> {
-        let name_str = name.to_str().unwrap();
+        let name_str = utf8_name(name)?;
         println!(">>> unlink parent={parent}, name={}", name_str);
+        self.check_writable()?;
 
-        match self.name_map.get(name_str) {
-            Some(ino) => {
-                println!("\tok ino={ino}");
-                self.inode_map.remove(ino);
-                self.name_map.remove(name_str);
-                Ok(())
-            }
-            _ => {
+        let ino = match self.name_map.get(&(parent, name_str.to_string())) {
+            Some(ino) => *ino,
+            None => {
                 println!("\t ENOENT");
-                Err(ENOENT)
+                return Err(ENOENT);
+            }
+        };
+        if self.check_dir(ino).is_ok() {
+            println!("\tEISDIR");
+            return Err(EISDIR);
+        }
+
+        // Remove the file from the stored tree before dropping its contents,
+        // so a failure leaves unreferenced objects rather than a file with
+        // missing contents
+        let detached = self.remove_entry(parent, name_str).unwrap();
+        let attr = detached.attr;
+        let source = detached.source.clone();
+        let ctime = match detached.last_link {
+            true => None,
+            false => Some(self.touch_ctime(ino)),
+        };
+        self.commit_or_undo(|fs| {
+            fs.restore_entry(parent, name_str, detached);
+            if let Some(ctime) = ctime {
+                fs.inode_map.get_mut(&ino).unwrap().ctime = ctime;
             }
+        })?;
+
+        // Contents are kept while other links to the file remain
+        if ctime.is_none() && attr.kind == FileType::RegularFile {
+            self.unlinked(attr, source);
         }
+
+        println!("\tok ino={ino} nlink={}", attr.nlink);
+        Ok(())
     }
 
     fn fuse_write(
@@ -562,7 +2507,19 @@ impl WrappedFilesystem for S3TMFS {
         _flags: i32,
         _lock_owner: Option<u64>,
     ) -> Result<ReplyWrite, i32> {
-        println!(">>> TODO: write ino={ino}, fh={fh}, offset={offset}");
+        println!(
+            ">>> write ino={ino}, fh={fh}, offset={offset}, size={}",
+            data.len()
+        );
+        self.check_writable()?;
+        self.file_size(ino)?;
+
+        // Wait for uploads if too much data is waiting for them
+        self.uploader.throttle(ino)?;
+
+        self.write_data(ino, offset as u64, data)?;
+
+        println!("\tok");
         Ok(ReplyWrite {
             size: data.len().try_into().unwrap(),
         })
@@ -0,0 +1,178 @@
+use crate::backend::{BackendError, ObjectInfo, ObjectStore, ObjectVersion};
+use crate::metadata::Metadata;
+
+use std::collections::BTreeMap;
+use std::ops::Range;
+use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use serde::{Deserialize, Serialize};
+
+// Manifests are stored below this prefix, named after their id
+pub const SNAPSHOT_PREFIX: &str = "snapshots/";
+
+// The filesystem as it was when a snapshot was taken. Band objects written
+// since are stored under new keys, so the objects listed stay as they were.
+#[derive(Serialize, Deserialize)]
+pub struct Manifest {
+    pub id: String,
+    pub created: SystemTime,
+    pub metadata: Metadata,
+    // ETag of every object holding file contents, by key
+    pub objects: BTreeMap<String, String>,
+    // Version id of the objects, by key, if the bucket keeps versions
+    #[serde(default)]
+    pub versions: BTreeMap<String, String>,
+}
+
+impl Manifest {
+    pub fn key(id: &str) -> String {
+        format!("{SNAPSHOT_PREFIX}{id}")
+    }
+
+    pub fn load(store: &dyn ObjectStore, id: &str) -> Result<Manifest, BackendError> {
+        let data = store.get(&Manifest::key(id), None)?;
+        let manifest: Manifest = serde_json::from_slice(&data)
+            .map_err(|err| BackendError::Other(format!("corrupt manifest {id}: {err}")))?;
+        manifest.metadata.check_version()?;
+        Ok(manifest)
+    }
+
+    // Store a new manifest. Manifests are never replaced.
+    pub fn save(&self, store: &dyn ObjectStore) -> Result<(), BackendError> {
+        let data = serde_json::to_vec(self).map_err(|err| BackendError::Other(err.to_string()))?;
+        store.put_if(&Manifest::key(&self.id), &data, None)?;
+        Ok(())
+    }
+}
+
+// Ids of the snapshots taken, oldest first
+pub fn snapshot_ids(store: &dyn ObjectStore) -> Result<Vec<String>, BackendError> {
+    Ok(store
+        .list(SNAPSHOT_PREFIX)?
+        .into_iter()
+        .map(|object| object.key[SNAPSHOT_PREFIX.len()..].to_string())
+        .collect())
+}
+
+// The snapshot a filesystem is mounted at: the one with the given id, or the
+// last one taken at or before a UTC time such as 2026-10-18T09:30:00Z,
+// 20261018T093000Z or 2026-10-18
+pub fn resolve_snapshot(store: &dyn ObjectStore, at: &str) -> Result<String, BackendError> {
+    let ids = snapshot_ids(store)?;
+    if ids.iter().any(|id| id == at) {
+        return Ok(at.to_string());
+    }
+
+    // Ids are times in the same order as written without separators. A
+    // time given in whole seconds or days includes the whole second or day.
+    let time: String = at.chars().filter(|c| !matches!(c, '-' | ':')).collect();
+    let time = time.trim_end_matches('Z');
+    if time.len() < 8 || !time.starts_with(|c: char| c.is_ascii_digit()) {
+        return Err(BackendError::Other(format!("invalid snapshot time {at}")));
+    }
+    ids.into_iter()
+        .rfind(|id| id.as_str() <= time || id.starts_with(time))
+        .ok_or_else(|| BackendError::Other(format!("no snapshot taken by {at}")))
+}
+
+// Snapshots are identified by the UTC time they were taken, so they sort in
+// that order, e.g. 20261018T093000.123Z
+pub fn snapshot_id(time: SystemTime) -> String {
+    utc_time(time)
+}
+
+// Compact UTC time to the millisecond
+pub fn utc_time(time: SystemTime) -> String {
+    let since_epoch = time.duration_since(UNIX_EPOCH).unwrap_or_default();
+    let secs = since_epoch.as_secs();
+    let (year, month, day) = civil_date(secs / 86400);
+    let time_of_day = secs % 86400;
+    format!(
+        "{year:04}{month:02}{day:02}T{:02}{:02}{:02}.{:03}Z",
+        time_of_day / 3600,
+        time_of_day / 60 % 60,
+        time_of_day % 60,
+        since_epoch.subsec_millis()
+    )
+}
+
+// Year, month and day of a number of days since 1970-01-01 in the proleptic
+// Gregorian calendar
+fn civil_date(days: u64) -> (u64, u64, u64) {
+    // Count from 0000-03-01, so leap days fall at the end of a year
+    let days = days + 719468;
+    let era = days / 146097;
+    let day_of_era = days % 146097;
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let month = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * month + 2) / 5 + 1;
+    let month = if month < 10 { month + 3 } else { month - 9 };
+    let year = year_of_era + era * 400 + u64::from(month <= 2);
+    (year, month, day)
+}
+
+// Object store reading the versions of objects a snapshot recorded, so its
+// files read as they were even where the objects were overwritten since
+pub struct PinnedStore {
+    inner: Arc<dyn ObjectStore>,
+    versions: BTreeMap<String, String>,
+}
+
+impl PinnedStore {
+    pub fn new(inner: Arc<dyn ObjectStore>, versions: BTreeMap<String, String>) -> PinnedStore {
+        PinnedStore { inner, versions }
+    }
+}
+
+impl ObjectStore for PinnedStore {
+    fn get(&self, key: &str, range: Option<Range<u64>>) -> Result<Vec<u8>, BackendError> {
+        match self.versions.get(key) {
+            Some(version_id) => self.inner.get_version(key, version_id, range),
+            None => self.inner.get(key, range),
+        }
+    }
+
+    fn put(&self, key: &str, data: &[u8]) -> Result<(), BackendError> {
+        self.inner.put(key, data)
+    }
+
+    fn get_tagged(&self, key: &str) -> Result<(Vec<u8>, String), BackendError> {
+        self.inner.get_tagged(key)
+    }
+
+    fn put_if(&self, key: &str, data: &[u8], etag: Option<&str>) -> Result<String, BackendError> {
+        self.inner.put_if(key, data, etag)
+    }
+
+    fn delete(&self, key: &str) -> Result<(), BackendError> {
+        self.inner.delete(key)
+    }
+
+    fn copy(&self, from: &str, range: Option<Range<u64>>, to: &str) -> Result<(), BackendError> {
+        self.inner.copy(from, range, to)
+    }
+
+    fn list(&self, prefix: &str) -> Result<Vec<ObjectInfo>, BackendError> {
+        self.inner.list(prefix)
+    }
+
+    fn version(&self, key: &str) -> Option<String> {
+        self.inner.version(key)
+    }
+
+    fn get_version(
+        &self,
+        key: &str,
+        version_id: &str,
+        range: Option<Range<u64>>,
+    ) -> Result<Vec<u8>, BackendError> {
+        self.inner.get_version(key, version_id, range)
+    }
+
+    fn list_versions(&self, prefix: &str) -> Result<Vec<ObjectVersion>, BackendError> {
+        self.inner.list_versions(prefix)
+    }
+}
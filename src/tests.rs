@@ -1,19 +1,70 @@
 use std::ffi::OsStr;
+use std::ops::Range;
+use std::sync::atomic::{AtomicU32, AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
-use fuser::FUSE_ROOT_ID;
+use fuser::{FileType, TimeOrNow, FUSE_ROOT_ID};
 
-use crate::s3tmfs::S3TMFS;
-use crate::wrapperfs::WrappedFilesystem;
+use crate::backend::{BackendError, MemoryStore, ObjectInfo, ObjectStore};
+use crate::compress::{parse_codec, Codec, CompressedStore};
+use crate::crypto::{is_encrypted, EncryptedStore, KdfParams};
+use crate::dedup::{
+    mark_chunks, parse_chunk_size, unmarked_chunks, ChunkSizes, DedupStore, CHUNK_PREFIX,
+};
+use crate::gc::{kept_snapshots, parse_duration, Retention};
+use crate::lease::{Lease, LeaseError, LeasedStore, LEASE_KEY};
+use crate::lock::{Lock, LockTable, F_RDLCK, F_UNLCK, F_WRLCK};
+use crate::retry::{RetryPolicy, RetryStore};
+use crate::s3tmfs::{Config, S3TMFS};
+use crate::snapshot::{resolve_snapshot, snapshot_id, Manifest, PinnedStore};
+use crate::wrapperfs::{reply_xattr, ReplyXattr, WrappedFilesystem, XattrReplier};
+
+// Small bands so tests span several of them
+const BAND_SIZE: u64 = 16;
+
+// Flags of the Linux renameat2() call
+const RENAME_NOREPLACE: u32 = 1;
+const RENAME_EXCHANGE: u32 = 2;
+
+fn test_config() -> Config {
+    Config {
+        band_size: BAND_SIZE,
+        cache_bands: 4,
+        prefetch_depth: 2,
+        prefetch_bandwidth: None,
+        upload_concurrency: 2,
+        max_dirty_bytes: 1024,
+        upload_retry: RetryPolicy {
+            max_attempts: 3,
+            base_delay: Duration::from_millis(1),
+            max_delay: Duration::from_millis(5),
+        },
+        read_only: false,
+        auto_snapshot: false,
+        snapshot: None,
+        retention: None,
+    }
+}
 
 fn make_fs() -> S3TMFS {
-    let mut fs = S3TMFS::new();
+    make_fs_with_store(Arc::new(MemoryStore::new()))
+}
+
+fn make_fs_with_store(store: Arc<MemoryStore>) -> S3TMFS {
+    let mut fs = S3TMFS::new(store, test_config());
     fs.fuse_init().unwrap();
-    return fs;
+    fs
+}
+
+// File contents that differ from band to band
+fn test_data(len: usize) -> Vec<u8> {
+    (0..len).map(|i| (i % 251) as u8).collect()
 }
 
 #[test]
 fn fuse_init_and_destroy() {
-    let mut fs = S3TMFS::new();
+    let mut fs = S3TMFS::new(Arc::new(MemoryStore::new()), Config::default());
     fs.fuse_init().unwrap();
     fs.fuse_destroy();
 }
@@ -84,3 +135,2866 @@ fn fuse_lookup_create() {
         Err(err) => panic!("lookup returned errno {err}"),
     }
 }
+
+#[test]
+fn fuse_non_utf8_name() {
+    use std::os::unix::ffi::OsStrExt;
+    let mut fs = make_fs();
+    let name = OsStr::from_bytes(b"foo\xff");
+    let foo = OsStr::new("foo");
+    fs.fuse_create(FUSE_ROOT_ID, foo, 0, 0, 0).unwrap();
+    assert!(fs.fuse_lookup(FUSE_ROOT_ID, name).err() == Some(libc::EINVAL));
+    assert!(fs.fuse_create(FUSE_ROOT_ID, name, 0, 0, 0).err() == Some(libc::EINVAL));
+    assert!(fs.fuse_mkdir(FUSE_ROOT_ID, name, 0o755, 0).err() == Some(libc::EINVAL));
+    assert!(fs.fuse_rename(FUSE_ROOT_ID, foo, FUSE_ROOT_ID, name, 0) == Err(libc::EINVAL));
+    assert!(fs.fuse_unlink(FUSE_ROOT_ID, name) == Err(libc::EINVAL));
+    assert!(fs.fuse_lookup(FUSE_ROOT_ID, foo).is_ok());
+}
+
+#[test]
+fn fuse_write_read() {
+    let mut fs = make_fs();
+    let rc = fs
+        .fuse_create(FUSE_ROOT_ID, OsStr::new("foo"), 0, 0, 0)
+        .unwrap();
+    let data = test_data(100);
+
+    let rw = fs
+        .fuse_write(rc.attr.ino, rc.fh, 0, &data, 0, 0, None)
+        .unwrap();
+    assert!(rw.size == 100);
+    assert!(fs.fuse_getattr(rc.attr.ino).unwrap().attr.size == 100);
+
+    // Unaligned read spanning several bands
+    let rd = fs.fuse_read(rc.attr.ino, rc.fh, 10, 50, 0, None).unwrap();
+    assert!(rd.data == &data[10..60]);
+
+    // Read past the end of the file is short
+    let rd = fs.fuse_read(rc.attr.ino, rc.fh, 90, 50, 0, None).unwrap();
+    assert!(rd.data == &data[90..]);
+}
+
+#[test]
+fn fuse_release_uploads_bands() {
+    let store = Arc::new(MemoryStore::new());
+    let mut fs = make_fs_with_store(store.clone());
+    let rc = fs
+        .fuse_create(FUSE_ROOT_ID, OsStr::new("foo"), 0, 0, 0)
+        .unwrap();
+    let ino = rc.attr.ino;
+    let data = test_data(40);
+
+    // Full bands may already be uploading, the last one waits for release
+    fs.fuse_write(ino, rc.fh, 0, &data, 0, 0, None).unwrap();
+    assert!(store.get(&format!("bands/{ino:x}/2"), None).is_err());
+
+    fs.fuse_release(ino, rc.fh, 0, None, true).unwrap();
+    fs.wait_idle();
+    assert!(store.get(&format!("bands/{ino:x}/0"), None).unwrap() == data[0..16]);
+    assert!(store.get(&format!("bands/{ino:x}/2"), None).unwrap() == data[32..40]);
+}
+
+#[test]
+fn fuse_read_sequential_prefetch() {
+    let store = Arc::new(MemoryStore::new());
+    let mut fs = make_fs_with_store(store.clone());
+    let rc = fs
+        .fuse_create(FUSE_ROOT_ID, OsStr::new("foo"), 0, 0, 0)
+        .unwrap();
+    let ino = rc.attr.ino;
+    let data = test_data(8 * BAND_SIZE as usize);
+
+    // Only the last bands written stay in the cache after the upload
+    fs.fuse_write(ino, rc.fh, 0, &data, 0, 0, None).unwrap();
+    fs.fuse_release(ino, rc.fh, 0, None, true).unwrap();
+    fs.wait_idle();
+
+    // Two sequential reads trigger read-ahead of the next bands
+    let ro = fs.fuse_open(ino, 0).unwrap();
+    fs.fuse_read(ino, ro.fh, 0, 16, 0, None).unwrap();
+    fs.fuse_read(ino, ro.fh, 16, 16, 0, None).unwrap();
+    fs.wait_idle();
+
+    // Prefetched bands are served from the cache
+    store.delete(&format!("bands/{ino:x}/2")).unwrap();
+    store.delete(&format!("bands/{ino:x}/3")).unwrap();
+    let rd = fs.fuse_read(ino, ro.fh, 32, 32, 0, None).unwrap();
+    assert!(rd.data == &data[32..64]);
+}
+
+#[test]
+fn fuse_read_random_does_not_prefetch() {
+    let store = Arc::new(MemoryStore::new());
+    let mut fs = make_fs_with_store(store.clone());
+    let rc = fs
+        .fuse_create(FUSE_ROOT_ID, OsStr::new("foo"), 0, 0, 0)
+        .unwrap();
+    let ino = rc.attr.ino;
+    let data = test_data(8 * BAND_SIZE as usize);
+
+    fs.fuse_write(ino, rc.fh, 0, &data, 0, 0, None).unwrap();
+    fs.fuse_release(ino, rc.fh, 0, None, true).unwrap();
+    fs.wait_idle();
+
+    let ro = fs.fuse_open(ino, 0).unwrap();
+    fs.fuse_read(ino, ro.fh, 48, 4, 0, None).unwrap();
+    fs.fuse_read(ino, ro.fh, 0, 4, 0, None).unwrap();
+    fs.wait_idle();
+
+    store.delete(&format!("bands/{ino:x}/1")).unwrap();
+    let rd = fs.fuse_read(ino, ro.fh, 16, 16, 0, None).unwrap();
+    assert!(rd.data == [0; 16]);
+}
+
+#[test]
+fn fuse_fsync_uploads_bands() {
+    let store = Arc::new(MemoryStore::new());
+    let mut fs = make_fs_with_store(store.clone());
+    let rc = fs
+        .fuse_create(FUSE_ROOT_ID, OsStr::new("foo"), 0, 0, 0)
+        .unwrap();
+    let ino = rc.attr.ino;
+    let mut data = test_data(64);
+
+    // Rewrite bands while their uploads may be in progress
+    fs.fuse_write(ino, rc.fh, 0, &data, 0, 0, None).unwrap();
+    data[0..20].fill(7);
+    fs.fuse_write(ino, rc.fh, 0, &data[0..20], 0, 0, None)
+        .unwrap();
+
+    fs.fuse_fsync(ino, rc.fh, false).unwrap();
+    for index in 0..4 {
+        let band = store
+            .get(&format!("bands/{ino:x}/{index:x}"), None)
+            .unwrap();
+        assert!(band == data[index * 16..(index + 1) * 16]);
+    }
+}
+
+#[test]
+fn fuse_write_backpressure() {
+    let store = Arc::new(MemoryStore::new());
+    let mut fs = S3TMFS::new(
+        store.clone(),
+        Config {
+            max_dirty_bytes: 32,
+            ..test_config()
+        },
+    );
+    let rc = fs
+        .fuse_create(FUSE_ROOT_ID, OsStr::new("foo"), 0, 0, 0)
+        .unwrap();
+    let ino = rc.attr.ino;
+    let data = test_data(16);
+
+    // Writes wait for earlier bands to be uploaded
+    for index in 0..8 {
+        fs.fuse_write(ino, rc.fh, index * 16, &data, 0, 0, None)
+            .unwrap();
+    }
+    fs.fuse_write(ino, rc.fh, 128, &data, 0, 0, None).unwrap();
+    for index in 0..6 {
+        assert!(store.get(&format!("bands/{ino:x}/{index:x}"), None).is_ok());
+    }
+}
+
+// Store rejecting all uploads
+struct ReadOnlyStore;
+
+impl ObjectStore for ReadOnlyStore {
+    fn get(&self, _key: &str, _range: Option<Range<u64>>) -> Result<Vec<u8>, BackendError> {
+        Err(BackendError::NotFound)
+    }
+
+    fn put(&self, _key: &str, _data: &[u8]) -> Result<(), BackendError> {
+        Err(BackendError::Other("read-only".to_string()))
+    }
+
+    fn get_tagged(&self, _key: &str) -> Result<(Vec<u8>, String), BackendError> {
+        Err(BackendError::NotFound)
+    }
+
+    fn put_if(
+        &self,
+        _key: &str,
+        _data: &[u8],
+        _etag: Option<&str>,
+    ) -> Result<String, BackendError> {
+        Err(BackendError::Other("read-only".to_string()))
+    }
+
+    fn delete(&self, _key: &str) -> Result<(), BackendError> {
+        Err(BackendError::Other("read-only".to_string()))
+    }
+
+    fn copy(&self, _from: &str, _range: Option<Range<u64>>, _to: &str) -> Result<(), BackendError> {
+        Err(BackendError::Other("read-only".to_string()))
+    }
+
+    fn list(&self, _prefix: &str) -> Result<Vec<ObjectInfo>, BackendError> {
+        Ok(Vec::new())
+    }
+}
+
+// The file is not created if the metadata cannot be stored
+#[test]
+fn fuse_create_commit_error() {
+    let mut fs = S3TMFS::new(Arc::new(ReadOnlyStore), test_config());
+    let result = fs.fuse_create(FUSE_ROOT_ID, OsStr::new("foo"), 0, 0, 0);
+    assert!(result.err() == Some(libc::EIO));
+    assert!(fs.fuse_lookup(FUSE_ROOT_ID, OsStr::new("foo")).err() == Some(libc::ENOENT));
+}
+
+// Attributes are restored if the metadata cannot be stored
+#[test]
+fn fuse_setattr_commit_error() {
+    let mut fs = S3TMFS::new(Arc::new(ReadOnlyStore), test_config());
+    let perm = fs.fuse_getattr(FUSE_ROOT_ID).unwrap().attr.perm;
+    let result = fs.fuse_setattr(
+        FUSE_ROOT_ID,
+        Some(0o700),
+        None,
+        None,
+        None,
+        None,
+        None,
+        None,
+        None,
+        None,
+        None,
+        None,
+        None,
+    );
+    assert!(result.err() == Some(libc::EIO));
+    assert!(fs.fuse_getattr(FUSE_ROOT_ID).unwrap().attr.perm == perm);
+}
+
+// Store failing a number of requests with the given error before passing
+// them on to a memory store
+struct FlakyStore {
+    error: BackendError,
+    failures: AtomicU32,
+    attempts: AtomicU32,
+    inner: MemoryStore,
+}
+
+impl FlakyStore {
+    fn new(error: BackendError, failures: u32) -> FlakyStore {
+        FlakyStore {
+            error,
+            failures: AtomicU32::new(failures),
+            attempts: AtomicU32::new(0),
+            inner: MemoryStore::new(),
+        }
+    }
+
+    fn attempt(&self) -> Result<(), BackendError> {
+        self.attempts.fetch_add(1, Ordering::SeqCst);
+        match self
+            .failures
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |n| n.checked_sub(1))
+        {
+            Ok(_) => Err(self.error.clone()),
+            Err(_) => Ok(()),
+        }
+    }
+}
+
+impl ObjectStore for FlakyStore {
+    fn get(&self, key: &str, range: Option<Range<u64>>) -> Result<Vec<u8>, BackendError> {
+        self.attempt()?;
+        self.inner.get(key, range)
+    }
+
+    fn put(&self, key: &str, data: &[u8]) -> Result<(), BackendError> {
+        self.attempt()?;
+        self.inner.put(key, data)
+    }
+
+    fn get_tagged(&self, key: &str) -> Result<(Vec<u8>, String), BackendError> {
+        self.attempt()?;
+        self.inner.get_tagged(key)
+    }
+
+    fn put_if(&self, key: &str, data: &[u8], etag: Option<&str>) -> Result<String, BackendError> {
+        self.attempt()?;
+        self.inner.put_if(key, data, etag)
+    }
+
+    fn delete(&self, key: &str) -> Result<(), BackendError> {
+        self.attempt()?;
+        self.inner.delete(key)
+    }
+
+    fn copy(&self, from: &str, range: Option<Range<u64>>, to: &str) -> Result<(), BackendError> {
+        self.attempt()?;
+        self.inner.copy(from, range, to)
+    }
+
+    fn list(&self, prefix: &str) -> Result<Vec<ObjectInfo>, BackendError> {
+        self.attempt()?;
+        self.inner.list(prefix)
+    }
+}
+
+fn retry_store(inner: Arc<FlakyStore>) -> RetryStore {
+    RetryStore::new(
+        inner,
+        RetryPolicy {
+            max_attempts: 4,
+            base_delay: Duration::from_millis(1),
+            max_delay: Duration::from_millis(5),
+        },
+    )
+}
+
+#[test]
+fn retry_transient_errors() {
+    for error in [
+        BackendError::Throttled,
+        BackendError::Server(500),
+        BackendError::Connection("reset".to_string()),
+    ] {
+        let flaky = Arc::new(FlakyStore::new(error, 3));
+        let store = retry_store(flaky.clone());
+        store.put("foo", b"bar").unwrap();
+        assert!(flaky.attempts.load(Ordering::SeqCst) == 4);
+        assert!(flaky.inner.get("foo", None).unwrap() == b"bar");
+    }
+}
+
+#[test]
+fn retry_gives_up() {
+    let flaky = Arc::new(FlakyStore::new(BackendError::Throttled, 10));
+    let store = retry_store(flaky.clone());
+    let err = store.put("foo", b"bar").unwrap_err();
+    assert!(err.errno() == libc::EAGAIN);
+    assert!(flaky.attempts.load(Ordering::SeqCst) == 4);
+}
+
+#[test]
+fn retry_permanent_errors_fail_immediately() {
+    for (error, errno) in [
+        (BackendError::AccessDenied, libc::EACCES),
+        (BackendError::NotFound, libc::ENOENT),
+        (BackendError::NoSpace, libc::ENOSPC),
+    ] {
+        let flaky = Arc::new(FlakyStore::new(error, 1));
+        let store = retry_store(flaky.clone());
+        assert!(store.get("foo", None).unwrap_err().errno() == errno);
+        assert!(flaky.attempts.load(Ordering::SeqCst) == 1);
+    }
+}
+
+#[test]
+fn fuse_fsync_upload_error() {
+    let flaky = Arc::new(FlakyStore::new(
+        BackendError::Other("failed".to_string()),
+        0,
+    ));
+    let mut fs = S3TMFS::new(flaky.clone(), test_config());
+    let rc = fs
+        .fuse_create(FUSE_ROOT_ID, OsStr::new("foo"), 0, 0, 0)
+        .unwrap();
+    flaky.failures.store(100, Ordering::SeqCst);
+    fs.fuse_write(rc.attr.ino, rc.fh, 0, &test_data(40), 0, 0, None)
+        .unwrap();
+
+    assert!(fs.fuse_fsync(rc.attr.ino, rc.fh, false) == Err(libc::EIO));
+}
+
+// Uploads are tried again when the store fails them for a while
+#[test]
+fn fuse_upload_retry() {
+    let flaky = Arc::new(FlakyStore::new(BackendError::Throttled, 0));
+    let mut fs = S3TMFS::new(flaky.clone(), test_config());
+    let rc = fs
+        .fuse_create(FUSE_ROOT_ID, OsStr::new("foo"), 0, 0, 0)
+        .unwrap();
+    flaky.failures.store(2, Ordering::SeqCst);
+    fs.fuse_write(rc.attr.ino, rc.fh, 0, &test_data(16), 0, 0, None)
+        .unwrap();
+    fs.fuse_fsync(rc.attr.ino, rc.fh, false).unwrap();
+    let key = format!("bands/{:x}/0", rc.attr.ino);
+    assert!(flaky.inner.get(&key, None).unwrap() == test_data(16));
+}
+
+// Writes waiting for uploads only fail for uploads of their own file
+#[test]
+fn fuse_write_throttle_error() {
+    let flaky = Arc::new(FlakyStore::new(
+        BackendError::Other("failed".to_string()),
+        0,
+    ));
+    let mut fs = S3TMFS::new(
+        flaky.clone(),
+        Config {
+            max_dirty_bytes: 16,
+            ..test_config()
+        },
+    );
+    let foo = fs
+        .fuse_create(FUSE_ROOT_ID, OsStr::new("foo"), 0, 0, 0)
+        .unwrap();
+    let bar = fs
+        .fuse_create(FUSE_ROOT_ID, OsStr::new("bar"), 0, 0, 0)
+        .unwrap();
+    flaky.failures.store(100, Ordering::SeqCst);
+    let data = test_data(32);
+    fs.fuse_write(foo.attr.ino, foo.fh, 0, &data, 0, 0, None)
+        .unwrap();
+    fs.fuse_write(bar.attr.ino, bar.fh, 0, &data, 0, 0, None)
+        .unwrap();
+    let result = fs.fuse_write(foo.attr.ino, foo.fh, 0, &data, 0, 0, None);
+    assert!(result.err() == Some(libc::EIO));
+}
+
+#[test]
+fn fuse_fsync_access_denied() {
+    let flaky = Arc::new(FlakyStore::new(BackendError::AccessDenied, 0));
+    let mut fs = S3TMFS::new(flaky.clone(), test_config());
+    let rc = fs
+        .fuse_create(FUSE_ROOT_ID, OsStr::new("foo"), 0, 0, 0)
+        .unwrap();
+    flaky.failures.store(100, Ordering::SeqCst);
+    fs.fuse_write(rc.attr.ino, rc.fh, 0, &test_data(16), 0, 0, None)
+        .unwrap();
+
+    assert!(fs.fuse_fsync(rc.attr.ino, rc.fh, false) == Err(libc::EACCES));
+}
+
+// Every operation replies, unimplemented ones with an error, rather than
+// panicking and taking the mount down
+#[test]
+fn fuse_all_operations_reply() {
+    let mut fs = make_fs();
+    let name = OsStr::new("foo");
+    let newname = OsStr::new("bar");
+
+    for ino in [FUSE_ROOT_ID, 1000] {
+        let _ = fs.fuse_getattr(ino);
+        let _ = fs.fuse_lookup(ino, name);
+        let _ = fs.fuse_access(ino, 0);
+        let _ = fs.fuse_bmap(ino, 512, 0);
+        let _ = fs.fuse_copy_file_range(ino, 1, 0, ino, 1, 0, 10, 0);
+        #[cfg(feature = "macos")]
+        let _ = fs.fuse_exchange(ino, name, ino, newname, 0);
+        let _ = fs.fuse_fallocate(ino, 1, 0, 10, 0);
+        let _ = fs.fuse_flush(ino, 1, 0);
+        fs.fuse_forget(ino, 1);
+        let _ = fs.fuse_fsync(ino, 1, false);
+        let _ = fs.fuse_fsyncdir(ino, 1, false);
+        let _ = fs.fuse_getlk(ino, 1, 0, 0, 10, libc::F_RDLCK, 0);
+        let _ = fs.fuse_getxattr(ino, name, 0);
+        #[cfg(feature = "macos")]
+        let _ = fs.fuse_getxtimes(ino);
+        let _ = fs.fuse_ioctl(ino, 1, 0, 0, &[], 0);
+        let _ = fs.fuse_link(ino, ino, newname);
+        let _ = fs.fuse_listxattr(ino, 0);
+        let _ = fs.fuse_lseek(ino, 1, 0, libc::SEEK_DATA);
+        let _ = fs.fuse_mkdir(ino, name, 0o755, 0);
+        let _ = fs.fuse_mknod(ino, name, 0o644, 0, 0);
+        let _ = fs.fuse_open(ino, 0);
+        let _ = fs.fuse_opendir(ino, 0);
+        let _ = fs.fuse_read(ino, 1, 0, 10, 0, None);
+        let _ = fs.fuse_readdir(ino, 1, 0);
+        let _ = fs.fuse_readdirplus(ino, 1, 0);
+        let _ = fs.fuse_readlink(ino);
+        let _ = fs.fuse_release(ino, 1, 0, None, false);
+        let _ = fs.fuse_releasedir(ino, 1, 0);
+        let _ = fs.fuse_removexattr(ino, name);
+        let _ = fs.fuse_rename(ino, name, ino, newname, 0);
+        let _ = fs.fuse_rmdir(ino, name);
+        let _ = fs.fuse_setattr(
+            ino,
+            Some(0o644),
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
+        );
+        let _ = fs.fuse_setattr(
+            ino, None, None, None, None, None, None, None, None, None, None, None, None,
+        );
+        let _ = fs.fuse_setlk(ino, 1, 0, 0, 10, libc::F_WRLCK, 0, false);
+        let _ = fs.fuse_setxattr(ino, name, b"value", 0, 0);
+        let _ = fs.fuse_statfs(ino);
+        let _ = fs.fuse_symlink(ino, newname, std::path::Path::new("foo"));
+        let _ = fs.fuse_unlink(ino, name);
+        let _ = fs.fuse_write(ino, 1, 0, b"data", 0, 0, None);
+        let _ = fs.fuse_create(ino, name, 0o644, 0, 0);
+    }
+    #[cfg(feature = "macos")]
+    let _ = fs.fuse_setvolname(name);
+    fs.fuse_destroy();
+
+    assert!(fs.fuse_bmap(FUSE_ROOT_ID, 512, 0).err() == Some(libc::ENOSYS));
+    assert!(fs.fuse_mknod(FUSE_ROOT_ID, newname, 0, 0, 0).err() == Some(libc::EOPNOTSUPP));
+}
+
+#[test]
+fn fuse_setattr_applies_all_fields() {
+    let mut fs = make_fs();
+    let rc = fs
+        .fuse_create(FUSE_ROOT_ID, OsStr::new("foo"), 0o644, 0, 0)
+        .unwrap();
+    let ino = rc.attr.ino;
+    let ctime = rc.attr.ctime;
+    let atime = UNIX_EPOCH + Duration::from_secs(1000);
+
+    let before = SystemTime::now();
+    let attr = *fs
+        .fuse_setattr(
+            ino,
+            Some(0o100600),
+            Some(1000),
+            Some(1001),
+            None,
+            Some(TimeOrNow::SpecificTime(atime)),
+            Some(TimeOrNow::Now),
+            None,
+            None,
+            None,
+            None,
+            None,
+            Some(0x8000),
+        )
+        .unwrap()
+        .attr;
+
+    assert!(attr.perm == 0o600);
+    assert!(attr.uid == 1000 && attr.gid == 1001);
+    assert!(attr.atime == atime);
+    assert!(attr.mtime >= before);
+    assert!(attr.ctime >= before && attr.ctime != ctime);
+    assert!(attr.flags == 0x8000);
+    assert!(*fs.fuse_getattr(ino).unwrap().attr == attr);
+}
+
+#[test]
+fn fuse_setattr_times() {
+    let mut fs = make_fs();
+    let rc = fs
+        .fuse_create(FUSE_ROOT_ID, OsStr::new("foo"), 0o644, 0, 0)
+        .unwrap();
+    let ino = rc.attr.ino;
+    let time = UNIX_EPOCH + Duration::from_secs(2000);
+
+    let attr = *fs
+        .fuse_setattr(
+            ino,
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
+            Some(time),
+            None,
+            Some(time),
+            None,
+            Some(time),
+            None,
+        )
+        .unwrap()
+        .attr;
+    assert!(attr.ctime == time);
+    assert!(attr.crtime == time);
+}
+
+#[test]
+fn fuse_setattr_truncate() {
+    let store = Arc::new(MemoryStore::new());
+    let mut fs = make_fs_with_store(store.clone());
+    let rc = fs
+        .fuse_create(FUSE_ROOT_ID, OsStr::new("foo"), 0o644, 0, 0)
+        .unwrap();
+    let ino = rc.attr.ino;
+    let data = test_data(4 * BAND_SIZE as usize);
+    fs.fuse_write(ino, rc.fh, 0, &data, 0, 0, None).unwrap();
+    fs.fuse_fsync(ino, rc.fh, false).unwrap();
+
+    // Shrink into the second band, dropping the bands after it
+    let size = BAND_SIZE + 4;
+    let attr = *fs
+        .fuse_setattr(
+            ino,
+            None,
+            None,
+            None,
+            Some(size),
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
+        )
+        .unwrap()
+        .attr;
+    assert!(attr.size == size);
+    fs.fuse_fsync(ino, rc.fh, false).unwrap();
+    assert!(store.get(&format!("bands/{ino:x}/2"), None).is_err());
+    assert!(store.get(&format!("bands/{ino:x}/3"), None).is_err());
+    assert!(store.get(&format!("bands/{ino:x}/1"), None).unwrap() == data[16..20]);
+
+    // Growing again reads zeros past the old size
+    fs.fuse_setattr(
+        ino,
+        None,
+        None,
+        None,
+        Some(3 * BAND_SIZE),
+        None,
+        None,
+        None,
+        None,
+        None,
+        None,
+        None,
+        None,
+    )
+    .unwrap();
+    let rd = fs.fuse_read(ino, rc.fh, 0, 1000, 0, None).unwrap();
+    assert!(rd.data.len() == 3 * BAND_SIZE as usize);
+    assert!(rd.data[..size as usize] == data[..size as usize]);
+    assert!(rd.data[size as usize..].iter().all(|b| *b == 0));
+}
+
+// A new mount on the same store sees the files of the previous one
+#[test]
+fn fuse_metadata_survives_remount() {
+    let store = Arc::new(MemoryStore::new());
+    let data = test_data(40);
+    let ino = {
+        let mut fs = make_fs_with_store(store.clone());
+        let rc = fs
+            .fuse_create(FUSE_ROOT_ID, OsStr::new("foo"), 0o644, 0, 0)
+            .unwrap();
+        fs.fuse_create(FUSE_ROOT_ID, OsStr::new("bar"), 0o644, 0, 0)
+            .unwrap();
+        fs.fuse_write(rc.attr.ino, rc.fh, 0, &data, 0, 0, None)
+            .unwrap();
+        fs.fuse_fsync(rc.attr.ino, rc.fh, false).unwrap();
+        fs.fuse_unlink(FUSE_ROOT_ID, OsStr::new("bar")).unwrap();
+        rc.attr.ino
+    };
+
+    let mut fs = make_fs_with_store(store);
+    let attr = *fs
+        .fuse_lookup(FUSE_ROOT_ID, OsStr::new("foo"))
+        .unwrap()
+        .attr;
+    assert!(attr.ino == ino && attr.size == 40);
+    assert!(fs.fuse_lookup(FUSE_ROOT_ID, OsStr::new("bar")).is_err());
+    let rd = fs.fuse_read(ino, 1, 0, 100, 0, None).unwrap();
+    assert!(rd.data == &data[..]);
+
+    // Inode numbers are not reused
+    let rc = fs
+        .fuse_create(FUSE_ROOT_ID, OsStr::new("baz"), 0o644, 0, 0)
+        .unwrap();
+    assert!(rc.attr.ino > ino + 1);
+}
+
+#[test]
+fn fuse_init_corrupt_metadata() {
+    let store = Arc::new(MemoryStore::new());
+    store.put("metadata", b"garbage").unwrap();
+    let mut fs = S3TMFS::new(store, test_config());
+    assert!(fs.fuse_init() == Err(libc::EIO));
+}
+
+fn lookup_ino(fs: &mut S3TMFS, parent: u64, name: &str) -> u64 {
+    fs.fuse_lookup(parent, OsStr::new(name)).unwrap().attr.ino
+}
+
+fn readdir_names(fs: &mut S3TMFS, ino: u64) -> Vec<String> {
+    let rd = fs.fuse_readdir(ino, 0, 0).unwrap();
+    rd.entries
+        .into_iter()
+        .map(|entry| entry.name.into_string().unwrap())
+        .collect()
+}
+
+#[test]
+fn fuse_mkdir_readdir_rmdir() {
+    let mut fs = make_fs();
+    let dir = fs
+        .fuse_mkdir(FUSE_ROOT_ID, OsStr::new("dir"), 0o755, 0)
+        .unwrap()
+        .attr
+        .ino;
+    fs.fuse_create(dir, OsStr::new("foo"), 0o644, 0, 0).unwrap();
+    fs.fuse_create(FUSE_ROOT_ID, OsStr::new("foo"), 0o644, 0, 0)
+        .unwrap();
+
+    // Names are looked up within their directory
+    assert!(lookup_ino(&mut fs, dir, "foo") != lookup_ino(&mut fs, FUSE_ROOT_ID, "foo"));
+    assert!(lookup_ino(&mut fs, dir, "..") == FUSE_ROOT_ID);
+    assert!(readdir_names(&mut fs, FUSE_ROOT_ID) == [".", "..", "dir", "foo"]);
+    assert!(readdir_names(&mut fs, dir) == [".", "..", "foo"]);
+    assert!(fs.fuse_getattr(FUSE_ROOT_ID).unwrap().attr.nlink == 3);
+
+    // Listing resumes from an offset
+    let rd = fs.fuse_readdir(FUSE_ROOT_ID, 0, 3).unwrap();
+    assert!(rd.entries.len() == 1 && rd.entries[0].name == "foo");
+
+    assert!(fs.fuse_rmdir(FUSE_ROOT_ID, OsStr::new("dir")) == Err(libc::ENOTEMPTY));
+    assert!(fs.fuse_unlink(FUSE_ROOT_ID, OsStr::new("dir")) == Err(libc::EISDIR));
+    fs.fuse_unlink(dir, OsStr::new("foo")).unwrap();
+    fs.fuse_rmdir(FUSE_ROOT_ID, OsStr::new("dir")).unwrap();
+    assert!(readdir_names(&mut fs, FUSE_ROOT_ID) == [".", "..", "foo"]);
+    assert!(fs.fuse_getattr(FUSE_ROOT_ID).unwrap().attr.nlink == 2);
+}
+
+// Store holding a sparsebundle written by another tool
+fn imported_store() -> Arc<MemoryStore> {
+    let store = Arc::new(MemoryStore::new());
+    store.put("tm.sparsebundle/Info.plist", b"plist").unwrap();
+    store
+        .put("tm.sparsebundle/bands/0", &test_data(40))
+        .unwrap();
+    store.put("tm.sparsebundle/bands/1", b"one").unwrap();
+    store.put("empty/", b"").unwrap();
+    store
+}
+
+#[test]
+fn fuse_import_listing() {
+    let mut fs = make_fs_with_store(imported_store());
+
+    assert!(readdir_names(&mut fs, FUSE_ROOT_ID) == [".", "..", "empty", "tm.sparsebundle"]);
+    let bundle = lookup_ino(&mut fs, FUSE_ROOT_ID, "tm.sparsebundle");
+    assert!(readdir_names(&mut fs, bundle) == [".", "..", "Info.plist", "bands"]);
+    let bands = lookup_ino(&mut fs, bundle, "bands");
+    assert!(readdir_names(&mut fs, bands) == [".", "..", "0", "1"]);
+    let empty = lookup_ino(&mut fs, FUSE_ROOT_ID, "empty");
+    assert!(readdir_names(&mut fs, empty) == [".", ".."]);
+
+    // Contents are read from the objects, across several bands
+    let ino = lookup_ino(&mut fs, bands, "0");
+    let attr = *fs.fuse_getattr(ino).unwrap().attr;
+    assert!(attr.size == 40 && attr.kind == fuser::FileType::RegularFile);
+    assert!(attr.mtime > UNIX_EPOCH);
+    let rd = fs.fuse_read(ino, 1, 10, 100, 0, None).unwrap();
+    assert!(rd.data == &test_data(40)[10..]);
+}
+
+#[test]
+fn fuse_import_write() {
+    let store = imported_store();
+    let data = test_data(40);
+    {
+        let mut fs = make_fs_with_store(store.clone());
+        let bundle = lookup_ino(&mut fs, FUSE_ROOT_ID, "tm.sparsebundle");
+        let bands = lookup_ino(&mut fs, bundle, "bands");
+        let ino = lookup_ino(&mut fs, bands, "0");
+
+        // Only the band written is stored as a band object
+        fs.fuse_write(ino, 1, 20, b"xyz", 0, 0, None).unwrap();
+        fs.fuse_fsync(ino, 1, false).unwrap();
+        assert!(store.get(&format!("bands/{ino:x}/1"), None).is_ok());
+        assert!(store.get(&format!("bands/{ino:x}/0"), None).is_err());
+    }
+
+    // The tree is now loaded from the stored metadata
+    let mut fs = make_fs_with_store(store.clone());
+    let bundle = lookup_ino(&mut fs, FUSE_ROOT_ID, "tm.sparsebundle");
+    let bands = lookup_ino(&mut fs, bundle, "bands");
+    let ino = lookup_ino(&mut fs, bands, "0");
+    let mut expected = data.clone();
+    expected[20..23].copy_from_slice(b"xyz");
+    let rd = fs.fuse_read(ino, 1, 0, 100, 0, None).unwrap();
+    assert!(rd.data == &expected[..]);
+
+    // Removing the file removes the object it was imported from
+    fs.fuse_unlink(bands, OsStr::new("0")).unwrap();
+    assert!(store.get("tm.sparsebundle/bands/0", None).is_err());
+}
+
+#[cfg(not(target_os = "macos"))]
+const ENOATTR: i32 = libc::ENODATA;
+#[cfg(target_os = "macos")]
+const ENOATTR: i32 = libc::ENOATTR;
+
+#[test]
+fn fuse_xattr_set_get() {
+    let mut fs = make_fs();
+    let ino = fs
+        .fuse_create(FUSE_ROOT_ID, OsStr::new("foo"), 0o644, 0, 0)
+        .unwrap()
+        .attr
+        .ino;
+    let name = OsStr::new("com.apple.FinderInfo");
+
+    assert!(fs.fuse_getxattr(ino, name, 0).err() == Some(ENOATTR));
+    fs.fuse_setxattr(ino, name, b"value", 0, 0).unwrap();
+
+    // Probing for the size, then reading into a large enough buffer
+    assert!(fs.fuse_getxattr(ino, name, 0) == Ok(ReplyXattr::Size(5)));
+    assert!(fs.fuse_getxattr(ino, name, 4).err() == Some(libc::ERANGE));
+    let rx = fs.fuse_getxattr(ino, name, 5);
+    assert!(rx == Ok(ReplyXattr::Data(b"value".to_vec())));
+
+    // Values are written at a position for resource forks
+    fs.fuse_setxattr(ino, name, b"ue!", 0, 3).unwrap();
+    let rx = fs.fuse_getxattr(ino, name, 100);
+    assert!(rx == Ok(ReplyXattr::Data(b"value!".to_vec())));
+}
+
+#[test]
+fn fuse_xattr_flags() {
+    let mut fs = make_fs();
+    let ino = fs
+        .fuse_create(FUSE_ROOT_ID, OsStr::new("foo"), 0o644, 0, 0)
+        .unwrap()
+        .attr
+        .ino;
+    let name = OsStr::new("user.test");
+
+    let result = fs.fuse_setxattr(ino, name, b"a", libc::XATTR_REPLACE, 0);
+    assert!(result == Err(ENOATTR));
+    fs.fuse_setxattr(ino, name, b"a", libc::XATTR_CREATE, 0)
+        .unwrap();
+    let result = fs.fuse_setxattr(ino, name, b"b", libc::XATTR_CREATE, 0);
+    assert!(result == Err(libc::EEXIST));
+    fs.fuse_setxattr(ino, name, b"b", libc::XATTR_REPLACE, 0)
+        .unwrap();
+    assert!(fs.fuse_getxattr(ino, name, 10) == Ok(ReplyXattr::Data(b"b".to_vec())));
+}
+
+#[test]
+fn fuse_xattr_list_remove() {
+    let store = Arc::new(MemoryStore::new());
+    let ino = {
+        let mut fs = make_fs_with_store(store.clone());
+        let ino = fs
+            .fuse_create(FUSE_ROOT_ID, OsStr::new("foo"), 0o644, 0, 0)
+            .unwrap()
+            .attr
+            .ino;
+        for name in ["user.a", "user.b", "user.c"] {
+            fs.fuse_setxattr(ino, OsStr::new(name), b"x", 0, 0).unwrap();
+        }
+        fs.fuse_removexattr(ino, OsStr::new("user.b")).unwrap();
+        assert!(fs.fuse_removexattr(ino, OsStr::new("user.b")) == Err(ENOATTR));
+        ino
+    };
+
+    // Attributes are stored with the metadata
+    let mut fs = make_fs_with_store(store);
+    assert!(fs.fuse_listxattr(ino, 0) == Ok(ReplyXattr::Size(14)));
+    assert!(fs.fuse_listxattr(ino, 13).err() == Some(libc::ERANGE));
+    let rx = fs.fuse_listxattr(ino, 14);
+    assert!(rx == Ok(ReplyXattr::Data(b"user.a\0user.c\0".to_vec())));
+}
+
+// Records the reply passed on to the kernel
+#[derive(Debug, PartialEq)]
+enum Replied {
+    Size(u32),
+    Data(Vec<u8>),
+    Error(i32),
+}
+
+impl XattrReplier for &mut Option<Replied> {
+    fn size(self, size: u32) {
+        *self = Some(Replied::Size(size));
+    }
+
+    fn data(self, data: &[u8]) {
+        *self = Some(Replied::Data(data.to_vec()));
+    }
+
+    fn error(self, err: i32) {
+        *self = Some(Replied::Error(err));
+    }
+}
+
+#[test]
+fn reply_xattr_size() {
+    let mut replied = None;
+    reply_xattr(Ok(ReplyXattr::Size(42)), &mut replied);
+    assert!(replied == Some(Replied::Size(42)));
+}
+
+#[test]
+fn reply_xattr_data() {
+    let mut replied = None;
+    reply_xattr(Ok(ReplyXattr::Data(b"value".to_vec())), &mut replied);
+    assert!(replied == Some(Replied::Data(b"value".to_vec())));
+
+    let mut replied = None;
+    reply_xattr(Err(libc::ERANGE), &mut replied);
+    assert!(replied == Some(Replied::Error(libc::ERANGE)));
+}
+
+#[test]
+fn fuse_rename_move_and_replace() {
+    let store = Arc::new(MemoryStore::new());
+    let mut fs = make_fs_with_store(store.clone());
+    let dir = fs
+        .fuse_mkdir(FUSE_ROOT_ID, OsStr::new("dir"), 0o755, 0)
+        .unwrap()
+        .attr
+        .ino;
+    let rc = fs
+        .fuse_create(FUSE_ROOT_ID, OsStr::new("foo"), 0o644, 0, 0)
+        .unwrap();
+    fs.fuse_write(rc.attr.ino, rc.fh, 0, b"foo", 0, 0, None)
+        .unwrap();
+    let old = fs.fuse_create(dir, OsStr::new("bar"), 0o644, 0, 0).unwrap();
+    fs.fuse_write(old.attr.ino, old.fh, 0, b"bar", 0, 0, None)
+        .unwrap();
+    fs.fuse_fsync(old.attr.ino, old.fh, false).unwrap();
+    fs.fuse_release(old.attr.ino, old.fh, 0, None, false)
+        .unwrap();
+
+    // Moving across directories replaces the target and drops its contents
+    fs.fuse_rename(FUSE_ROOT_ID, OsStr::new("foo"), dir, OsStr::new("bar"), 0)
+        .unwrap();
+    assert!(lookup_ino(&mut fs, dir, "bar") == rc.attr.ino);
+    assert!(fs.fuse_lookup(FUSE_ROOT_ID, OsStr::new("foo")).is_err());
+    assert!(fs.fuse_getattr(old.attr.ino).is_err());
+    assert!(store
+        .get(&format!("bands/{:x}/0", old.attr.ino), None)
+        .is_err());
+    let rd = fs.fuse_read(rc.attr.ino, rc.fh, 0, 10, 0, None).unwrap();
+    assert!(rd.data == b"foo");
+
+    // Directories move with their contents
+    fs.fuse_rename(
+        FUSE_ROOT_ID,
+        OsStr::new("dir"),
+        FUSE_ROOT_ID,
+        OsStr::new("moved"),
+        0,
+    )
+    .unwrap();
+    let moved = lookup_ino(&mut fs, FUSE_ROOT_ID, "moved");
+    assert!(lookup_ino(&mut fs, moved, "bar") == rc.attr.ino);
+
+    // The renamed tree is stored
+    let mut fs = make_fs_with_store(store);
+    let moved = lookup_ino(&mut fs, FUSE_ROOT_ID, "moved");
+    assert!(lookup_ino(&mut fs, moved, "bar") == rc.attr.ino);
+}
+
+#[test]
+fn fuse_rename_errors() {
+    let mut fs = make_fs();
+    let dir = fs
+        .fuse_mkdir(FUSE_ROOT_ID, OsStr::new("dir"), 0o755, 0)
+        .unwrap()
+        .attr
+        .ino;
+    let sub = fs
+        .fuse_mkdir(dir, OsStr::new("sub"), 0o755, 0)
+        .unwrap()
+        .attr
+        .ino;
+    fs.fuse_create(sub, OsStr::new("foo"), 0o644, 0, 0).unwrap();
+    fs.fuse_create(FUSE_ROOT_ID, OsStr::new("foo"), 0o644, 0, 0)
+        .unwrap();
+    fs.fuse_mkdir(FUSE_ROOT_ID, OsStr::new("other"), 0o755, 0)
+        .unwrap();
+
+    let root = FUSE_ROOT_ID;
+    let rename = |fs: &mut S3TMFS, parent, name, newparent, newname, flags| {
+        fs.fuse_rename(
+            parent,
+            OsStr::new(name),
+            newparent,
+            OsStr::new(newname),
+            flags,
+        )
+    };
+    assert!(rename(&mut fs, root, "missing", root, "x", 0) == Err(libc::ENOENT));
+    assert!(rename(&mut fs, root, "dir", sub, "x", 0) == Err(libc::EINVAL));
+    assert!(rename(&mut fs, root, "foo", root, "other", 0) == Err(libc::EISDIR));
+    assert!(rename(&mut fs, root, "other", root, "foo", 0) == Err(libc::ENOTDIR));
+    assert!(rename(&mut fs, root, "other", dir, "sub", 0) == Err(libc::ENOTEMPTY));
+    assert!(rename(&mut fs, root, "foo", sub, "foo", RENAME_NOREPLACE) == Err(libc::EEXIST));
+    assert!(rename(&mut fs, root, "foo", root, "x", RENAME_EXCHANGE) == Err(libc::ENOENT));
+    assert!(rename(&mut fs, root, "foo", root, "x", 4) == Err(libc::EINVAL));
+
+    rename(&mut fs, root, "foo", root, "bar", RENAME_NOREPLACE).unwrap();
+    assert!(fs.fuse_lookup(root, OsStr::new("bar")).is_ok());
+}
+
+#[test]
+fn fuse_rename_exchange() {
+    let mut fs = make_fs();
+    let dir = fs
+        .fuse_mkdir(FUSE_ROOT_ID, OsStr::new("dir"), 0o755, 0)
+        .unwrap()
+        .attr
+        .ino;
+    let file = fs
+        .fuse_create(FUSE_ROOT_ID, OsStr::new("foo"), 0o644, 0, 0)
+        .unwrap()
+        .attr
+        .ino;
+    let sub = fs
+        .fuse_mkdir(dir, OsStr::new("sub"), 0o755, 0)
+        .unwrap()
+        .attr
+        .ino;
+
+    fs.fuse_rename(
+        FUSE_ROOT_ID,
+        OsStr::new("foo"),
+        dir,
+        OsStr::new("sub"),
+        RENAME_EXCHANGE,
+    )
+    .unwrap();
+    assert!(lookup_ino(&mut fs, FUSE_ROOT_ID, "foo") == sub);
+    assert!(lookup_ino(&mut fs, dir, "sub") == file);
+
+    // The subdirectory now links to the root instead of dir
+    assert!(fs.fuse_getattr(FUSE_ROOT_ID).unwrap().attr.nlink == 4);
+    assert!(fs.fuse_getattr(dir).unwrap().attr.nlink == 2);
+}
+
+// Memory store counting the objects copied inside it and the listings
+#[derive(Default)]
+struct CopyCountingStore {
+    copies: AtomicU32,
+    lists: AtomicU32,
+    inner: MemoryStore,
+}
+
+impl ObjectStore for CopyCountingStore {
+    fn get(&self, key: &str, range: Option<Range<u64>>) -> Result<Vec<u8>, BackendError> {
+        self.inner.get(key, range)
+    }
+
+    fn put(&self, key: &str, data: &[u8]) -> Result<(), BackendError> {
+        self.inner.put(key, data)
+    }
+
+    fn get_tagged(&self, key: &str) -> Result<(Vec<u8>, String), BackendError> {
+        self.inner.get_tagged(key)
+    }
+
+    fn put_if(&self, key: &str, data: &[u8], etag: Option<&str>) -> Result<String, BackendError> {
+        self.inner.put_if(key, data, etag)
+    }
+
+    fn delete(&self, key: &str) -> Result<(), BackendError> {
+        self.inner.delete(key)
+    }
+
+    fn copy(&self, from: &str, range: Option<Range<u64>>, to: &str) -> Result<(), BackendError> {
+        self.copies.fetch_add(1, Ordering::SeqCst);
+        self.inner.copy(from, range, to)
+    }
+
+    fn list(&self, prefix: &str) -> Result<Vec<ObjectInfo>, BackendError> {
+        self.lists.fetch_add(1, Ordering::SeqCst);
+        self.inner.list(prefix)
+    }
+}
+
+// Filesystem with a file holding the given data and an empty file
+fn copy_fs(store: Arc<CopyCountingStore>, data: &[u8]) -> (S3TMFS, u64, u64) {
+    let mut fs = S3TMFS::new(store, test_config());
+    fs.fuse_init().unwrap();
+    let rc = fs
+        .fuse_create(FUSE_ROOT_ID, OsStr::new("src"), 0o644, 0, 0)
+        .unwrap();
+    fs.fuse_write(rc.attr.ino, rc.fh, 0, data, 0, 0, None)
+        .unwrap();
+    let dst = fs
+        .fuse_create(FUSE_ROOT_ID, OsStr::new("dst"), 0o644, 0, 0)
+        .unwrap()
+        .attr
+        .ino;
+    (fs, rc.attr.ino, dst)
+}
+
+#[test]
+fn fuse_copy_file_range_aligned() {
+    let store = Arc::new(CopyCountingStore::default());
+    let data = test_data(3 * BAND_SIZE as usize + 8);
+    let (mut fs, src, dst) = copy_fs(store.clone(), &data);
+
+    // Every band, including the partial last one, is copied in the store
+    let rw = fs
+        .fuse_copy_file_range(src, 1, 0, dst, 2, 0, 1000, 0)
+        .unwrap();
+    assert!(rw.size == data.len() as u32);
+    assert!(store.copies.load(Ordering::SeqCst) == 4);
+    assert!(fs.fuse_getattr(dst).unwrap().attr.size == data.len() as u64);
+    let rd = fs.fuse_read(dst, 2, 0, 1000, 0, None).unwrap();
+    assert!(rd.data == &data[..]);
+}
+
+#[test]
+fn fuse_copy_file_range_unaligned() {
+    let store = Arc::new(CopyCountingStore::default());
+    let data = test_data(3 * BAND_SIZE as usize);
+    let (mut fs, src, dst) = copy_fs(store.clone(), &data);
+    fs.fuse_write(dst, 2, 0, &[0xff; 40], 0, 0, None).unwrap();
+
+    // Unaligned ranges are copied through the cache
+    let rw = fs
+        .fuse_copy_file_range(src, 1, 5, dst, 2, 3, 30, 0)
+        .unwrap();
+    assert!(rw.size == 30);
+    assert!(store.copies.load(Ordering::SeqCst) == 0);
+
+    // A partial band at the end does not replace the rest of the target band
+    let rw = fs
+        .fuse_copy_file_range(src, 1, 0, dst, 2, 0, BAND_SIZE + 4, 0)
+        .unwrap();
+    assert!(rw.size == BAND_SIZE as u32 + 4);
+    assert!(store.copies.load(Ordering::SeqCst) == 1);
+
+    let mut expected = [0xff; 40];
+    expected[3..33].copy_from_slice(&data[5..35]);
+    expected[..20].copy_from_slice(&data[..20]);
+    let rd = fs.fuse_read(dst, 2, 0, 1000, 0, None).unwrap();
+    assert!(rd.data == &expected[..]);
+}
+
+#[test]
+fn fuse_copy_file_range_errors() {
+    let store = Arc::new(CopyCountingStore::default());
+    let (mut fs, src, dst) = copy_fs(store, &test_data(40));
+
+    let overlap = fs.fuse_copy_file_range(src, 1, 0, src, 1, 8, 16, 0);
+    assert!(overlap.err() == Some(libc::EINVAL));
+    let dir = fs.fuse_copy_file_range(src, 1, 0, FUSE_ROOT_ID, 1, 0, 16, 0);
+    assert!(dir.err() == Some(libc::EISDIR));
+    for (offset_in, offset_out) in [(-1, 0), (0, -8), (0, i64::MIN)] {
+        let negative = fs.fuse_copy_file_range(src, 1, offset_in, src, 1, offset_out, 16, 0);
+        assert!(negative.err() == Some(libc::EINVAL));
+    }
+    assert!(fs.fuse_getattr(src).unwrap().attr.size == 40);
+
+    // Nothing is copied from past the end of the file
+    let rw = fs
+        .fuse_copy_file_range(src, 1, 100, dst, 2, 0, 16, 0)
+        .unwrap();
+    assert!(rw.size == 0);
+}
+
+#[test]
+fn fuse_hard_link() {
+    let store = Arc::new(MemoryStore::new());
+    let data = test_data(40);
+    let mut fs = make_fs_with_store(store.clone());
+    let rc = fs
+        .fuse_create(FUSE_ROOT_ID, OsStr::new("foo"), 0o644, 0, 0)
+        .unwrap();
+    let ino = rc.attr.ino;
+    fs.fuse_write(ino, rc.fh, 0, &data, 0, 0, None).unwrap();
+    fs.fuse_fsync(ino, rc.fh, false).unwrap();
+    let dir = fs
+        .fuse_mkdir(FUSE_ROOT_ID, OsStr::new("dir"), 0o755, 0)
+        .unwrap()
+        .attr
+        .ino;
+
+    let attr = *fs.fuse_link(ino, dir, OsStr::new("bar")).unwrap().attr;
+    assert!(attr.ino == ino && attr.nlink == 2);
+    assert!(fs.fuse_lookup(dir, OsStr::new("bar")).unwrap().attr.ino == ino);
+
+    // The contents stay until the last link is removed
+    fs.fuse_unlink(FUSE_ROOT_ID, OsStr::new("foo")).unwrap();
+    assert!(fs.fuse_getattr(ino).unwrap().attr.nlink == 1);
+    let rd = fs.fuse_read(ino, 1, 0, 100, 0, None).unwrap();
+    assert!(rd.data == &data[..]);
+
+    // Links are kept in the stored metadata
+    let mut fs = make_fs_with_store(store.clone());
+    fs.fuse_link(ino, FUSE_ROOT_ID, OsStr::new("baz")).unwrap();
+    let mut fs = make_fs_with_store(store.clone());
+    assert!(fs.fuse_getattr(ino).unwrap().attr.nlink == 2);
+    fs.fuse_unlink(dir, OsStr::new("bar")).unwrap();
+    assert!(store.get("bands/2/0", None).is_ok());
+    fs.fuse_unlink(FUSE_ROOT_ID, OsStr::new("baz")).unwrap();
+    assert!(fs.fuse_getattr(ino).is_err());
+    assert!(store.get("bands/2/0", None).is_err());
+}
+
+#[test]
+fn fuse_hard_link_errors() {
+    let mut fs = make_fs();
+    let ino = fs
+        .fuse_create(FUSE_ROOT_ID, OsStr::new("foo"), 0o644, 0, 0)
+        .unwrap()
+        .attr
+        .ino;
+    let dir = fs
+        .fuse_mkdir(FUSE_ROOT_ID, OsStr::new("dir"), 0o755, 0)
+        .unwrap()
+        .attr
+        .ino;
+
+    let exists = fs.fuse_link(ino, FUSE_ROOT_ID, OsStr::new("dir"));
+    assert!(exists.err() == Some(libc::EEXIST));
+    let of_dir = fs.fuse_link(dir, FUSE_ROOT_ID, OsStr::new("bar"));
+    assert!(of_dir.err() == Some(libc::EPERM));
+    let missing = fs.fuse_link(100, FUSE_ROOT_ID, OsStr::new("bar"));
+    assert!(missing.err() == Some(libc::ENOENT));
+    let not_dir = fs.fuse_link(ino, ino, OsStr::new("bar"));
+    assert!(not_dir.err() == Some(libc::ENOTDIR));
+
+    // Renaming over another link to the same file does nothing
+    fs.fuse_link(ino, dir, OsStr::new("bar")).unwrap();
+    fs.fuse_rename(dir, OsStr::new("bar"), FUSE_ROOT_ID, OsStr::new("foo"), 0)
+        .unwrap();
+    assert!(fs.fuse_getattr(ino).unwrap().attr.nlink == 2);
+}
+
+#[test]
+fn fuse_symlink_readlink() {
+    let store = Arc::new(MemoryStore::new());
+    let mut fs = make_fs_with_store(store.clone());
+    let target = std::path::Path::new("../backups/disk.sparsebundle");
+    let attr = *fs
+        .fuse_symlink(FUSE_ROOT_ID, OsStr::new("link"), target)
+        .unwrap()
+        .attr;
+    assert!(attr.kind == FileType::Symlink);
+    assert!(attr.size == target.as_os_str().len() as u64);
+
+    let file = fs
+        .fuse_create(FUSE_ROOT_ID, OsStr::new("foo"), 0o644, 0, 0)
+        .unwrap()
+        .attr
+        .ino;
+    assert!(fs.fuse_readlink(file).err() == Some(libc::EINVAL));
+    let exists = fs.fuse_symlink(FUSE_ROOT_ID, OsStr::new("foo"), target);
+    assert!(exists.err() == Some(libc::EEXIST));
+
+    // Targets are kept in the stored metadata
+    let mut fs = make_fs_with_store(store);
+    let rd = fs.fuse_readlink(attr.ino).unwrap();
+    assert!(rd.data == b"../backups/disk.sparsebundle");
+    fs.fuse_unlink(FUSE_ROOT_ID, OsStr::new("link")).unwrap();
+    assert!(fs.fuse_readlink(attr.ino).err() == Some(libc::ENOENT));
+}
+
+#[test]
+fn fuse_read_write_not_file() {
+    let mut fs = make_fs();
+    let dir = *fs
+        .fuse_mkdir(FUSE_ROOT_ID, OsStr::new("dir"), 0o755, 0)
+        .unwrap()
+        .attr;
+    let link = fs
+        .fuse_symlink(
+            FUSE_ROOT_ID,
+            OsStr::new("link"),
+            std::path::Path::new("dir"),
+        )
+        .unwrap()
+        .attr
+        .ino;
+
+    // Only regular files have data to read or write
+    let written = fs.fuse_write(dir.ino, 0, 0, b"data", 0, 0, None);
+    assert!(written.err() == Some(libc::EISDIR));
+    assert!(fs.fuse_getattr(dir.ino).unwrap().attr.size == dir.size);
+    assert!(fs.fuse_read(dir.ino, 0, 0, 10, 0, None).err() == Some(libc::EISDIR));
+    assert!(fs.fuse_read(link, 0, 0, 10, 0, None).err() == Some(libc::EINVAL));
+    let written = fs.fuse_write(link, 0, 0, b"data", 0, 0, None);
+    assert!(written.err() == Some(libc::EINVAL));
+}
+
+fn lock(owner: u64, start: u64, end: u64, typ: i32) -> Lock {
+    Lock {
+        owner,
+        start,
+        end,
+        typ,
+        pid: owner as u32,
+    }
+}
+
+#[test]
+fn lock_table_conflicts() {
+    let mut locks = LockTable::new();
+    locks.set(1, lock(1, 0, 99, F_RDLCK)).unwrap();
+
+    // Read locks are shared, write locks are not
+    locks.set(1, lock(2, 50, 149, F_RDLCK)).unwrap();
+    let held = locks.set(1, lock(3, 120, 200, F_WRLCK)).unwrap_err();
+    assert!(held == lock(2, 50, 149, F_RDLCK));
+    locks.set(1, lock(3, 150, u64::MAX, F_WRLCK)).unwrap();
+    assert!(locks.conflict(1, &lock(1, 200, 300, F_RDLCK)).is_some());
+
+    // Locks on other inodes and the owner's own locks don't conflict
+    locks.set(2, lock(1, 0, u64::MAX, F_WRLCK)).unwrap();
+    locks.set(1, lock(2, 0, 149, F_WRLCK)).unwrap_err();
+    locks.set(1, lock(3, 150, 160, F_RDLCK)).unwrap();
+
+    locks.release(1, 2);
+    assert!(locks.conflict(1, &lock(4, 100, 149, F_WRLCK)).is_none());
+}
+
+#[test]
+fn lock_table_split() {
+    let mut locks = LockTable::new();
+    locks.set(1, lock(1, 0, 99, F_WRLCK)).unwrap();
+
+    // Unlocking the middle of a lock leaves the two ends locked
+    locks.set(1, lock(1, 40, 59, F_UNLCK)).unwrap();
+    assert!(locks.conflict(1, &lock(2, 40, 59, F_WRLCK)).is_none());
+    assert!(locks.conflict(1, &lock(2, 39, 39, F_RDLCK)) == Some(lock(1, 0, 39, F_WRLCK)));
+    assert!(locks.conflict(1, &lock(2, 60, 60, F_RDLCK)) == Some(lock(1, 60, 99, F_WRLCK)));
+
+    // Changing the type of part of a lock
+    locks.set(1, lock(1, 80, 99, F_RDLCK)).unwrap();
+    assert!(locks.conflict(1, &lock(2, 80, 99, F_RDLCK)).is_none());
+    assert!(locks.conflict(1, &lock(2, 79, 80, F_RDLCK)).is_some());
+}
+
+#[test]
+fn fuse_getlk_setlk() {
+    let mut fs = make_fs();
+    let rc = fs
+        .fuse_create(FUSE_ROOT_ID, OsStr::new("token"), 0o644, 0, 0)
+        .unwrap();
+    let ino = rc.attr.ino;
+
+    fs.fuse_setlk(ino, rc.fh, 1, 0, u64::MAX, F_WRLCK, 100, false)
+        .unwrap();
+    let rl = fs.fuse_getlk(ino, rc.fh, 2, 0, 10, F_RDLCK, 200).unwrap();
+    assert!(rl.typ == F_WRLCK && rl.pid == 100 && rl.end == u64::MAX);
+    let busy = fs.fuse_setlk(ino, rc.fh, 2, 0, 10, F_RDLCK, 200, false);
+    assert!(busy.err() == Some(libc::EAGAIN));
+    let busy = fs.fuse_setlk(ino, rc.fh, 2, 0, 10, F_RDLCK, 200, true);
+    assert!(busy.err() == Some(libc::ENOLCK));
+    let rl = fs.fuse_getlk(ino, rc.fh, 1, 0, 10, F_WRLCK, 100).unwrap();
+    assert!(rl.typ == F_UNLCK);
+
+    // Locks of an owner are dropped when it closes the file
+    fs.fuse_flush(ino, rc.fh, 1).unwrap();
+    fs.fuse_setlk(ino, rc.fh, 2, 0, 10, F_WRLCK, 200, false)
+        .unwrap();
+    fs.fuse_release(ino, rc.fh, 0, Some(2), false).unwrap();
+    fs.fuse_setlk(ino, rc.fh, 1, 0, 10, F_WRLCK, 100, false)
+        .unwrap();
+
+    let invalid = fs.fuse_setlk(ino, rc.fh, 1, 0, 10, 42, 100, false);
+    assert!(invalid.err() == Some(libc::EINVAL));
+    let missing = fs.fuse_getlk(100, rc.fh, 1, 0, 10, F_RDLCK, 100);
+    assert!(missing.err() == Some(libc::ENOENT));
+}
+
+#[test]
+fn memory_store_put_if() {
+    let store = MemoryStore::new();
+    let etag = store.put_if("foo", b"one", None).unwrap();
+    assert!(store.put_if("foo", b"two", None) == Err(BackendError::PreconditionFailed));
+
+    let (data, current) = store.get_tagged("foo").unwrap();
+    assert!(data == b"one" && current == etag);
+    let etag2 = store.put_if("foo", b"two", Some(&etag)).unwrap();
+    assert!(etag2 != etag);
+    assert!(store.put_if("foo", b"three", Some(&etag)) == Err(BackendError::PreconditionFailed));
+    assert!(store.get("foo", None).unwrap() == b"two");
+}
+
+#[test]
+fn lease_held_until_released() {
+    let store: Arc<dyn ObjectStore> = Arc::new(MemoryStore::new());
+    let lease = Lease::acquire(store.clone(), Duration::from_secs(60), false).unwrap();
+    assert!(lease.is_valid());
+
+    match Lease::acquire(store.clone(), Duration::from_secs(60), false) {
+        Err(LeaseError::Held(held)) => assert!(held.pid == std::process::id()),
+        _ => panic!("lease taken twice"),
+    }
+
+    lease.renew().unwrap();
+    lease.release();
+    assert!(store.get(LEASE_KEY, None) == Err(BackendError::NotFound));
+    Lease::acquire(store, Duration::from_secs(60), false).unwrap();
+}
+
+#[test]
+fn lease_expires() {
+    let store: Arc<dyn ObjectStore> = Arc::new(MemoryStore::new());
+    let lease = Lease::acquire(store.clone(), Duration::from_millis(20), false).unwrap();
+    std::thread::sleep(Duration::from_millis(40));
+    assert!(!lease.is_valid());
+
+    // Once expired, another mount may take it
+    let other = Lease::acquire(store, Duration::from_secs(60), false).unwrap();
+    assert!(other.is_valid());
+    assert!(lease.renew() == Err(BackendError::LeaseLost));
+}
+
+#[test]
+fn lease_force_break() {
+    let store: Arc<dyn ObjectStore> = Arc::new(MemoryStore::new());
+    let lease = Lease::acquire(store.clone(), Duration::from_secs(60), false).unwrap();
+    let leased = LeasedStore::new(store.clone(), lease.clone());
+    leased.put("foo", b"data").unwrap();
+
+    let other = Lease::acquire(store.clone(), Duration::from_secs(60), true).unwrap();
+    assert!(lease.renew() == Err(BackendError::LeaseLost));
+    assert!(!lease.is_valid());
+
+    // The mount which lost its lease can read but not write
+    assert!(leased.get("foo", None).unwrap() == b"data");
+    assert!(leased.put("foo", b"new") == Err(BackendError::LeaseLost));
+    assert!(leased.delete("foo").unwrap_err().errno() == libc::EROFS);
+
+    // Releasing a lost lease leaves the new one in place
+    lease.release();
+    other.renew().unwrap();
+}
+
+#[test]
+fn lease_kept_renewed() {
+    let store: Arc<dyn ObjectStore> = Arc::new(MemoryStore::new());
+    let lease = Lease::acquire(store.clone(), Duration::from_millis(60), false).unwrap();
+    lease.keep_renewed();
+    std::thread::sleep(Duration::from_millis(150));
+    assert!(lease.is_valid());
+
+    lease.release();
+    assert!(store.get(LEASE_KEY, None) == Err(BackendError::NotFound));
+}
+
+fn read_only_fs(store: Arc<MemoryStore>) -> S3TMFS {
+    let config = Config {
+        read_only: true,
+        ..test_config()
+    };
+    let mut fs = S3TMFS::new(store, config);
+    fs.fuse_init().unwrap();
+    fs
+}
+
+#[test]
+fn fuse_read_only() {
+    let store = Arc::new(MemoryStore::new());
+    let data = test_data(40);
+    let ino = {
+        let mut fs = make_fs_with_store(store.clone());
+        let rc = fs
+            .fuse_create(FUSE_ROOT_ID, OsStr::new("foo"), 0o644, 0, 0)
+            .unwrap();
+        fs.fuse_write(rc.attr.ino, rc.fh, 0, &data, 0, 0, None)
+            .unwrap();
+        fs.fuse_fsync(rc.attr.ino, rc.fh, false).unwrap();
+        rc.attr.ino
+    };
+    let objects = store.list("").unwrap();
+
+    let mut fs = read_only_fs(store.clone());
+    let fh = fs.fuse_open(ino, libc::O_RDONLY).unwrap().fh;
+    let rd = fs.fuse_read(ino, fh, 0, 100, 0, None).unwrap();
+    assert!(rd.data == &data[..]);
+
+    // Every modification is refused
+    let name = OsStr::new("bar");
+    let erofs = Some(libc::EROFS);
+    assert!(fs.fuse_open(ino, libc::O_RDWR).err() == erofs);
+    assert!(fs.fuse_write(ino, fh, 0, b"x", 0, 0, None).err() == erofs);
+    assert!(fs.fuse_create(FUSE_ROOT_ID, name, 0o644, 0, 0).err() == erofs);
+    assert!(fs.fuse_mkdir(FUSE_ROOT_ID, name, 0o755, 0).err() == erofs);
+    assert!(fs.fuse_unlink(FUSE_ROOT_ID, OsStr::new("foo")).err() == erofs);
+    assert!(
+        fs.fuse_rename(FUSE_ROOT_ID, OsStr::new("foo"), FUSE_ROOT_ID, name, 0)
+            .err()
+            == erofs
+    );
+    assert!(fs.fuse_link(ino, FUSE_ROOT_ID, name).err() == erofs);
+    assert!(fs.fuse_setxattr(ino, name, b"value", 0, 0).err() == erofs);
+    assert!(
+        fs.fuse_copy_file_range(ino, fh, 0, ino, fh, 40, 10, 0)
+            .err()
+            == erofs
+    );
+    let truncate = fs.fuse_setattr(
+        ino,
+        None,
+        None,
+        None,
+        Some(0),
+        None,
+        None,
+        None,
+        None,
+        None,
+        None,
+        None,
+        None,
+    );
+    assert!(truncate.err() == erofs);
+
+    fs.fuse_release(ino, fh, 0, None, true).unwrap();
+    fs.fuse_destroy();
+    assert!(store.list("").unwrap() == objects);
+}
+
+// An imported tree is not stored by a read-only mount
+#[test]
+fn fuse_read_only_import() {
+    let store = imported_store();
+    let objects = store.list("").unwrap();
+
+    let mut fs = read_only_fs(store.clone());
+    assert!(!readdir_names(&mut fs, FUSE_ROOT_ID).is_empty());
+    fs.fuse_destroy();
+    assert!(store.list("").unwrap() == objects);
+}
+
+#[test]
+fn snapshot_ids() {
+    let time = UNIX_EPOCH + Duration::from_millis(1760779800123);
+    assert!(snapshot_id(time) == "20251018T093000.123Z");
+    let leap_day = UNIX_EPOCH + Duration::from_secs(1709164800);
+    assert!(snapshot_id(leap_day) == "20240229T000000.000Z");
+    let end_of_day = UNIX_EPOCH + Duration::from_secs(951868799);
+    assert!(snapshot_id(end_of_day) == "20000229T235959.000Z");
+}
+
+// The manifests stored, oldest first
+fn manifests(store: &MemoryStore) -> Vec<Manifest> {
+    store
+        .list("snapshots/")
+        .unwrap()
+        .iter()
+        .map(|object| serde_json::from_slice(&store.get(&object.key, None).unwrap()).unwrap())
+        .collect()
+}
+
+#[test]
+fn fuse_snapshot_copy_on_write() {
+    let store = Arc::new(MemoryStore::new());
+    let data = test_data(40);
+    let mut fs = make_fs_with_store(store.clone());
+    let rc = fs
+        .fuse_create(FUSE_ROOT_ID, OsStr::new("foo"), 0o644, 0, 0)
+        .unwrap();
+    let ino = rc.attr.ino;
+    fs.fuse_write(ino, rc.fh, 0, &data, 0, 0, None).unwrap();
+
+    let id = fs.snapshot().unwrap();
+    let manifest = &manifests(&store)[0];
+    assert!(manifest.id == id);
+    let keys: Vec<&String> = manifest.objects.keys().collect();
+    assert!(keys == ["bands/2/0", "bands/2/1", "bands/2/2"]);
+    assert!(manifest.metadata.inodes[&ino].size == 40);
+
+    // Bands the snapshot refers to are left as they were
+    fs.fuse_write(ino, rc.fh, 4, b"new", 0, 0, None).unwrap();
+    fs.fuse_write(ino, rc.fh, 40, b"more", 0, 0, None).unwrap();
+    fs.fuse_fsync(ino, rc.fh, false).unwrap();
+    assert!(store.get("bands/2/0", None).unwrap() == data[..16]);
+    assert!(store.get("bands/2/2", None).unwrap() == data[32..]);
+    let (band, etag) = store.get_tagged("bands/2/2").unwrap();
+    assert!(band == data[32..] && manifest.objects["bands/2/2"] == etag);
+
+    let mut expected = data.clone();
+    expected[4..7].copy_from_slice(b"new");
+    expected.extend_from_slice(b"more");
+    assert!(store.get("bands/2/0.1", None).unwrap() == expected[..16]);
+
+    // Writes are in place until the next snapshot
+    fs.fuse_write(ino, rc.fh, 0, b"again", 0, 0, None).unwrap();
+    fs.fuse_fsync(ino, rc.fh, false).unwrap();
+    expected[..5].copy_from_slice(b"again");
+    assert!(store.list("bands/2/0").unwrap().len() == 2);
+
+    let mut fs = make_fs_with_store(store.clone());
+    let rd = fs.fuse_read(ino, 1, 0, 100, 0, None).unwrap();
+    assert!(rd.data == &expected[..]);
+}
+
+#[test]
+fn fuse_snapshot_keeps_removed_bands() {
+    let store = Arc::new(MemoryStore::new());
+    let mut fs = make_fs_with_store(store.clone());
+    for name in ["foo", "bar"] {
+        let rc = fs
+            .fuse_create(FUSE_ROOT_ID, OsStr::new(name), 0o644, 0, 0)
+            .unwrap();
+        fs.fuse_write(rc.attr.ino, rc.fh, 0, &test_data(40), 0, 0, None)
+            .unwrap();
+        fs.fuse_release(rc.attr.ino, rc.fh, 0, None, false).unwrap();
+    }
+    fs.snapshot().unwrap();
+    let bar = lookup_ino(&mut fs, FUSE_ROOT_ID, "bar");
+
+    // Removing or truncating files leaves the objects of the snapshot
+    fs.fuse_unlink(FUSE_ROOT_ID, OsStr::new("foo")).unwrap();
+    fs.fuse_setattr(
+        bar,
+        None,
+        None,
+        None,
+        Some(8),
+        None,
+        None,
+        None,
+        None,
+        None,
+        None,
+        None,
+        None,
+    )
+    .unwrap();
+    fs.wait_idle();
+    let manifest = &manifests(&store)[0];
+    for key in manifest.objects.keys() {
+        assert!(store.get(key, None).is_ok());
+    }
+
+    // Objects written since the last snapshot are deleted
+    fs.fuse_write(bar, 1, 16, &test_data(20), 0, 0, None)
+        .unwrap();
+    fs.fuse_fsync(bar, 1, false).unwrap();
+    assert!(store.get("bands/3/2.1", None).is_ok());
+    fs.fuse_unlink(FUSE_ROOT_ID, OsStr::new("bar")).unwrap();
+    assert!(store.get("bands/3/2.1", None).is_err());
+    assert!(store.get("bands/3/2", None).is_ok());
+}
+
+#[test]
+fn fuse_snapshot_on_last_close() {
+    let store = Arc::new(MemoryStore::new());
+    let config = Config {
+        auto_snapshot: true,
+        ..test_config()
+    };
+    let mut fs = S3TMFS::new(store.clone(), config);
+    fs.fuse_init().unwrap();
+
+    let rc = fs
+        .fuse_create(FUSE_ROOT_ID, OsStr::new("foo"), 0o644, 0, 0)
+        .unwrap();
+    let ino = rc.attr.ino;
+    let fh = fs.fuse_open(ino, libc::O_RDWR).unwrap().fh;
+    fs.fuse_write(ino, fh, 0, b"data", 0, 0, None).unwrap();
+    fs.fuse_release(ino, fh, 0, None, true).unwrap();
+    assert!(manifests(&store).is_empty());
+
+    fs.fuse_release(ino, rc.fh, 0, None, true).unwrap();
+    let manifest = &manifests(&store)[0];
+    assert!(manifest.objects.contains_key("bands/2/0"));
+
+    // Nothing changed since, so closing again takes no snapshot
+    let fh = fs.fuse_open(ino, libc::O_RDONLY).unwrap().fh;
+    fs.fuse_read(ino, fh, 0, 100, 0, None).unwrap();
+    fs.fuse_release(ino, fh, 0, None, true).unwrap();
+    assert!(manifests(&store).len() == 1);
+
+    // Changing metadata alone takes no snapshot either
+    let rc = fs
+        .fuse_create(FUSE_ROOT_ID, OsStr::new("bar"), 0o644, 0, 0)
+        .unwrap();
+    fs.fuse_release(rc.attr.ino, rc.fh, 0, None, true).unwrap();
+    assert!(manifests(&store).len() == 1);
+
+    // Contents changed while a file is still open are snapshotted on unmount
+    let fh = fs.fuse_open(ino, libc::O_RDWR).unwrap().fh;
+    fs.fuse_write(ino, fh, 0, b"more", 0, 0, None).unwrap();
+    fs.fuse_destroy();
+    assert!(manifests(&store).len() == 2);
+}
+
+#[test]
+fn snapshot_resolve() {
+    let store = MemoryStore::new();
+    for id in [
+        "20261017T220000.000Z",
+        "20261018T093000.123Z",
+        "20261018T120000.000Z",
+    ] {
+        store.put(&format!("snapshots/{id}"), b"{}").unwrap();
+    }
+
+    let resolve = |at| resolve_snapshot(&store, at).ok();
+    assert!(resolve("20261018T093000.123Z").unwrap() == "20261018T093000.123Z");
+    assert!(resolve("2026-10-18T09:30:00Z").unwrap() == "20261018T093000.123Z");
+    assert!(resolve("2026-10-18T11:59:59Z").unwrap() == "20261018T093000.123Z");
+    assert!(resolve("20261018T0929").unwrap() == "20261017T220000.000Z");
+    assert!(resolve("2026-10-18").unwrap() == "20261018T120000.000Z");
+    assert!(resolve("2026-10-17T21:00:00Z").is_none());
+    assert!(resolve("yesterday").is_none());
+}
+
+#[test]
+fn fuse_mount_snapshot() {
+    let store = Arc::new(MemoryStore::new());
+    let data = test_data(40);
+    let mut fs = make_fs_with_store(store.clone());
+    let rc = fs
+        .fuse_create(FUSE_ROOT_ID, OsStr::new("foo"), 0o644, 0, 0)
+        .unwrap();
+    let ino = rc.attr.ino;
+    fs.fuse_write(ino, rc.fh, 0, &data, 0, 0, None).unwrap();
+    let id = fs.snapshot().unwrap();
+
+    // Changes made after the snapshot
+    fs.fuse_write(ino, rc.fh, 0, &[0xff; 48], 0, 0, None)
+        .unwrap();
+    fs.fuse_create(FUSE_ROOT_ID, OsStr::new("bar"), 0o644, 0, 0)
+        .unwrap();
+    fs.fuse_fsync(ino, rc.fh, false).unwrap();
+
+    let config = Config {
+        read_only: true,
+        snapshot: Some(id),
+        ..test_config()
+    };
+    let mut fs = S3TMFS::new(store.clone(), config);
+    fs.fuse_init().unwrap();
+    assert!(readdir_names(&mut fs, FUSE_ROOT_ID) == [".", "..", "foo"]);
+    assert!(fs.fuse_getattr(ino).unwrap().attr.size == 40);
+    let rd = fs.fuse_read(ino, 1, 0, 100, 0, None).unwrap();
+    assert!(rd.data == &data[..]);
+    let write = fs.fuse_write(ino, 1, 0, b"x", 0, 0, None);
+    assert!(write.err() == Some(libc::EROFS));
+
+    let config = Config {
+        read_only: true,
+        snapshot: Some("20261018T093000.123Z".to_string()),
+        ..test_config()
+    };
+    let mut fs = S3TMFS::new(store, config);
+    assert!(fs.fuse_init() == Err(libc::ENOENT));
+}
+
+#[test]
+fn memory_store_versions() {
+    let store = MemoryStore::versioned();
+    store.put("foo", b"one").unwrap();
+    let first = store.version("foo").unwrap();
+    store.put("foo", b"two").unwrap();
+    let second = store.version("foo").unwrap();
+    store.delete("foo").unwrap();
+    assert!(store.version("foo").is_none());
+    store.put("foobar", b"three").unwrap();
+
+    let versions = store.list_versions("foo").unwrap();
+    let listed: Vec<(&str, bool, bool)> = versions
+        .iter()
+        .map(|version| (version.key.as_str(), version.latest, version.deleted))
+        .collect();
+    assert!(
+        listed
+            == [
+                ("foo", true, true),
+                ("foo", false, false),
+                ("foo", false, false),
+                ("foobar", true, false)
+            ]
+    );
+    assert!(versions[1].version_id == second && versions[2].version_id == first);
+    assert!(versions[2].size == 3);
+
+    assert!(store.get_version("foo", &first, None).unwrap() == b"one");
+    assert!(store.get_version("foo", &second, Some(1..2)).unwrap() == b"w");
+    let deleted = &versions[0].version_id;
+    assert!(store.get_version("foo", deleted, None) == Err(BackendError::NotFound));
+
+    // Without versioning only the current objects are kept
+    let store = MemoryStore::new();
+    store.put("foo", b"one").unwrap();
+    assert!(store.version("foo").is_none());
+    assert!(store.list_versions("").unwrap().is_empty());
+}
+
+#[test]
+fn fuse_snapshot_versions() {
+    let store = Arc::new(MemoryStore::versioned());
+    let data = test_data(40);
+    let mut fs = make_fs_with_store(store.clone());
+    let rc = fs
+        .fuse_create(FUSE_ROOT_ID, OsStr::new("foo"), 0o644, 0, 0)
+        .unwrap();
+    let ino = rc.attr.ino;
+    fs.fuse_write(ino, rc.fh, 0, &data, 0, 0, None).unwrap();
+    let id = fs.snapshot().unwrap();
+
+    let manifest = &manifests(&store)[0];
+    let keys: Vec<&String> = manifest.versions.keys().collect();
+    assert!(keys == ["bands/2/0", "bands/2/1", "bands/2/2"]);
+    assert!(manifest.versions["bands/2/0"] == store.version("bands/2/0").unwrap());
+
+    // Versions not written by the mount are found by listing
+    drop(fs);
+    let mut fs = make_fs_with_store(store.clone());
+    fs.fuse_mkdir(FUSE_ROOT_ID, OsStr::new("dir"), 0o755, 0)
+        .unwrap();
+    // Snapshots taken within the same millisecond would share an id
+    std::thread::sleep(Duration::from_millis(2));
+    fs.snapshot().unwrap();
+    assert!(manifests(&store)[1].versions == manifest.versions);
+
+    // A band replaced by another tool still reads as it was
+    store.put("bands/2/1", &[0xff; 16]).unwrap();
+    let pinned = PinnedStore::new(store.clone(), manifest.versions.clone());
+    let config = Config {
+        read_only: true,
+        snapshot: Some(id),
+        ..test_config()
+    };
+    let mut fs = S3TMFS::new(Arc::new(pinned), config);
+    fs.fuse_init().unwrap();
+    let rd = fs.fuse_read(ino, 1, 0, 100, 0, None).unwrap();
+    assert!(rd.data == &data[..]);
+}
+
+#[test]
+fn gc_parse_duration() {
+    let days = |n: u64| Duration::from_secs(n * 24 * 3600);
+    assert!(parse_duration("36h") == Ok(Duration::from_secs(36 * 3600)));
+    assert!(parse_duration("30d") == Ok(days(30)));
+    assert!(parse_duration("2w") == Ok(days(14)));
+    assert!(parse_duration("1y") == Ok(days(365)));
+    assert!(parse_duration("0") == Ok(Duration::ZERO));
+    assert!(parse_duration("5").is_err());
+    assert!(parse_duration("d").is_err());
+    assert!(parse_duration("3m").is_err());
+}
+
+#[test]
+fn gc_kept_snapshots() {
+    // 20 minutes before the hour
+    let now = UNIX_EPOCH + Duration::from_secs(1_792_000_000);
+    let minutes_ago = |minutes: u64| now - Duration::from_secs(minutes * 60);
+    // Every 20 minutes for 3 hours, then every 5 hours for 150 days
+    let mut times: Vec<SystemTime> = (0..9).map(|n| minutes_ago(n * 20)).collect();
+    times.extend((1..720).map(|n| minutes_ago(n * 300)));
+    times.reverse();
+    let snapshots: Vec<(String, SystemTime)> = times
+        .iter()
+        .map(|time| (snapshot_id(*time), *time))
+        .collect();
+
+    let retention = Retention {
+        hourly: Duration::from_secs(3 * 3600),
+        daily: Duration::from_secs(7 * 24 * 3600),
+        monthly: Duration::from_secs(90 * 24 * 3600),
+    };
+    let kept = kept_snapshots(&snapshots, &retention, now);
+    for minutes in [0, 60, 120] {
+        assert!(kept.contains(&snapshot_id(minutes_ago(minutes))));
+    }
+    for minutes in [20, 40, 80, 100, 140, 160] {
+        assert!(!kept.contains(&snapshot_id(minutes_ago(minutes))));
+    }
+
+    // The last one of every day within a week and every month within 90 days
+    let last_of = |len: usize| {
+        let mut ids: Vec<&String> = Vec::new();
+        for (id, _) in snapshots.iter().rev() {
+            if ids.last().is_none_or(|last| last[..len] != id[..len]) {
+                ids.push(id);
+            }
+        }
+        ids
+    };
+    let daily = last_of(8);
+    let monthly = last_of(6);
+    assert!(daily[..7].iter().all(|id| kept.contains(*id)));
+    // 2026-10-14 back to 2026-07-16, ending June's snapshots
+    assert!(monthly[..4].iter().all(|id| kept.contains(*id)));
+    assert!(!kept.contains(monthly[4]));
+    assert!(kept.len() <= 3 + 7 + 4);
+
+    // The latest snapshot is kept whatever its age
+    let retention = Retention {
+        hourly: Duration::ZERO,
+        daily: Duration::ZERO,
+        monthly: Duration::ZERO,
+    };
+    let kept = kept_snapshots(&snapshots[..5], &retention, now);
+    assert!(kept.into_iter().collect::<Vec<_>>() == [snapshots[4].0.clone()]);
+}
+
+#[test]
+fn fuse_gc() {
+    let store = Arc::new(MemoryStore::new());
+    let data = test_data(40);
+    let mut fs = make_fs_with_store(store.clone());
+    let rc = fs
+        .fuse_create(FUSE_ROOT_ID, OsStr::new("foo"), 0o644, 0, 0)
+        .unwrap();
+    let ino = rc.attr.ino;
+    fs.fuse_write(ino, rc.fh, 0, &data, 0, 0, None).unwrap();
+    let first = fs.snapshot().unwrap();
+    fs.fuse_write(ino, rc.fh, 0, b"one", 0, 0, None).unwrap();
+    std::thread::sleep(Duration::from_millis(2));
+    let second = fs.snapshot().unwrap();
+    fs.fuse_write(ino, rc.fh, 0, b"two", 0, 0, None).unwrap();
+    fs.fuse_setattr(
+        ino,
+        None,
+        None,
+        None,
+        Some(20),
+        None,
+        None,
+        None,
+        None,
+        None,
+        None,
+        None,
+        None,
+    )
+    .unwrap();
+    fs.fuse_fsync(ino, rc.fh, false).unwrap();
+
+    // Only the latest snapshot of the hour is kept
+    let garbage = fs.collect_garbage(&Retention::default()).unwrap();
+    let expired: Vec<&str> = garbage
+        .snapshots
+        .iter()
+        .map(|object| object.key.as_str())
+        .collect();
+    assert!(expired == [format!("snapshots/{first}")]);
+    let objects: Vec<&str> = garbage
+        .objects
+        .iter()
+        .map(|object| object.key.as_str())
+        .collect();
+    assert!(objects == ["bands/2/0"]);
+    assert!(garbage.bytes() == garbage.snapshots[0].size + 16);
+
+    garbage.sweep(&*store).unwrap();
+    let keys: Vec<String> = store
+        .list("")
+        .unwrap()
+        .into_iter()
+        .map(|object| object.key)
+        .collect();
+    let second_key = format!("snapshots/{second}");
+    assert!(
+        keys == [
+            "bands/2/0.1",
+            "bands/2/0.2",
+            "bands/2/1",
+            "bands/2/1.2",
+            "bands/2/2",
+            "metadata",
+            second_key.as_str()
+        ]
+    );
+
+    let mut expected = data.clone();
+    expected[..3].copy_from_slice(b"two");
+    let rd = fs.fuse_read(ino, rc.fh, 0, 100, 0, None).unwrap();
+    assert!(rd.data == &expected[..20]);
+    let garbage = fs.collect_garbage(&Retention::default()).unwrap();
+    assert!(garbage.snapshots.is_empty() && garbage.objects.is_empty());
+}
+
+// Band objects of the generation being written are left alone, as they may
+// have been written since finding the objects referenced
+#[test]
+fn fuse_gc_skips_current_generation() {
+    let store = Arc::new(MemoryStore::new());
+    let mut fs = make_fs_with_store(store.clone());
+    fs.snapshot().unwrap();
+    store.put("bands/9/0", b"old").unwrap();
+    store.put("bands/9/0.1", b"new").unwrap();
+    let garbage = fs.collect_garbage(&Retention::default()).unwrap();
+    let objects: Vec<&str> = garbage
+        .objects
+        .iter()
+        .map(|object| object.key.as_str())
+        .collect();
+    assert!(objects == ["bands/9/0"]);
+}
+
+// Keys of the band objects of a file
+fn band_keys(store: &MemoryStore, ino: u64) -> Vec<String> {
+    store
+        .list(&format!("bands/{ino:x}/"))
+        .unwrap()
+        .into_iter()
+        .map(|object| object.key)
+        .collect()
+}
+
+#[test]
+fn fuse_unlink_open_file() {
+    let store = Arc::new(MemoryStore::new());
+    let data = test_data(40);
+    let mut fs = make_fs_with_store(store.clone());
+    let rc = fs
+        .fuse_create(FUSE_ROOT_ID, OsStr::new("foo"), 0o644, 0, 0)
+        .unwrap();
+    let ino = rc.attr.ino;
+    fs.fuse_write(ino, rc.fh, 0, &data, 0, 0, None).unwrap();
+    fs.fuse_fsync(ino, rc.fh, false).unwrap();
+    let other = fs.fuse_open(ino, libc::O_RDONLY).unwrap();
+
+    // The file stays readable and writable through its handles
+    fs.fuse_unlink(FUSE_ROOT_ID, OsStr::new("foo")).unwrap();
+    assert!(fs.fuse_lookup(FUSE_ROOT_ID, OsStr::new("foo")).is_err());
+    assert!(fs.fuse_getattr(ino).unwrap().attr.nlink == 0);
+    assert!(!band_keys(&store, ino).is_empty());
+    let rd = fs.fuse_read(ino, rc.fh, 0, 100, 0, None).unwrap();
+    assert!(rd.data == &data[..]);
+    fs.fuse_write(ino, rc.fh, 40, b"more", 0, 0, None).unwrap();
+    fs.fuse_fsync(ino, rc.fh, false).unwrap();
+    let rd = fs.fuse_read(ino, other.fh, 36, 100, 0, None).unwrap();
+    assert!(rd.data == [&data[36..], b"more"].concat());
+
+    // It is no longer in the stored tree
+    let mut remounted = make_fs_with_store(store.clone());
+    assert!(remounted.fuse_getattr(ino).is_err());
+
+    // Its contents go with the last handle
+    fs.fuse_release(ino, rc.fh, 0, None, false).unwrap();
+    assert!(fs.fuse_getattr(ino).is_ok());
+    fs.fuse_release(ino, other.fh, 0, None, false).unwrap();
+    fs.wait_idle();
+    assert!(fs.fuse_getattr(ino).is_err());
+    assert!(band_keys(&store, ino).is_empty());
+}
+
+#[test]
+fn fuse_fallocate_punch_hole() {
+    const PUNCH_HOLE: i32 = 3; // FALLOC_FL_PUNCH_HOLE | FALLOC_FL_KEEP_SIZE
+    let store = Arc::new(MemoryStore::new());
+    let data = test_data(56);
+    let mut fs = make_fs_with_store(store.clone());
+    let rc = fs
+        .fuse_create(FUSE_ROOT_ID, OsStr::new("foo"), 0o644, 0, 0)
+        .unwrap();
+    let ino = rc.attr.ino;
+    fs.fuse_write(ino, rc.fh, 0, &data, 0, 0, None).unwrap();
+    fs.fuse_fsync(ino, rc.fh, false).unwrap();
+    assert!(band_keys(&store, ino).len() == 4);
+
+    // Bands covered up to the end of the file are dropped
+    fs.fuse_fallocate(ino, rc.fh, 8, 100, PUNCH_HOLE).unwrap();
+    assert!(fs.fuse_getattr(ino).unwrap().attr.size == 56);
+    let mut expected = data.clone();
+    expected[8..].fill(0);
+    let rd = fs.fuse_read(ino, rc.fh, 0, 100, 0, None).unwrap();
+    assert!(rd.data == &expected[..]);
+    fs.fuse_fsync(ino, rc.fh, false).unwrap();
+    assert!(band_keys(&store, ino) == ["bands/2/0"]);
+
+    // A band left all zeros is deleted rather than uploaded
+    fs.fuse_fallocate(ino, rc.fh, 0, 8, PUNCH_HOLE).unwrap();
+    fs.fuse_fsync(ino, rc.fh, false).unwrap();
+    assert!(band_keys(&store, ino).is_empty());
+
+    let mut fs = make_fs_with_store(store.clone());
+    let rd = fs.fuse_read(ino, 1, 0, 100, 0, None).unwrap();
+    assert!(rd.data == &[0; 56][..]);
+}
+
+#[test]
+fn fuse_fallocate_modes() {
+    let store = Arc::new(MemoryStore::new());
+    let data = test_data(40);
+    let mut fs = make_fs_with_store(store.clone());
+    let rc = fs
+        .fuse_create(FUSE_ROOT_ID, OsStr::new("foo"), 0o644, 0, 0)
+        .unwrap();
+    let ino = rc.attr.ino;
+    fs.fuse_write(ino, rc.fh, 0, &data, 0, 0, None).unwrap();
+
+    // Allocating extends the file with zeros, unless the size is kept
+    fs.fuse_fallocate(ino, rc.fh, 30, 70, 1).unwrap();
+    assert!(fs.fuse_getattr(ino).unwrap().attr.size == 40);
+    fs.fuse_fallocate(ino, rc.fh, 30, 70, 0).unwrap();
+    assert!(fs.fuse_getattr(ino).unwrap().attr.size == 100);
+    let rd = fs.fuse_read(ino, rc.fh, 0, 200, 0, None).unwrap();
+    assert!(rd.data[..40] == data[..] && rd.data[40..] == [0; 60]);
+
+    // Holes punched after a snapshot leave its bands in place
+    fs.snapshot().unwrap();
+    fs.fuse_fallocate(ino, rc.fh, 16, 16, 3).unwrap();
+    fs.fuse_fsync(ino, rc.fh, false).unwrap();
+    assert!(store.get("bands/2/1", None).unwrap() == data[16..32]);
+    let rd = fs.fuse_read(ino, rc.fh, 0, 40, 0, None).unwrap();
+    assert!(rd.data[16..32] == [0; 16] && rd.data[32..] == data[32..]);
+
+    assert!(fs.fuse_fallocate(ino, rc.fh, 0, 10, 2) == Err(libc::EOPNOTSUPP));
+    assert!(fs.fuse_fallocate(ino, rc.fh, -1, 10, 0) == Err(libc::EINVAL));
+    assert!(fs.fuse_fallocate(ino, rc.fh, 0, 0, 0) == Err(libc::EINVAL));
+    assert!(fs.fuse_fallocate(99, rc.fh, 0, 10, 0) == Err(libc::ENOENT));
+}
+
+#[test]
+fn fuse_lseek_data_hole() {
+    let store = Arc::new(MemoryStore::new());
+    let data = test_data(56);
+    let mut fs = make_fs_with_store(store.clone());
+    let rc = fs
+        .fuse_create(FUSE_ROOT_ID, OsStr::new("foo"), 0o644, 0, 0)
+        .unwrap();
+    let ino = rc.attr.ino;
+    fs.fuse_write(ino, rc.fh, 0, &data[..16], 0, 0, None)
+        .unwrap();
+    fs.fuse_write(ino, rc.fh, 40, &data[40..], 0, 0, None)
+        .unwrap();
+
+    // Bands 0, 2 and 3 hold data, whether waiting for upload or stored
+    let seek = |fs: &mut S3TMFS, offset, whence| {
+        fs.fuse_lseek(ino, rc.fh, offset, whence)
+            .map(|reply| reply.offset)
+    };
+    for _ in 0..2 {
+        assert!(seek(&mut fs, 0, libc::SEEK_DATA) == Ok(0));
+        assert!(seek(&mut fs, 5, libc::SEEK_DATA) == Ok(5));
+        assert!(seek(&mut fs, 16, libc::SEEK_DATA) == Ok(32));
+        assert!(seek(&mut fs, 50, libc::SEEK_DATA) == Ok(50));
+        assert!(seek(&mut fs, 0, libc::SEEK_HOLE) == Ok(16));
+        assert!(seek(&mut fs, 20, libc::SEEK_HOLE) == Ok(20));
+        assert!(seek(&mut fs, 32, libc::SEEK_HOLE) == Ok(56));
+        fs.fuse_fsync(ino, rc.fh, false).unwrap();
+    }
+
+    assert!(seek(&mut fs, 56, libc::SEEK_DATA) == Err(libc::ENXIO));
+    assert!(seek(&mut fs, 60, libc::SEEK_HOLE) == Err(libc::ENXIO));
+    assert!(seek(&mut fs, -1, libc::SEEK_DATA) == Err(libc::ENXIO));
+    assert!(seek(&mut fs, 10, libc::SEEK_SET) == Ok(10));
+    assert!(seek(&mut fs, -6, libc::SEEK_END) == Ok(50));
+    assert!(seek(&mut fs, -60, libc::SEEK_END) == Err(libc::EINVAL));
+
+    // No data is left past a hole punched to the end
+    fs.fuse_fallocate(ino, rc.fh, 32, 24, 3).unwrap();
+    fs.fuse_fsync(ino, rc.fh, false).unwrap();
+    assert!(seek(&mut fs, 16, libc::SEEK_DATA) == Err(libc::ENXIO));
+    assert!(fs.fuse_lseek(99, rc.fh, 0, libc::SEEK_DATA).err() == Some(libc::ENOENT));
+}
+
+// The band objects of a file are listed once, then kept track of
+#[test]
+fn fuse_lseek_lists_once() {
+    let store = Arc::new(CopyCountingStore::default());
+    let data = test_data(3 * BAND_SIZE as usize);
+    let (mut fs, src, _) = copy_fs(store.clone(), &data);
+    let lists = store.lists.load(Ordering::SeqCst);
+    let seek = |fs: &mut S3TMFS, offset, whence| {
+        fs.fuse_lseek(src, 1, offset, whence)
+            .map(|reply| reply.offset)
+    };
+    assert!(seek(&mut fs, 0, libc::SEEK_HOLE) == Ok(48));
+    fs.fuse_fsync(src, 1, false).unwrap();
+    assert!(seek(&mut fs, 20, libc::SEEK_DATA) == Ok(20));
+    fs.fuse_fallocate(src, 1, 16, 16, 3).unwrap();
+    assert!(seek(&mut fs, 0, libc::SEEK_HOLE) == Ok(16));
+    assert!(seek(&mut fs, 16, libc::SEEK_DATA) == Ok(32));
+    assert!(store.lists.load(Ordering::SeqCst) == lists + 1);
+}
+
+// Cheap key derivation, so tests don't take long
+const TEST_KDF: KdfParams = KdfParams {
+    m_cost: 64,
+    t_cost: 1,
+    p_cost: 1,
+};
+
+#[test]
+fn encrypted_store_round_trip() {
+    let inner = Arc::new(MemoryStore::new());
+    let store = EncryptedStore::open(inner.clone(), "secret", &TEST_KDF).unwrap();
+    assert!(is_encrypted(&*inner).unwrap());
+
+    // Data spanning several segments, and exactly one segment
+    let data = test_data(150_000);
+    store.put("big", &data).unwrap();
+    store.put("whole", &data[..65536]).unwrap();
+    store.put("empty", b"").unwrap();
+    let sealed = inner.get("big", None).unwrap();
+    assert!(sealed.len() > data.len() && !sealed.windows(64).any(|w| w == &data[1000..1064]));
+
+    assert!(store.get("big", None).unwrap() == data);
+    assert!(store.get("whole", None).unwrap() == data[..65536]);
+    assert!(store.get("empty", None).unwrap().is_empty());
+    assert!(store.get("big", Some(65000..70000)).unwrap() == data[65000..70000]);
+    assert!(store.get("big", Some(140_000..200_000)).unwrap() == data[140_000..]);
+    assert!(store.get("whole", Some(65530..70000)).unwrap() == data[65530..65536]);
+    assert!(store.get("big", Some(200_000..300_000)).unwrap().is_empty());
+
+    let (read, _) = store.get_tagged("big").unwrap();
+    assert!(read == data);
+    store.copy("big", Some(10..20), "copy").unwrap();
+    assert!(store.get("copy", None).unwrap() == data[10..20]);
+
+    let listed: Vec<(String, u64)> = store
+        .list("")
+        .unwrap()
+        .into_iter()
+        .map(|object| (object.key, object.size))
+        .collect();
+    let expected = [
+        ("big", 150_000),
+        ("copy", 10),
+        ("empty", 0),
+        ("whole", 65536),
+    ];
+    assert!(listed.len() == 4);
+    for ((key, size), (expected_key, expected_size)) in listed.iter().zip(expected) {
+        assert!(key == expected_key && *size == expected_size);
+    }
+
+    // The data key is unlocked again with the passphrase only
+    let store = EncryptedStore::open(inner.clone(), "secret", &TEST_KDF).unwrap();
+    assert!(store.get("big", None).unwrap() == data);
+    let wrong = EncryptedStore::open(inner.clone(), "guess", &TEST_KDF);
+    assert!(wrong.is_err());
+
+    // Unencrypted objects are never mixed with encrypted ones
+    let plain = Arc::new(MemoryStore::new());
+    plain.put("foo", b"bar").unwrap();
+    assert!(EncryptedStore::open(plain.clone(), "secret", &TEST_KDF).is_err());
+    assert!(!is_encrypted(&*plain).unwrap());
+}
+
+#[test]
+fn encrypted_store_tampering() {
+    let inner = Arc::new(MemoryStore::new());
+    let store = EncryptedStore::open(inner.clone(), "secret", &TEST_KDF).unwrap();
+    let data = test_data(150_000);
+    store.put("foo", &data).unwrap();
+    store.put("bar", b"bar").unwrap();
+    let sealed = inner.get("foo", None).unwrap();
+
+    let failed = |result: Result<Vec<u8>, BackendError>| {
+        result.is_err_and(|err| err.errno() == libc::EIO && !err.is_transient())
+    };
+
+    let mut flipped = sealed.clone();
+    flipped[70000] ^= 1;
+    inner.put("foo", &flipped).unwrap();
+    assert!(failed(store.get("foo", None)));
+    assert!(failed(store.get("foo", Some(65536..65540))));
+    assert!(store.get("foo", Some(0..10)).unwrap() == data[..10]);
+
+    // Objects moved to another key, or cut short at a segment boundary
+    inner.put("bar", &sealed).unwrap();
+    assert!(failed(store.get("bar", None)));
+    inner.put("foo", &sealed[..2 * (65536 + 28)]).unwrap();
+    assert!(failed(store.get("foo", None)));
+    assert!(failed(store.get("foo", Some(0..150_000))));
+    inner.put("foo", &sealed[..10]).unwrap();
+    assert!(failed(store.get("foo", None)));
+}
+
+#[test]
+fn fuse_encrypted() {
+    let inner = Arc::new(MemoryStore::new());
+    let store = Arc::new(EncryptedStore::open(inner.clone(), "secret", &TEST_KDF).unwrap());
+    let data = test_data(40);
+    let mut fs = S3TMFS::new(store.clone(), test_config());
+    fs.fuse_init().unwrap();
+    let rc = fs
+        .fuse_create(FUSE_ROOT_ID, OsStr::new("foo"), 0o644, 0, 0)
+        .unwrap();
+    fs.fuse_write(rc.attr.ino, rc.fh, 0, &data, 0, 0, None)
+        .unwrap();
+    fs.fuse_release(rc.attr.ino, rc.fh, 0, None, false).unwrap();
+    fs.fuse_destroy();
+
+    // Neither file names nor contents are stored in the clear
+    for object in inner.list("").unwrap() {
+        let sealed = inner.get(&object.key, None).unwrap();
+        assert!(!sealed.windows(3).any(|w| w == b"foo"));
+        assert!(!sealed.windows(8).any(|w| data.windows(8).any(|d| d == w)));
+    }
+
+    let mut fs = S3TMFS::new(store, test_config());
+    fs.fuse_init().unwrap();
+    let ino = lookup_ino(&mut fs, FUSE_ROOT_ID, "foo");
+    let rd = fs.fuse_read(ino, 1, 0, 100, 0, None).unwrap();
+    assert!(rd.data == &data[..]);
+}
+
+#[test]
+fn encrypted_store_key_slots() {
+    let inner = Arc::new(MemoryStore::new());
+    let store = EncryptedStore::open(inner.clone(), "secret", &TEST_KDF).unwrap();
+    store.put("foo", b"bar").unwrap();
+
+    store.add_slot("alice", "other", &TEST_KDF).unwrap();
+    assert!(store.add_slot("alice", "third", &TEST_KDF).is_err());
+    assert!(store.slots().unwrap() == ["default", "alice"]);
+    let other = EncryptedStore::open(inner.clone(), "other", &TEST_KDF).unwrap();
+    assert!(other.get("foo", None).unwrap() == b"bar");
+
+    // A passphrase removed no longer unlocks the bucket, the others still do
+    other.remove_slot("default").unwrap();
+    assert!(EncryptedStore::open(inner.clone(), "secret", &TEST_KDF).is_err());
+    let other = EncryptedStore::open(inner.clone(), "other", &TEST_KDF).unwrap();
+    assert!(other.get("foo", None).unwrap() == b"bar");
+    assert!(other.remove_slot("default").is_err());
+    assert!(other.remove_slot("alice").is_err());
+    assert!(other.slots().unwrap() == ["alice"]);
+}
+
+#[test]
+fn encrypted_store_rekey() {
+    let inner = Arc::new(MemoryStore::new());
+    let store = EncryptedStore::open(inner.clone(), "secret", &TEST_KDF).unwrap();
+    let data = test_data(100_000);
+    store.put("old", &data).unwrap();
+    store.put("kept", b"kept").unwrap();
+    let sealed = inner.get("old", None).unwrap();
+
+    // Objects written before a rotation stay readable
+    store.rotate().unwrap();
+    store.put("new", b"new").unwrap();
+    assert!(store.get("old", None).unwrap() == data);
+    assert!(store.get("old", Some(70000..70010)).unwrap() == data[70000..70010]);
+    let reopened = EncryptedStore::open(inner.clone(), "secret", &TEST_KDF).unwrap();
+    assert!(reopened.get("new", None).unwrap() == b"new");
+    assert!(reopened.get("old", None).unwrap() == data);
+
+    // Only the objects under the old key are written again
+    assert!(store.rekey().unwrap() == 2);
+    assert!(inner.get("old", None).unwrap() != sealed);
+    assert!(store.rekey().unwrap() == 0);
+
+    // The old key is gone, so objects still encrypted with it can't be read
+    inner.put("stale", &sealed).unwrap();
+    let reopened = EncryptedStore::open(inner.clone(), "secret", &TEST_KDF).unwrap();
+    assert!(reopened.get("old", None).unwrap() == data);
+    assert!(reopened.get("kept", None).unwrap() == b"kept");
+    assert!(reopened.get("new", None).unwrap() == b"new");
+    assert!(reopened.get("stale", None).is_err());
+}
+
+// Keys versions are encrypted with are kept, so pinned snapshots still read
+#[test]
+fn encrypted_store_rekey_versions() {
+    let inner = Arc::new(MemoryStore::versioned());
+    let store = EncryptedStore::open(inner.clone(), "secret", &TEST_KDF).unwrap();
+    store.put("foo", b"first").unwrap();
+    store.put("bar", b"bar").unwrap();
+    let first = store.version("foo").unwrap();
+    store.rotate().unwrap();
+    store.rotate().unwrap();
+
+    assert!(store.rekey().unwrap() == 2);
+    let reopened = EncryptedStore::open(inner.clone(), "secret", &TEST_KDF).unwrap();
+    assert!(reopened.get_version("foo", &first, None).unwrap() == b"first");
+    assert!(reopened.get("bar", None).unwrap() == b"bar");
+
+    // The key no version is encrypted with is dropped
+    let header: serde_json::Value =
+        serde_json::from_slice(&inner.get("keyheader", None).unwrap()).unwrap();
+    assert!(header["data_keys"].as_array().unwrap().len() == 2);
+}
+
+#[test]
+fn fuse_encrypted_rekey() {
+    let inner = Arc::new(MemoryStore::new());
+    let store = Arc::new(EncryptedStore::open(inner.clone(), "secret", &TEST_KDF).unwrap());
+    let data = test_data(40);
+    let mut fs = S3TMFS::new(store.clone(), test_config());
+    fs.fuse_init().unwrap();
+    let rc = fs
+        .fuse_create(FUSE_ROOT_ID, OsStr::new("foo"), 0o644, 0, 0)
+        .unwrap();
+    fs.fuse_write(rc.attr.ino, rc.fh, 0, &data, 0, 0, None)
+        .unwrap();
+    fs.fuse_release(rc.attr.ino, rc.fh, 0, None, false).unwrap();
+
+    // The filesystem stays usable while its objects are encrypted again
+    store.rotate().unwrap();
+    let rekeyer = {
+        let store = store.clone();
+        std::thread::spawn(move || store.rekey().unwrap())
+    };
+    let rc = fs
+        .fuse_create(FUSE_ROOT_ID, OsStr::new("bar"), 0o644, 0, 0)
+        .unwrap();
+    fs.fuse_write(rc.attr.ino, rc.fh, 0, &data, 0, 0, None)
+        .unwrap();
+    fs.fuse_release(rc.attr.ino, rc.fh, 0, None, false).unwrap();
+    rekeyer.join().unwrap();
+    fs.fuse_destroy();
+
+    let store = Arc::new(EncryptedStore::open(inner, "secret", &TEST_KDF).unwrap());
+    let mut fs = S3TMFS::new(store, test_config());
+    fs.fuse_init().unwrap();
+    for name in ["foo", "bar"] {
+        let ino = lookup_ino(&mut fs, FUSE_ROOT_ID, name);
+        let rd = fs.fuse_read(ino, 1, 0, 100, 0, None).unwrap();
+        assert!(rd.data == &data[..]);
+    }
+}
+
+#[test]
+fn compress_parse_codec() {
+    assert!(parse_codec("lz4") == Ok(Codec::Lz4));
+    assert!(parse_codec("zstd") == Ok(Codec::Zstd(3)));
+    assert!(parse_codec("zstd:19") == Ok(Codec::Zstd(19)));
+    assert!(parse_codec("zstd:99").is_err());
+    assert!(parse_codec("lz4:1").is_err());
+    assert!(parse_codec("gzip").is_err());
+}
+
+#[test]
+fn compressed_store_round_trip() {
+    let mut random = vec![0; 1000];
+    fastrand::fill(&mut random);
+    let padded: Vec<u8> = test_data(1000).into_iter().chain([0; 3000]).collect();
+
+    for codec in [Codec::Zstd(3), Codec::Lz4] {
+        let inner = Arc::new(MemoryStore::new());
+        let store = CompressedStore::new(inner.clone(), Some(codec));
+        store.put("bands/1/0", &padded).unwrap();
+        store.put("bands/1/1", &random).unwrap();
+        store.put("metadata", &padded).unwrap();
+        assert!(inner.get("bands/1/0", None).unwrap().len() < 1000);
+        assert!(inner.get("bands/1/1", None).unwrap().len() > 1000);
+        assert!(inner.get("metadata", None).unwrap() == padded);
+
+        assert!(store.get("bands/1/0", None).unwrap() == padded);
+        assert!(store.get("bands/1/0", Some(990..1010)).unwrap() == padded[990..1010]);
+        assert!(store.get("bands/1/0", Some(3990..5000)).unwrap() == padded[3990..]);
+        assert!(store.get("bands/1/1", Some(10..20)).unwrap() == random[10..20]);
+        assert!(store.get_tagged("bands/1/0").unwrap().0 == padded);
+        assert!(store.get("metadata", Some(0..10)).unwrap() == padded[..10]);
+
+        store.copy("bands/1/0", None, "bands/2/0").unwrap();
+        store.copy("bands/1/0", Some(0..100), "bands/2/1").unwrap();
+        store.copy("metadata", Some(0..100), "bands/2/2").unwrap();
+        assert!(store.get("bands/2/0", None).unwrap() == padded);
+        assert!(store.get("bands/2/1", None).unwrap() == padded[..100]);
+        assert!(store.get("bands/2/2", None).unwrap() == padded[..100]);
+
+        // Bands read the same without a codec to write with
+        let plain = CompressedStore::new(inner.clone(), None);
+        assert!(plain.get("bands/1/0", Some(0..4000)).unwrap() == padded);
+        let stats = store.stats().report();
+        assert!(stats.starts_with("compressed 5200 bytes of bands to "));
+    }
+
+    // Bands stored before compression was enabled read as they are, and
+    // those looking compressed are stored so they aren't taken for it
+    let inner = Arc::new(MemoryStore::new());
+    let plain = CompressedStore::new(inner.clone(), None);
+    plain.put("bands/1/0", &padded).unwrap();
+    plain
+        .put("bands/1/1", b"S3Z\x01\x01\0\0\0\0\0\0\0\0 looks compressed")
+        .unwrap();
+    assert!(inner.get("bands/1/0", None).unwrap() == padded);
+    let store = CompressedStore::new(inner.clone(), Some(Codec::Lz4));
+    assert!(store.get("bands/1/0", Some(0..10)).unwrap() == padded[..10]);
+    assert!(store
+        .get("bands/1/1", None)
+        .unwrap()
+        .ends_with(b"looks compressed"));
+
+    // Corrupt objects fail to read
+    let mut stored = inner.get("bands/1/1", None).unwrap();
+    stored.truncate(stored.len() - 3);
+    stored[4] = 1;
+    inner.put("bands/1/1", &stored).unwrap();
+    assert!(store.get("bands/1/1", None).is_err());
+}
+
+// Memory store recording the ranges read from it, None for whole objects,
+// and the bytes fetched
+#[derive(Default)]
+struct RangeRecordingStore {
+    reads: std::sync::Mutex<Vec<Option<Range<u64>>>>,
+    fetched: AtomicU64,
+    inner: MemoryStore,
+}
+
+impl RangeRecordingStore {
+    fn whole_reads(&self) -> usize {
+        let reads = std::mem::take(&mut *self.reads.lock().unwrap());
+        reads.iter().filter(|range| range.is_none()).count()
+    }
+
+    fn fetched(&self) -> u64 {
+        self.fetched.swap(0, Ordering::SeqCst)
+    }
+}
+
+impl ObjectStore for RangeRecordingStore {
+    fn get(&self, key: &str, range: Option<Range<u64>>) -> Result<Vec<u8>, BackendError> {
+        self.reads.lock().unwrap().push(range.clone());
+        let data = self.inner.get(key, range)?;
+        self.fetched.fetch_add(data.len() as u64, Ordering::SeqCst);
+        Ok(data)
+    }
+
+    fn put(&self, key: &str, data: &[u8]) -> Result<(), BackendError> {
+        self.inner.put(key, data)
+    }
+
+    fn get_tagged(&self, key: &str) -> Result<(Vec<u8>, String), BackendError> {
+        self.reads.lock().unwrap().push(None);
+        let (data, etag) = self.inner.get_tagged(key)?;
+        self.fetched.fetch_add(data.len() as u64, Ordering::SeqCst);
+        Ok((data, etag))
+    }
+
+    fn put_if(&self, key: &str, data: &[u8], etag: Option<&str>) -> Result<String, BackendError> {
+        self.inner.put_if(key, data, etag)
+    }
+
+    fn delete(&self, key: &str) -> Result<(), BackendError> {
+        self.inner.delete(key)
+    }
+
+    fn copy(&self, from: &str, range: Option<Range<u64>>, to: &str) -> Result<(), BackendError> {
+        self.inner.copy(from, range, to)
+    }
+
+    fn list(&self, prefix: &str) -> Result<Vec<ObjectInfo>, BackendError> {
+        self.inner.list(prefix)
+    }
+}
+
+// Ranges of bands which aren't compressed are read without the rest
+#[test]
+fn compressed_store_ranged_reads() {
+    let inner = Arc::new(RangeRecordingStore::default());
+    let store = CompressedStore::new(inner.clone(), None);
+    let data = test_data(4000);
+    let mut looking_compressed = b"S3Z\x01".to_vec();
+    looking_compressed.extend_from_slice(&data);
+    store.put("bands/1/0", &data).unwrap();
+    store.put("bands/1/1", &looking_compressed).unwrap();
+
+    for (key, data) in [("bands/1/0", &data), ("bands/1/1", &looking_compressed)] {
+        assert!(store.get(key, Some(1000..1010)).unwrap() == data[1000..1010]);
+        assert!(store.get(key, Some(0..10)).unwrap() == data[..10]);
+        assert!(store.get(key, Some(3990..5000)).unwrap() == data[3990..]);
+        assert!(store.get(key, Some(0..5000)).unwrap() == *data);
+        assert!(inner.whole_reads() == 0);
+    }
+
+    // Compressed bands are fetched whole
+    let store = CompressedStore::new(inner.clone(), Some(Codec::Lz4));
+    store.put("bands/1/2", &[0; 4000]).unwrap();
+    assert!(store.get("bands/1/2", Some(0..10)).unwrap() == [0; 10]);
+    assert!(store.get("bands/1/2", Some(1000..1010)).unwrap() == [0; 10]);
+    assert!(inner.whole_reads() == 2);
+}
+
+#[test]
+fn fuse_compressed() {
+    let inner = Arc::new(MemoryStore::new());
+    let store = Arc::new(CompressedStore::new(inner.clone(), Some(Codec::Zstd(3))));
+    let mut config = test_config();
+    config.band_size = 4096;
+    let data = test_data(5000);
+    let mut fs = S3TMFS::new(store.clone(), config);
+    fs.fuse_init().unwrap();
+    let rc = fs
+        .fuse_create(FUSE_ROOT_ID, OsStr::new("foo"), 0o644, 0, 0)
+        .unwrap();
+    fs.fuse_write(rc.attr.ino, rc.fh, 0, &data, 0, 0, None)
+        .unwrap();
+    fs.fuse_release(rc.attr.ino, rc.fh, 0, None, false).unwrap();
+    fs.fuse_destroy();
+
+    let stored: u64 = inner
+        .list("bands/")
+        .unwrap()
+        .iter()
+        .map(|object| object.size)
+        .sum();
+    assert!(stored < 1000);
+    assert!(store.stats().bytes_in.load(Ordering::Relaxed) == 5000);
+
+    // Compressed bands are read back whatever the codec
+    let mut config = test_config();
+    config.band_size = 4096;
+    let mut fs = S3TMFS::new(Arc::new(CompressedStore::new(inner, None)), config);
+    fs.fuse_init().unwrap();
+    let ino = lookup_ino(&mut fs, FUSE_ROOT_ID, "foo");
+    let rd = fs.fuse_read(ino, 1, 4000, 1000, 0, None).unwrap();
+    assert!(rd.data == &data[4000..]);
+}
+
+// Keys of the chunks a store holds
+fn chunk_keys(store: &MemoryStore) -> Vec<String> {
+    store
+        .list("chunks/")
+        .unwrap()
+        .into_iter()
+        .map(|object| object.key)
+        .collect()
+}
+
+#[test]
+fn dedup_parse_chunk_size() {
+    let sizes = parse_chunk_size("65536").unwrap();
+    assert!(sizes == ChunkSizes::default());
+    assert!(sizes.min == 16384 && sizes.max == 262144);
+    assert!(parse_chunk_size("256").is_ok());
+    assert!(parse_chunk_size("100").is_err());
+    assert!(parse_chunk_size("64k").is_err());
+}
+
+// Ranges of bands stored whole are read without the rest, and without
+// looking for a chunk list unless chunks are stored
+#[test]
+fn dedup_store_ranged_reads() {
+    let inner = Arc::new(RangeRecordingStore::default());
+    let data = test_data(20000);
+    let plain = DedupStore::new(inner.clone(), inner.clone(), None, None, None);
+    plain.put("bands/1/0", &data).unwrap();
+    assert!(plain.get("bands/1/0", Some(1000..1010)).unwrap() == data[1000..1010]);
+    assert!(inner.reads.lock().unwrap().len() == 1);
+    assert!(inner.whole_reads() == 0);
+    // Without chunks, marking them reads no band
+    assert!(plain.unreferenced(&[]).unwrap().is_empty());
+    assert!(inner.reads.lock().unwrap().is_empty());
+
+    let sizes = parse_chunk_size("1024").unwrap();
+    let store = DedupStore::new(inner.clone(), inner.clone(), None, Some(sizes), None);
+    store.put("bands/1/1", &data).unwrap();
+    let plain = DedupStore::new(inner.clone(), inner.clone(), None, None, None);
+    for store in [&store, &plain] {
+        assert!(store.get("bands/1/0", Some(1000..1010)).unwrap() == data[1000..1010]);
+        assert!(store.get("bands/1/0", Some(0..10)).unwrap() == data[..10]);
+        assert!(inner.whole_reads() == 0);
+        assert!(store.get("bands/1/1", Some(1000..1010)).unwrap() == data[1000..1010]);
+        assert!(store.get("bands/1/1", Some(0..30000)).unwrap() == data);
+        inner.whole_reads();
+    }
+
+    // Marking chunks reads only the chunk lists whole
+    assert!(store.unreferenced(&[]).unwrap().is_empty());
+    assert!(inner.whole_reads() == 1);
+    store.end_marking();
+}
+
+#[test]
+fn dedup_store_round_trip() {
+    let inner = Arc::new(MemoryStore::new());
+    let sizes = parse_chunk_size("1024").unwrap();
+    let store = DedupStore::new(inner.clone(), inner.clone(), None, Some(sizes), None);
+    let mut data = vec![0; 20000];
+    fastrand::Rng::with_seed(1).fill(&mut data);
+
+    // The same contents at another offset make mostly the same chunks
+    store.put("bands/1/0", &data).unwrap();
+    let chunks = chunk_keys(&inner).len();
+    assert!(chunks > 5);
+    store.put("bands/2/0", &data[100..]).unwrap();
+    assert!(chunk_keys(&inner).len() < chunks + 3);
+    assert!(inner.get("bands/1/0", None).unwrap().len() < 2000);
+    assert!(store.list("").unwrap().len() == 2);
+
+    assert!(store.get("bands/1/0", None).unwrap() == data);
+    assert!(store.get("bands/2/0", None).unwrap() == data[100..]);
+    assert!(store.get("bands/1/0", Some(5000..9000)).unwrap() == data[5000..9000]);
+    assert!(store.get("bands/1/0", Some(19990..30000)).unwrap() == data[19990..]);
+    assert!(store.get_tagged("bands/2/0").unwrap().0 == data[100..]);
+
+    // Whole bands are copied sharing their chunks
+    store.copy("bands/1/0", None, "bands/3/0").unwrap();
+    store.copy("bands/1/0", Some(10..20), "bands/3/1").unwrap();
+    assert!(inner.get("bands/3/0", None).unwrap() == inner.get("bands/1/0", None).unwrap());
+    assert!(store.get("bands/3/1", None).unwrap() == data[10..20]);
+
+    // Bands written whole read as they are, and other objects are never split
+    let plain = DedupStore::new(inner.clone(), inner.clone(), None, None, None);
+    plain.put("bands/4/0", b"whole").unwrap();
+    plain.put("bands/4/1", b"S3C\x01{\"chunks\":[]}").unwrap();
+    store.put("metadata", &data).unwrap();
+    assert!(inner.get("bands/4/0", None).unwrap() == b"whole");
+    assert!(inner.get("metadata", None).unwrap() == data);
+    assert!(store.get("bands/4/0", Some(1..3)).unwrap() == b"ho");
+    assert!(store.get("bands/4/1", None).unwrap() == b"S3C\x01{\"chunks\":[]}");
+    assert!(plain.get("bands/2/0", None).unwrap() == data[100..]);
+
+    // A missing chunk fails the read rather than reading as a hole
+    for chunk in chunk_keys(&inner) {
+        inner.delete(&chunk).unwrap();
+    }
+    assert!(store.get("bands/1/0", None).is_err());
+}
+
+#[test]
+fn dedup_store_unreferenced() {
+    let inner = Arc::new(MemoryStore::new());
+    let sizes = parse_chunk_size("256").unwrap();
+    let store = DedupStore::new(inner.clone(), inner.clone(), None, Some(sizes), None);
+    let mut data = vec![0; 4000];
+    fastrand::Rng::with_seed(2).fill(&mut data);
+    store.put("bands/1/0", &data[..2000]).unwrap();
+    store.put("bands/2/0", &data[2000..]).unwrap();
+    assert!(store.unreferenced(&[]).unwrap().is_empty());
+
+    // Chunks only referred to by bands about to be deleted
+    let garbage = store.unreferenced(&["bands/2/0".to_string()]).unwrap();
+    assert!(!garbage.is_empty());
+    store.delete("bands/2/0").unwrap();
+
+    // Chunks referred to again since are kept
+    store.put("bands/3/0", &data[3000..]).unwrap();
+    for chunk in &garbage {
+        store.delete(&chunk.key).unwrap();
+    }
+    store.end_marking();
+    assert!(store.get("bands/1/0", None).unwrap() == data[..2000]);
+    assert!(store.get("bands/3/0", None).unwrap() == data[3000..]);
+    assert!(chunk_keys(&inner).len() < 16);
+
+    // Deleted chunks are written again when needed
+    store.put("bands/4/0", &data[2000..]).unwrap();
+    assert!(store.get("bands/4/0", None).unwrap() == data[2000..]);
+}
+
+#[test]
+fn dedup_shared_chunks() {
+    let chunks = Arc::new(MemoryStore::new());
+    let images = [Arc::new(MemoryStore::new()), Arc::new(MemoryStore::new())];
+    let sizes = parse_chunk_size("256").unwrap();
+    let mut data = vec![0; 4000];
+    fastrand::Rng::with_seed(3).fill(&mut data);
+    for image in &images {
+        let store = DedupStore::new(
+            image.clone(),
+            image.clone(),
+            Some(chunks.clone()),
+            Some(sizes),
+            None,
+        );
+        store.put("bands/1/0", &data).unwrap();
+        // Chunks shared with other filesystems aren't collected with either
+        assert!(store
+            .unreferenced(&["bands/1/0".to_string()])
+            .unwrap()
+            .is_empty());
+    }
+    let stored = chunk_keys(&chunks);
+    assert!(chunk_keys(&images[0]).is_empty());
+
+    let mut marked = std::collections::HashSet::new();
+    images[0].delete("bands/1/0").unwrap();
+    mark_chunks(&*images[0], &Default::default(), &mut marked).unwrap();
+    let listed = chunks.list(CHUNK_PREFIX).unwrap();
+    assert!(unmarked_chunks(listed.clone(), &marked).len() == stored.len());
+    mark_chunks(&*images[1], &Default::default(), &mut marked).unwrap();
+    assert!(unmarked_chunks(listed, &marked).is_empty());
+}
+
+#[test]
+fn dedup_encrypted_chunk_names() {
+    let sizes = parse_chunk_size("256").unwrap();
+    let mut data = vec![0; 4000];
+    fastrand::Rng::with_seed(4).fill(&mut data);
+    let plain = Arc::new(MemoryStore::new());
+    DedupStore::new(plain.clone(), plain.clone(), None, Some(sizes), None)
+        .put("bands/1/0", &data)
+        .unwrap();
+
+    // Chunks of encrypted buckets aren't named after the SHA-256 of their
+    // data, which would tell who has the data which bands hold it
+    let inner = Arc::new(MemoryStore::new());
+    let encrypted = Arc::new(EncryptedStore::open(inner.clone(), "secret", &TEST_KDF).unwrap());
+    let key = encrypted.chunk_name_key();
+    let store = DedupStore::new(
+        encrypted.clone(),
+        encrypted.clone(),
+        None,
+        Some(sizes),
+        Some(key),
+    );
+    store.put("bands/1/0", &data).unwrap();
+    let names = chunk_keys(&inner);
+    assert!(names.len() == chunk_keys(&plain).len());
+    assert!(names.iter().all(|name| !chunk_keys(&plain).contains(name)));
+    assert!(store.get("bands/1/0", None).unwrap() == data);
+
+    // The key stays the same for any passphrase and data key, so the same
+    // data still makes the same chunks
+    encrypted.add_slot("other", "other", &TEST_KDF).unwrap();
+    encrypted.rotate().unwrap();
+    let other = EncryptedStore::open(inner.clone(), "other", &TEST_KDF).unwrap();
+    assert!(other.chunk_name_key() == key);
+    let other = Arc::new(other);
+    let store = DedupStore::new(other.clone(), other, None, Some(sizes), Some(key));
+    store.put("bands/2/0", &data).unwrap();
+    assert!(chunk_keys(&inner) == names);
+}
+
+#[test]
+fn fuse_dedup_gc() {
+    let inner = Arc::new(RangeRecordingStore::default());
+    let compressed = Arc::new(CompressedStore::new(inner.clone(), Some(Codec::Zstd(3))));
+    let store = Arc::new(DedupStore::new(
+        compressed,
+        inner.clone(),
+        None,
+        Some(ChunkSizes::default()),
+        None,
+    ));
+    let mut fs = S3TMFS::new(store.clone(), test_config());
+    fs.set_dedup(store.clone());
+    fs.fuse_init().unwrap();
+
+    // Files with the same contents share the chunks of their bands
+    let data = test_data(40);
+    let mut inos = Vec::new();
+    for name in ["foo", "bar"] {
+        let rc = fs
+            .fuse_create(FUSE_ROOT_ID, OsStr::new(name), 0o644, 0, 0)
+            .unwrap();
+        fs.fuse_write(rc.attr.ino, rc.fh, 0, &data, 0, 0, None)
+            .unwrap();
+        fs.fuse_release(rc.attr.ino, rc.fh, 0, None, false).unwrap();
+        inos.push(rc.attr.ino);
+    }
+    fs.wait_idle();
+    assert!(chunk_keys(&inner.inner).len() == 3);
+    let rd = fs.fuse_read(inos[1], 1, 0, 100, 0, None).unwrap();
+    assert!(rd.data == &data[..]);
+
+    let retention = Retention {
+        hourly: Duration::ZERO,
+        daily: Duration::ZERO,
+        monthly: Duration::ZERO,
+    };
+    // Removing a file deletes its band objects, the chunks stay while
+    // another band refers to them
+    fs.fuse_unlink(FUSE_ROOT_ID, OsStr::new("foo")).unwrap();
+    fs.wait_idle();
+    inner.whole_reads();
+    inner.fetched();
+    let garbage = fs.collect_garbage(&retention).unwrap();
+    assert!(garbage.objects.is_empty() && garbage.chunks.is_empty());
+
+    // Marking reads the chunk lists once as stored, without decompressing
+    // anything
+    let lists = inner.inner.list("bands/").unwrap();
+    let stored: u64 = lists.iter().map(|list| list.size).sum();
+    assert!(inner.whole_reads() == lists.len());
+    assert!(inner.fetched() == stored + 4 * lists.len() as u64);
+
+    fs.fuse_unlink(FUSE_ROOT_ID, OsStr::new("bar")).unwrap();
+    fs.wait_idle();
+    let garbage = fs.collect_garbage(&retention).unwrap();
+    assert!(garbage.chunks.len() == 3);
+    assert!(garbage
+        .report()
+        .contains("0 objects and 3 chunks unreferenced"));
+    garbage.sweep(&*store).unwrap();
+    store.end_marking();
+    assert!(chunk_keys(&inner.inner).is_empty());
+}
@@ -0,0 +1,255 @@
+use crate::backend::ObjectStore;
+use crate::cache::{BandCache, BandId, Generations, StoredBands};
+use crate::retry::RetryPolicy;
+
+use std::collections::{HashMap, HashSet};
+use std::ops::Range;
+use std::sync::mpsc::{channel, Receiver, Sender};
+use std::sync::{Arc, Condvar, Mutex, MutexGuard};
+use std::thread::{self, JoinHandle};
+
+#[derive(Default)]
+struct State {
+    // Bands waiting for a worker
+    queued: HashSet<BandId>,
+    // Bands being uploaded
+    uploading: HashSet<BandId>,
+    // Bands written again while being uploaded, to upload once more afterwards
+    redirtied: HashSet<BandId>,
+    // Errors of failed uploads by inode, reported on the next flush
+    errors: HashMap<u64, i32>,
+}
+
+impl State {
+    fn busy(&self, ino: u64) -> bool {
+        self.queued
+            .iter()
+            .chain(self.uploading.iter())
+            .any(|id| id.0 == ino)
+    }
+}
+
+type Shared = Arc<(Mutex<State>, Condvar)>;
+
+// Uploads dirty bands from the cache using a pool of worker threads
+pub struct Uploader {
+    cache: Arc<BandCache>,
+    max_dirty_bytes: u64,
+    shared: Shared,
+    sender: Option<Sender<BandId>>,
+    workers: Vec<JoinHandle<()>>,
+}
+
+impl Uploader {
+    // An uploader without workers never uploads anything, as for a read-only
+    // mount
+    pub fn new(
+        store: Arc<dyn ObjectStore>,
+        cache: Arc<BandCache>,
+        generations: Arc<Generations>,
+        stored: Arc<StoredBands>,
+        concurrency: usize,
+        max_dirty_bytes: u64,
+        retry: RetryPolicy,
+    ) -> Uploader {
+        let (sender, receiver) = channel::<BandId>();
+        let sender = (concurrency > 0).then_some(sender);
+        let receiver = Arc::new(Mutex::new(receiver));
+        let shared: Shared = Arc::new((Mutex::new(State::default()), Condvar::new()));
+
+        let workers = (0..concurrency)
+            .map(|_| {
+                let store = store.clone();
+                let cache = cache.clone();
+                let generations = generations.clone();
+                let stored = stored.clone();
+                let receiver = receiver.clone();
+                let shared = shared.clone();
+                let retry = retry.clone();
+                thread::spawn(move || {
+                    worker(store, cache, generations, stored, receiver, shared, retry)
+                })
+            })
+            .collect();
+
+        Uploader {
+            cache,
+            max_dirty_bytes,
+            shared,
+            sender,
+            workers,
+        }
+    }
+
+    fn lock(&self) -> MutexGuard<'_, State> {
+        self.shared.0.lock().unwrap()
+    }
+
+    fn wait<'a>(&self, state: MutexGuard<'a, State>) -> MutexGuard<'a, State> {
+        self.shared.1.wait(state).unwrap()
+    }
+
+    // Queue a band for upload. A band already queued is not queued twice, and
+    // a band being uploaded is uploaded again once the upload completes.
+    pub fn schedule(&self, id: BandId) {
+        let Some(sender) = &self.sender else {
+            return;
+        };
+        let mut state = self.lock();
+        if state.uploading.contains(&id) {
+            state.redirtied.insert(id);
+        } else if state.queued.insert(id) {
+            sender.send(id).unwrap();
+        }
+    }
+
+    // Block while there is more data waiting to be uploaded than allowed.
+    // Only failed uploads of the file being written are reported, others are
+    // left for the flush of their own file.
+    pub fn throttle(&self, ino: u64) -> Result<(), i32> {
+        if self.cache.dirty_bytes() <= self.max_dirty_bytes {
+            return Ok(());
+        }
+
+        for id in self.cache.all_dirty() {
+            self.schedule(id);
+        }
+
+        let mut state = self.lock();
+        while self.cache.dirty_bytes() > self.max_dirty_bytes {
+            if let Some(errno) = state.errors.remove(&ino) {
+                return Err(errno);
+            }
+            if state.queued.is_empty() && state.uploading.is_empty() {
+                break;
+            }
+            state = self.wait(state);
+        }
+        Ok(())
+    }
+
+    // Upload the dirty bands of a file and wait for the uploads to complete
+    pub fn flush(&self, ino: u64) -> Result<(), i32> {
+        self.lock().errors.remove(&ino);
+        for id in self.cache.dirty_bands(ino) {
+            self.schedule(id);
+        }
+
+        let mut state = self.lock();
+        while state.busy(ino) {
+            state = self.wait(state);
+        }
+        match state.errors.remove(&ino) {
+            Some(errno) => Err(errno),
+            None => Ok(()),
+        }
+    }
+
+    // Drop pending uploads of a range of bands of a file, waiting for those
+    // in progress
+    pub fn forget_bands(&self, ino: u64, bands: Range<u64>) {
+        let dropped = |id: &BandId| id.0 == ino && bands.contains(&id.1);
+        let mut state = self.lock();
+        state.queued.retain(|id| !dropped(id));
+        state.redirtied.retain(|id| !dropped(id));
+        while state.uploading.iter().any(dropped) {
+            state = self.wait(state);
+        }
+    }
+
+    // Drop pending uploads of a deleted file, waiting for those in progress
+    pub fn forget(&self, ino: u64) {
+        self.forget_bands(ino, 0..u64::MAX);
+        self.lock().errors.remove(&ino);
+    }
+
+    // Wait for all queued uploads to complete
+    pub fn wait_idle(&self) {
+        let mut state = self.lock();
+        while !state.queued.is_empty() || !state.uploading.is_empty() {
+            state = self.wait(state);
+        }
+    }
+}
+
+impl Drop for Uploader {
+    fn drop(&mut self) {
+        self.sender = None;
+        for worker in self.workers.drain(..) {
+            worker.join().unwrap();
+        }
+    }
+}
+
+fn worker(
+    store: Arc<dyn ObjectStore>,
+    cache: Arc<BandCache>,
+    generations: Arc<Generations>,
+    stored: Arc<StoredBands>,
+    receiver: Arc<Mutex<Receiver<BandId>>>,
+    shared: Shared,
+    retry: RetryPolicy,
+) {
+    let (lock, done) = &*shared;
+    loop {
+        let id = match receiver.lock().unwrap().recv() {
+            Ok(id) => id,
+            Err(_) => return,
+        };
+
+        {
+            let mut state = lock.lock().unwrap();
+            // Uploads of deleted files are dropped from the queue
+            if !state.queued.remove(&id) {
+                continue;
+            }
+            state.uploading.insert(id);
+        }
+
+        let mut attempt = 0;
+        loop {
+            let result = match cache.snapshot(id) {
+                Some((data, version)) => {
+                    // Bands of zeros read the same without an object, so
+                    // sparse images take no space in the store
+                    let key = generations.key(id);
+                    match data.iter().all(|byte| *byte == 0) {
+                        true => store.delete(&key).map(|_| stored.remove(id.0, &key)),
+                        false => store.put(&key, &data).map(|_| stored.insert(id.0, key)),
+                    }
+                    .map(|_| cache.mark_clean(id, version))
+                }
+                None => Ok(()),
+            };
+
+            let mut state = lock.lock().unwrap();
+            match result {
+                // Written again during the upload, so upload it once more
+                Ok(()) if state.redirtied.remove(&id) => continue,
+                Ok(()) => (),
+                // The store gave up retrying, but the band is still dirty
+                // and is uploaded again after a while in case the failure
+                // goes away
+                Err(err) if err.is_transient() && attempt + 1 < retry.max_attempts => {
+                    let delay = retry.delay(attempt);
+                    println!(
+                        "\tupload {} failed: {err}, retrying in {delay:?}",
+                        generations.key(id)
+                    );
+                    drop(state);
+                    thread::sleep(delay);
+                    attempt += 1;
+                    continue;
+                }
+                Err(err) => {
+                    println!("\tupload {} failed: {err}", generations.key(id));
+                    state.redirtied.remove(&id);
+                    state.errors.insert(id.0, err.errno());
+                }
+            }
+            state.uploading.remove(&id);
+            done.notify_all();
+            break;
+        }
+    }
+}
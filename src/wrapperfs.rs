@@ -1,6 +1,7 @@
 use crate::s3tmfs::S3TMFS;
 
-use fuser::{FileAttr, Filesystem, ReplyEmpty};
+use fuser::{FileAttr, FileType, Filesystem, ReplyEmpty};
+use std::ffi::OsString;
 use std::time::Duration;
 
 #[cfg(feature = "macos")]
@@ -49,8 +50,42 @@ pub struct ReplyLock {
     pub pid: u32,
 }
 
-pub struct ReplyXattr {
-    pub size: u32,
+// An attribute value or name list, or only its size when the caller probes
+// for it with a size of 0
+#[derive(Debug, PartialEq)]
+pub enum ReplyXattr {
+    Size(u32),
+    Data(Vec<u8>),
+}
+
+// The replies to an xattr request, implemented by fuser::ReplyXattr
+pub trait XattrReplier {
+    fn size(self, size: u32);
+    fn data(self, data: &[u8]);
+    fn error(self, err: i32);
+}
+
+impl XattrReplier for fuser::ReplyXattr {
+    fn size(self, size: u32) {
+        fuser::ReplyXattr::size(self, size)
+    }
+
+    fn data(self, data: &[u8]) {
+        fuser::ReplyXattr::data(self, data)
+    }
+
+    fn error(self, err: i32) {
+        fuser::ReplyXattr::error(self, err)
+    }
+}
+
+// Pass the result of getxattr or listxattr on to the kernel
+pub fn reply_xattr(result: Result<ReplyXattr, i32>, reply: impl XattrReplier) {
+    match result {
+        Ok(ReplyXattr::Size(size)) => reply.size(size),
+        Ok(ReplyXattr::Data(data)) => reply.data(&data),
+        Err(err) => reply.error(err),
+    }
 }
 
 #[cfg(feature = "macos")]
@@ -77,8 +112,18 @@ pub struct ReplyData<'a> {
     pub data: &'a [u8],
 }
 
+pub struct DirectoryEntry {
+    pub ino: u64,
+    // Offset of the entry following this one
+    pub offset: i64,
+    pub kind: FileType,
+    pub name: OsString,
+}
+
+// Entries from the offset asked for to the end of the directory, only as
+// many as fit are passed on to the kernel
 pub struct ReplyDirectory {
-    // TODO: add() method
+    pub entries: Vec<DirectoryEntry>,
 }
 
 pub struct ReplyDirectoryPlus {
@@ -96,10 +141,12 @@ pub struct ReplyStatfs {
     pub frsize: u32,
 }
 
+// Argument lists mirror the fuser::Filesystem methods
+#[allow(clippy::too_many_arguments)]
 pub trait WrappedFilesystem {
     fn fuse_init(&mut self) -> Result<(), libc::c_int>;
-    fn fuse_getattr(&mut self, ino: u64) -> Result<ReplyAttr, i32>;
-    fn fuse_lookup(&mut self, parent: u64, name: &std::ffi::OsStr) -> Result<ReplyEntry, i32>;
+    fn fuse_getattr(&mut self, ino: u64) -> Result<ReplyAttr<'_>, i32>;
+    fn fuse_lookup(&mut self, parent: u64, name: &std::ffi::OsStr) -> Result<ReplyEntry<'_>, i32>;
     fn fuse_create(
         &mut self,
         parent: u64,
@@ -169,13 +216,13 @@ pub trait WrappedFilesystem {
         _cmd: u32,
         _in_data: &[u8],
         _out_size: u32,
-    ) -> Result<ReplyIoctl, i32>;
+    ) -> Result<ReplyIoctl<'_>, i32>;
     fn fuse_link(
         &mut self,
         _ino: u64,
         _newparent: u64,
         _newname: &std::ffi::OsStr,
-    ) -> Result<ReplyEntry, i32>;
+    ) -> Result<ReplyEntry<'_>, i32>;
     fn fuse_listxattr(&mut self, _ino: u64, _size: u32) -> Result<ReplyXattr, i32>;
     fn fuse_lseek(
         &mut self,
@@ -190,7 +237,7 @@ pub trait WrappedFilesystem {
         _name: &std::ffi::OsStr,
         _mode: u32,
         _umask: u32,
-    ) -> Result<ReplyEntry, i32>;
+    ) -> Result<ReplyEntry<'_>, i32>;
     fn fuse_mknod(
         &mut self,
         _parent: u64,
@@ -198,7 +245,7 @@ pub trait WrappedFilesystem {
         _mode: u32,
         _umask: u32,
         _rdev: u32,
-    ) -> Result<ReplyEntry, i32>;
+    ) -> Result<ReplyEntry<'_>, i32>;
     fn fuse_open(&mut self, ino: u64, flags: i32) -> Result<ReplyOpen, i32>;
     fn fuse_opendir(&mut self, _ino: u64, _flags: i32) -> Result<ReplyOpen, i32>;
     fn fuse_read(
@@ -209,7 +256,7 @@ pub trait WrappedFilesystem {
         size: u32,
         _flags: i32,
         _lock_owner: Option<u64>,
-    ) -> Result<ReplyData, i32>;
+    ) -> Result<ReplyData<'_>, i32>;
     fn fuse_readdir(&mut self, _ino: u64, _fh: u64, _offset: i64) -> Result<ReplyDirectory, i32>;
     fn fuse_readdirplus(
         &mut self,
@@ -217,7 +264,7 @@ pub trait WrappedFilesystem {
         _fh: u64,
         _offset: i64,
     ) -> Result<ReplyDirectoryPlus, i32>;
-    fn fuse_readlink(&mut self, _ino: u64) -> Result<ReplyData, i32>;
+    fn fuse_readlink(&mut self, _ino: u64) -> Result<ReplyData<'_>, i32>;
     fn fuse_release(
         &mut self,
         ino: u64,
@@ -252,7 +299,7 @@ pub trait WrappedFilesystem {
         chgtime: Option<std::time::SystemTime>,
         bkuptime: Option<std::time::SystemTime>,
         flags: Option<u32>,
-    ) -> Result<ReplyAttr, i32>;
+    ) -> Result<ReplyAttr<'_>, i32>;
     fn fuse_setlk(
         &mut self,
         _ino: u64,
@@ -280,7 +327,7 @@ pub trait WrappedFilesystem {
         _parent: u64,
         _link_name: &std::ffi::OsStr,
         _target: &std::path::Path,
-    ) -> Result<ReplyEntry, i32>;
+    ) -> Result<ReplyEntry<'_>, i32>;
     fn fuse_unlink(&mut self, parent: u64, name: &std::ffi::OsStr) -> Result<(), i32>;
     fn fuse_write(
         &mut self,
@@ -490,10 +537,7 @@ impl Filesystem for S3TMFS {
         size: u32,
         reply: fuser::ReplyXattr,
     ) {
-        match self.fuse_getxattr(ino, name, size) {
-            Ok(rx) => reply.size(rx.size),
-            Err(err) => reply.error(err),
-        }
+        reply_xattr(self.fuse_getxattr(ino, name, size), reply);
     }
 
     #[cfg(feature = "macos")]
@@ -542,10 +586,7 @@ impl Filesystem for S3TMFS {
         size: u32,
         reply: fuser::ReplyXattr,
     ) {
-        match self.fuse_listxattr(ino, size) {
-            Ok(rx) => reply.size(rx.size),
-            Err(err) => reply.error(err),
-        }
+        reply_xattr(self.fuse_listxattr(ino, size), reply);
     }
 
     fn lseek(
@@ -637,10 +678,18 @@ impl Filesystem for S3TMFS {
         ino: u64,
         fh: u64,
         offset: i64,
-        reply: fuser::ReplyDirectory,
+        mut reply: fuser::ReplyDirectory,
     ) {
         match self.fuse_readdir(ino, fh, offset) {
-            Ok(_rd) => reply.ok(),
+            Ok(rd) => {
+                for entry in rd.entries {
+                    // The reply buffer is full
+                    if reply.add(entry.ino, entry.offset, entry.kind, entry.name) {
+                        break;
+                    }
+                }
+                reply.ok()
+            }
             Err(err) => reply.error(err),
         }
     }